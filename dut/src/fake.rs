@@ -0,0 +1,161 @@
+//! Serial port without hardware behind it, for tests
+
+use std::{
+	collections::VecDeque,
+	io::{self, Read, Write},
+	sync::{Arc, Mutex},
+	time,
+};
+
+/// Port that returns one chunk per read and times out once they are used
+/// up
+#[derive(Default, Clone)]
+pub struct FakePort {
+	chunks: Arc<Mutex<VecDeque<Vec<u8>>>>,
+	/// Everything written to the port
+	pub written: Arc<Mutex<Vec<u8>>>,
+}
+
+impl FakePort {
+	pub fn with_chunks(chunks: &[&[u8]]) -> FakePort {
+		let port = FakePort::default();
+		port.chunks
+			.lock()
+			.unwrap()
+			.extend(chunks.iter().map(|c| c.to_vec()));
+
+		port
+	}
+}
+
+impl Read for FakePort {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let mut chunks = self.chunks.lock().unwrap();
+		let Some(mut chunk) = chunks.pop_front() else {
+			return Err(io::ErrorKind::TimedOut.into());
+		};
+
+		// Chunks larger than `buf` take several reads
+		let len = chunk.len().min(buf.len());
+		buf[..len].copy_from_slice(&chunk[..len]);
+		if len < chunk.len() {
+			chunks.push_front(chunk.split_off(len));
+		}
+
+		Ok(len)
+	}
+}
+
+impl Write for FakePort {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.written.lock().unwrap().extend_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl serialport::SerialPort for FakePort {
+	fn name(&self) -> Option<String> {
+		None
+	}
+
+	fn baud_rate(&self) -> serialport::Result<u32> {
+		Ok(115200)
+	}
+
+	fn data_bits(&self) -> serialport::Result<serialport::DataBits> {
+		Ok(serialport::DataBits::Eight)
+	}
+
+	fn flow_control(&self) -> serialport::Result<serialport::FlowControl> {
+		Ok(serialport::FlowControl::None)
+	}
+
+	fn parity(&self) -> serialport::Result<serialport::Parity> {
+		Ok(serialport::Parity::None)
+	}
+
+	fn stop_bits(&self) -> serialport::Result<serialport::StopBits> {
+		Ok(serialport::StopBits::One)
+	}
+
+	fn timeout(&self) -> time::Duration {
+		time::Duration::ZERO
+	}
+
+	fn set_baud_rate(&mut self, _: u32) -> serialport::Result<()> {
+		Ok(())
+	}
+
+	fn set_data_bits(&mut self, _: serialport::DataBits) -> serialport::Result<()> {
+		Ok(())
+	}
+
+	fn set_flow_control(&mut self, _: serialport::FlowControl) -> serialport::Result<()> {
+		Ok(())
+	}
+
+	fn set_parity(&mut self, _: serialport::Parity) -> serialport::Result<()> {
+		Ok(())
+	}
+
+	fn set_stop_bits(&mut self, _: serialport::StopBits) -> serialport::Result<()> {
+		Ok(())
+	}
+
+	fn set_timeout(&mut self, _: time::Duration) -> serialport::Result<()> {
+		Ok(())
+	}
+
+	fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+		Ok(())
+	}
+
+	fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+		Ok(())
+	}
+
+	fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+		Ok(false)
+	}
+
+	fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+		Ok(false)
+	}
+
+	fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+		Ok(false)
+	}
+
+	fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+		Ok(false)
+	}
+
+	fn bytes_to_read(&self) -> serialport::Result<u32> {
+		Ok(0)
+	}
+
+	fn bytes_to_write(&self) -> serialport::Result<u32> {
+		Ok(0)
+	}
+
+	fn clear(&self, _: serialport::ClearBuffer) -> serialport::Result<()> {
+		self.chunks.lock().unwrap().clear();
+		Ok(())
+	}
+
+	fn try_clone(&self) -> serialport::Result<Box<dyn serialport::SerialPort>> {
+		Ok(Box::new(self.clone()))
+	}
+
+	fn set_break(&self) -> serialport::Result<()> {
+		Ok(())
+	}
+
+	fn clear_break(&self) -> serialport::Result<()> {
+		Ok(())
+	}
+}
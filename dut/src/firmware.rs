@@ -0,0 +1,390 @@
+use std::{collections::BTreeMap, fmt, time};
+
+use crate::{
+	regex,
+	serial::{self, Pattern},
+};
+
+const SSID_MAX_LENGTH: usize = 32;
+const PASSWORD_MAX_LENGTH: usize = 64;
+
+#[derive(Debug)]
+pub enum Error {
+	/// An argument can't be sent to the firmware
+	InvalidArgument(String),
+	/// The firmware rejected the command, or didn't answer in time
+	Command(String),
+	/// The answer didn't look like expected
+	Parse(String),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Error::InvalidArgument(e) => write!(f, "invalid argument: {}", e),
+			Error::Command(e) => write!(f, "command failed: {}", e),
+			Error::Parse(e) => write!(f, "unexpected response: {}", e),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+	pub board: u32,
+	pub hardware: u32,
+	pub protocol: u32,
+	pub firmware: String,
+	pub address: String,
+	pub mac: String,
+	pub status: u32,
+	pub wifi_state: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorInfo {
+	pub id: u32,
+	pub imu: String,
+	pub quaternion: [f32; 4],
+	pub working: bool,
+	pub had_data: bool,
+	pub magnetometer: Option<String>,
+}
+
+/// Answer to `GET INFO`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Info {
+	pub device: DeviceInfo,
+	pub sensors: Vec<SensorInfo>,
+	pub battery_voltage: f32,
+	pub battery_level: f32,
+	pub git_commit: String,
+}
+
+/// Answer to `GET TEST`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestInfo {
+	pub device: DeviceInfo,
+	pub sensor: SensorInfo,
+	/// All lines read while waiting for the answer
+	pub logs: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Network {
+	pub ssid: String,
+	pub rssi: i32,
+	pub encryption: String,
+}
+
+fn quote(name: &str, value: &str, max_length: usize) -> Result<String, Error> {
+	if value.len() > max_length {
+		return Err(Error::InvalidArgument(format!(
+			"{} is longer than {} bytes",
+			name, max_length
+		)));
+	}
+
+	if value.contains(['"', '\n', '\r']) {
+		return Err(Error::InvalidArgument(format!(
+			"{} must not contain quotes or line breaks",
+			name
+		)));
+	}
+
+	Ok(format!("\"{}\"", value))
+}
+
+fn parse<T: std::str::FromStr>(captures: &regex::Captures, name: &str) -> Result<T, Error> {
+	captures[name]
+		.parse()
+		.map_err(|_| Error::Parse(format!("invalid {}: {}", name, &captures[name])))
+}
+
+/// `GET INFO` prints `board: ...`, `GET TEST` prints `[TEST] Board: ...`
+fn parse_device_info(logs: &str) -> Result<DeviceInfo, Error> {
+	let re = regex!(
+		r"(?i)board: (?P<board>\d+), hardware: (?P<hardware>\d+), protocol: (?P<protocol>\d+), firmware: (?P<firmware>[^,]*), address: (?P<address>[^,]*), mac: (?P<mac>[^,]*), status: (?P<status>\d+), wifi state: (?P<wifi_state>\d+)",
+	);
+
+	let captures = logs
+		.lines()
+		.rev()
+		.find_map(|l| re.captures(l))
+		.ok_or_else(|| Error::Parse("no device info found".to_string()))?;
+
+	Ok(DeviceInfo {
+		board: parse(&captures, "board")?,
+		hardware: parse(&captures, "hardware")?,
+		protocol: parse(&captures, "protocol")?,
+		firmware: captures["firmware"].to_string(),
+		address: captures["address"].to_string(),
+		mac: captures["mac"].to_string(),
+		status: parse(&captures, "status")?,
+		wifi_state: parse(&captures, "wifi_state")?,
+	})
+}
+
+fn parse_sensors(logs: &str) -> Result<Vec<SensorInfo>, Error> {
+	let sensor_re = regex!(
+		r"Sensor\[(?P<id>\d+)\]: (?P<imu>\S+) \((?P<x>-?[\d.]+) (?P<y>-?[\d.]+) (?P<z>-?[\d.]+) (?P<w>-?[\d.]+)\) is working: (?P<working>true|false), had data: (?P<had_data>true|false)",
+	);
+	let mag_re = regex!(r"Sensor\[(?P<id>\d+)\] magnetometer: (?P<mag>.+)$");
+
+	let mut sensors: Vec<SensorInfo> = Vec::new();
+
+	for line in logs.lines() {
+		if let Some(c) = sensor_re.captures(line) {
+			let id = parse(&c, "id")?;
+			sensors.retain(|s| s.id != id);
+
+			sensors.push(SensorInfo {
+				id,
+				imu: c["imu"].to_string(),
+				quaternion: [
+					parse(&c, "x")?,
+					parse(&c, "y")?,
+					parse(&c, "z")?,
+					parse(&c, "w")?,
+				],
+				working: &c["working"] == "true",
+				had_data: &c["had_data"] == "true",
+				magnetometer: None,
+			});
+		} else if let Some(c) = mag_re.captures(line) {
+			let id: u32 = parse(&c, "id")?;
+
+			if let Some(sensor) = sensors.iter_mut().find(|s| s.id == id) {
+				sensor.magnetometer = Some(c["mag"].trim().to_string());
+			}
+		}
+	}
+
+	Ok(sensors)
+}
+
+/// Client for the serial commands of the SlimeVR tracker firmware
+pub struct Client<'a> {
+	serial: &'a mut serial::Reader,
+	timeout: time::Duration,
+}
+
+impl<'a> Client<'a> {
+	pub fn new(serial: &'a mut serial::Reader, timeout: time::Duration) -> Self {
+		Client { serial, timeout }
+	}
+
+	/// Sends `command` and waits for one of the expected answers
+	fn command(
+		&mut self,
+		command: &str,
+		positive: &[Pattern],
+		negative: &[Pattern],
+		timeout: time::Duration,
+	) -> Result<serial::Match, Error> {
+		self.serial
+			.write(format!("{}\n", command).as_bytes())
+			.map_err(Error::Command)?;
+
+		self.serial
+			.expect(positive, negative, timeout)
+			.map_err(Error::Command)
+	}
+
+	pub fn set_wifi(&mut self, ssid: &str, password: &str) -> Result<(), Error> {
+		let command = format!(
+			"SET WIFI {} {}",
+			quote("SSID", ssid, SSID_MAX_LENGTH)?,
+			quote("password", password, PASSWORD_MAX_LENGTH)?
+		);
+
+		self.command(
+			&command,
+			&["CMD SET WIFI OK".into()],
+			&["CMD SET WIFI ERROR".into(), "CMD SET ERROR".into()],
+			self.timeout,
+		)?;
+
+		Ok(())
+	}
+
+	pub fn get_info(&mut self) -> Result<Info, Error> {
+		let m = self.command(
+			"GET INFO",
+			&[Pattern::Regex(
+				regex!(r"Git commit: (?P<commit>\S+)").clone(),
+			)],
+			&[],
+			self.timeout,
+		)?;
+
+		let battery_re = regex!(r"Battery voltage: (?P<voltage>[\d.]+), level: (?P<level>[\d.]+)%");
+		let battery = m
+			.logs
+			.lines()
+			.rev()
+			.find_map(|l| battery_re.captures(l))
+			.ok_or_else(|| Error::Parse("no battery info found".to_string()))?;
+
+		Ok(Info {
+			device: parse_device_info(&m.logs)?,
+			sensors: parse_sensors(&m.logs)?,
+			battery_voltage: parse(&battery, "voltage")?,
+			battery_level: parse(&battery, "level")?,
+			git_commit: m.get("commit").unwrap_or_default().to_string(),
+		})
+	}
+
+	/// Runs `GET TEST`. A sensor that didn't send any data yet is reported
+	/// through [`SensorInfo::had_data`], not as an error.
+	pub fn get_test(&mut self) -> Result<TestInfo, Error> {
+		// Older firmware counts sensors from 1 and prints `Sensor 1`
+		let m = self.command(
+			"GET TEST",
+			&[Pattern::Regex(
+				regex!(
+					r"Sensor ?\[?\d+\]? (?P<result>sent some data, looks working|didn't send any data yet)",
+				)
+				.clone(),
+			)],
+			&[],
+			self.timeout,
+		)?;
+
+		let had_data = m.get("result") == Some("sent some data, looks working");
+
+		let device = parse_device_info(&m.logs)?;
+		let mut sensor = parse_sensors(&m.logs)?
+			.into_iter()
+			.next()
+			.unwrap_or(SensorInfo {
+				id: 0,
+				imu: "unknown".to_string(),
+				quaternion: [0.0; 4],
+				working: had_data,
+				had_data,
+				magnetometer: None,
+			});
+		sensor.had_data = had_data;
+
+		Ok(TestInfo {
+			device,
+			sensor,
+			logs: m.logs,
+		})
+	}
+
+	/// Runs `GET CONFIG`, returning the `KEY=VALUE` pairs
+	pub fn get_config(&mut self) -> Result<BTreeMap<String, String>, Error> {
+		let m = self.command("GET CONFIG", &["LED_INVERTED=".into()], &[], self.timeout)?;
+
+		let re = regex!(r"^(?P<key>[A-Z0-9_]+)=(?P<value>.*)$");
+
+		Ok(m.logs
+			.lines()
+			.filter_map(|l| re.captures(l.trim()))
+			.map(|c| (c["key"].to_string(), c["value"].to_string()))
+			.collect())
+	}
+
+	/// Runs `GET WIFISCAN`, scanning takes a few seconds so `timeout` should
+	/// be generous
+	pub fn wifi_scan(&mut self, timeout: time::Duration) -> Result<Vec<Network>, Error> {
+		let m = self.command(
+			"GET WIFISCAN",
+			&[Pattern::Regex(
+				regex!(r"\[WSCAN\] Found (?P<count>\d+) networks").clone(),
+			)],
+			&["[WSCAN] Scan failed!".into()],
+			timeout,
+		)?;
+
+		let count: usize = m.get("count").unwrap_or_default().parse().unwrap_or(0);
+		let re = regex!(
+			r"\[WSCAN\] (?P<index>\d+):\t\d+\t'(?P<ssid>.*)'\t\((?P<rssi>-?\d+) dBm\)\t(?P<encryption>\S+)",
+		);
+
+		let mut networks = Vec::new();
+		for _ in 0..count {
+			let m = self
+				.serial
+				.expect(&[Pattern::Regex(re.clone())], &[], self.timeout)
+				.map_err(Error::Command)?;
+
+			networks.push(Network {
+				ssid: m.get("ssid").unwrap_or_default().to_string(),
+				rssi: m.get("rssi").unwrap_or_default().parse().unwrap_or(0),
+				encryption: m.get("encryption").unwrap_or_default().to_string(),
+			});
+		}
+
+		Ok(networks)
+	}
+
+	/// Resets the configuration and WiFi credentials, the tracker restarts
+	/// afterwards
+	pub fn factory_reset(&mut self) -> Result<(), Error> {
+		self.command(
+			"FRST",
+			// Anchored, the refusal starts with the same words
+			&[Pattern::Regex(regex!(r"FACTORY RESET$").clone())],
+			&["FACTORY RESET NOT SUPPORTED".into()],
+			self.timeout,
+		)?;
+
+		Ok(())
+	}
+
+	pub fn reboot(&mut self) -> Result<(), Error> {
+		self.command("REBOOT", &["REBOOT".into()], &[], self.timeout)?;
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const GET_TEST: &str = "\
+[INFO ] [SerialCommands] [TEST] Board: 4, hardware: 2, protocol: 19, firmware: 0.5.0, address: 0xc0a80117, mac: 84:F3:EB:12:34:56, status: 0, wifi state: 3
+[INFO ] [SerialCommands] [TEST] Sensor[0]: BNO085 (0.001 -0.002 0.003 0.999) is working: true, had data: true
+[INFO ] [SerialCommands] [TEST] Sensor[0] sent some data, looks working.";
+
+	#[test]
+	fn parses_device_info_of_get_test() {
+		let device = parse_device_info(GET_TEST).unwrap();
+
+		assert_eq!(
+			device,
+			DeviceInfo {
+				board: 4,
+				hardware: 2,
+				protocol: 19,
+				firmware: "0.5.0".to_string(),
+				address: "0xc0a80117".to_string(),
+				mac: "84:F3:EB:12:34:56".to_string(),
+				status: 0,
+				wifi_state: 3,
+			}
+		);
+	}
+
+	#[test]
+	fn parses_device_info_of_get_info() {
+		let logs = "[INFO ] [SerialCommands] SlimeVR Tracker, board: 4, hardware: 2, protocol: 19, firmware: 0.5.0, address: 0xc0a80117, mac: 84:F3:EB:12:34:56, status: 0, wifi state: 3";
+
+		assert_eq!(parse_device_info(logs).unwrap().board, 4);
+	}
+
+	#[test]
+	fn parses_sensors_of_get_test() {
+		let sensors = parse_sensors(GET_TEST).unwrap();
+
+		assert_eq!(sensors.len(), 1);
+		assert_eq!(sensors[0].imu, "BNO085");
+		assert_eq!(sensors[0].quaternion, [0.001, -0.002, 0.003, 0.999]);
+		assert!(sensors[0].working && sensors[0].had_data);
+	}
+}
@@ -0,0 +1,19 @@
+//! Serial link to the device under test, shared by the tester and the
+//! updater
+
+#[cfg(any(test, feature = "fake"))]
+pub mod fake;
+pub mod firmware;
+pub mod serial;
+
+#[doc(hidden)]
+pub use regex::Regex;
+
+/// Compiles the regular expression `$re` once and returns a `&'static Regex`
+#[macro_export]
+macro_rules! regex {
+	($re:literal $(,)?) => {{
+		static RE: std::sync::OnceLock<$crate::Regex> = std::sync::OnceLock::new();
+		RE.get_or_init(|| $crate::Regex::new($re).unwrap())
+	}};
+}
@@ -0,0 +1,498 @@
+use std::{
+	collections::{BTreeMap, VecDeque},
+	io,
+	io::Write,
+	time,
+};
+
+use regex::Regex;
+
+pub type Serial = Box<dyn serialport::SerialPort>;
+
+/// Lines kept in the history unless configured otherwise
+const DEFAULT_HISTORY_SIZE: usize = 1000;
+
+/// Upper bound for a single blocking read, so deadlines are noticed in time
+const READ_TIMEOUT: time::Duration = time::Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct Line {
+	pub received_at: chrono::DateTime<chrono::Utc>,
+	pub text: String,
+}
+
+/// What a line is matched against
+#[derive(Debug, Clone)]
+pub enum Pattern {
+	Contains(String),
+	Regex(Regex),
+}
+
+impl Pattern {
+	pub fn regex(re: &str) -> Result<Pattern, regex::Error> {
+		Ok(Pattern::Regex(Regex::new(re)?))
+	}
+
+	/// Returns the named captures if `line` matches
+	pub fn captures(&self, line: &str) -> Option<BTreeMap<String, String>> {
+		match self {
+			Pattern::Contains(s) => line.contains(s.as_str()).then(BTreeMap::new),
+			Pattern::Regex(re) => {
+				let captures = re.captures(line)?;
+
+				Some(
+					re.capture_names()
+						.flatten()
+						.filter_map(|name| {
+							captures
+								.name(name)
+								.map(|m| (name.to_string(), m.as_str().to_string()))
+						})
+						.collect(),
+				)
+			}
+		}
+	}
+}
+
+impl From<&str> for Pattern {
+	fn from(s: &str) -> Self {
+		Pattern::Contains(s.to_string())
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct Match {
+	/// The line that matched
+	pub line: Line,
+	/// Named captures of the pattern that matched
+	pub captures: BTreeMap<String, String>,
+	/// All lines read while waiting, including the matching one
+	pub logs: String,
+}
+
+impl Match {
+	pub fn get(&self, name: &str) -> Option<&str> {
+		self.captures.get(name).map(|s| s.as_str())
+	}
+}
+
+fn join_lines<'a>(lines: impl IntoIterator<Item = &'a Line>) -> String {
+	lines
+		.into_iter()
+		.map(|l| l.text.as_str())
+		.collect::<Vec<_>>()
+		.join("\n")
+}
+
+/// Gets a copy of all traffic of a [`Reader`], e.g. to log it
+pub trait Tap: Send {
+	fn received(&mut self, data: &[u8]) -> io::Result<()>;
+	fn sent(&mut self, data: &[u8]) -> io::Result<()>;
+}
+
+/// Buffered, line oriented reader on top of a serial port
+pub struct Reader {
+	serial: Serial,
+	/// Bytes of a line that has not been terminated yet
+	partial: Vec<u8>,
+	/// Complete lines that have not been consumed yet
+	pending: VecDeque<Line>,
+	history: VecDeque<Line>,
+	history_size: usize,
+	tap: Option<Box<dyn Tap>>,
+	/// Clock used for deadlines
+	now: fn() -> time::Instant,
+	/// Checked while waiting for data, reading fails with its error
+	interrupt: fn() -> Result<(), String>,
+}
+
+impl Reader {
+	pub fn new(serial: Serial) -> Self {
+		Self::with_history_size(serial, DEFAULT_HISTORY_SIZE)
+	}
+
+	pub fn with_history_size(serial: Serial, history_size: usize) -> Self {
+		Reader {
+			serial,
+			partial: Vec::new(),
+			pending: VecDeque::new(),
+			history: VecDeque::new(),
+			history_size,
+			tap: None,
+			now: time::Instant::now,
+			interrupt: || Ok(()),
+		}
+	}
+
+	/// Uses `now` instead of [`time::Instant::now`] for deadlines, e.g. to
+	/// replay the timing of a recorded session
+	pub fn set_clock(&mut self, now: fn() -> time::Instant) {
+		self.now = now;
+	}
+
+	/// Calls `interrupt` while waiting for lines and stops reading once it
+	/// fails, e.g. when the operator aborts a step
+	pub fn set_interrupt(&mut self, interrupt: fn() -> Result<(), String>) {
+		self.interrupt = interrupt;
+	}
+
+	/// Passes all traffic from now on to `tap`, until it fails
+	pub fn set_tap(&mut self, tap: impl Tap + 'static) {
+		self.tap = Some(Box::new(tap));
+	}
+
+	fn tap(&mut self, f: impl FnOnce(&mut dyn Tap) -> io::Result<()>) {
+		if let Some(tap) = &mut self.tap {
+			if let Err(e) = f(tap.as_mut()) {
+				tracing::warn!("could not pass on serial traffic, stopping: {}", e);
+				self.tap = None;
+			}
+		}
+	}
+
+	pub fn serial_mut(&mut self) -> &mut Serial {
+		&mut self.serial
+	}
+
+	/// The last received lines, oldest first
+	pub fn history(&self) -> impl Iterator<Item = &Line> {
+		self.history.iter()
+	}
+
+	/// Drops everything received so far that has not been consumed
+	pub fn clear(&mut self) -> Result<(), String> {
+		self.partial.clear();
+		self.pending.clear();
+
+		self.serial
+			.clear(serialport::ClearBuffer::All)
+			.map_err(|e| format!("could not clear serial port: {}", e))
+	}
+
+	/// Switches to another baud rate, dropping the incomplete line received
+	/// at the old one
+	pub fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), String> {
+		self.partial.clear();
+
+		self.serial
+			.set_baud_rate(baud_rate)
+			.map_err(|e| format!("could not set baud rate: {}", e))
+	}
+
+	pub fn write(&mut self, data: &[u8]) -> Result<(), String> {
+		self.tap(|t| t.sent(data));
+
+		if let Err(e) = self.serial.write_all(data) {
+			return Err(format!("could not write to serial port: {}", e));
+		}
+
+		if let Err(e) = self.serial.flush() {
+			return Err(format!("could not flush serial port: {}", e));
+		}
+
+		Ok(())
+	}
+
+	fn push_line(&mut self, bytes: &[u8]) {
+		let bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+
+		let line = Line {
+			received_at: chrono::Utc::now(),
+			text: String::from_utf8_lossy(bytes).replace('\u{0000}', ""),
+		};
+
+		if self.history.len() >= self.history_size {
+			self.history.pop_front();
+		}
+		self.history.push_back(line.clone());
+		self.pending.push_back(line);
+	}
+
+	/// Reads whatever arrives until `deadline`, but at most for [`READ_TIMEOUT`]
+	fn fill(&mut self, deadline: time::Instant) -> Result<(), String> {
+		let remaining = deadline.saturating_duration_since((self.now)());
+		if remaining.is_zero() {
+			return Ok(());
+		}
+
+		if let Err(e) = self.serial.set_timeout(remaining.min(READ_TIMEOUT)) {
+			return Err(format!("could not set serial port timeout: {}", e));
+		}
+
+		let mut buf = [0u8; 256];
+		let bytes_read = match self.serial.read(&mut buf) {
+			Ok(bytes_read) => bytes_read,
+			Err(e) if e.kind() == io::ErrorKind::TimedOut => return Ok(()),
+			Err(e) => return Err(format!("could not read from serial port: {}", e)),
+		};
+
+		self.tap(|t| t.received(&buf[..bytes_read]));
+
+		let mut rest = &buf[..bytes_read];
+		while let Some(pos) = rest.iter().position(|b| *b == b'\n') {
+			let mut line = std::mem::take(&mut self.partial);
+			line.extend_from_slice(&rest[..pos]);
+			self.push_line(&line);
+
+			rest = &rest[pos + 1..];
+		}
+		self.partial.extend_from_slice(rest);
+
+		Ok(())
+	}
+
+	/// Returns the next complete line, or `None` if none arrived before
+	/// `deadline`
+	pub fn read_line(&mut self, deadline: time::Instant) -> Result<Option<Line>, String> {
+		loop {
+			if let Some(line) = self.pending.pop_front() {
+				return Ok(Some(line));
+			}
+
+			(self.interrupt)()?;
+
+			if (self.now)() >= deadline {
+				return Ok(None);
+			}
+
+			self.fill(deadline)?;
+		}
+	}
+
+	/// Reads lines until one contains any of `positive` (`Ok`) or `negative`
+	/// (`Err`), or until `timeout` is over (`Err`). Both return all lines
+	/// read in the meantime.
+	pub fn read_until(
+		&mut self,
+		positive: &[&str],
+		negative: &[&str],
+		timeout: time::Duration,
+	) -> Result<String, String> {
+		let positive = positive
+			.iter()
+			.map(|p| Pattern::from(*p))
+			.collect::<Vec<_>>();
+		let negative = negative
+			.iter()
+			.map(|n| Pattern::from(*n))
+			.collect::<Vec<_>>();
+
+		self.expect(&positive, &negative, timeout).map(|m| m.logs)
+	}
+
+	/// Like [`Reader::read_until`], but with patterns that can capture values
+	/// from the matching line
+	pub fn expect(
+		&mut self,
+		positive: &[Pattern],
+		negative: &[Pattern],
+		timeout: time::Duration,
+	) -> Result<Match, String> {
+		let ((line, captures), logs) = self.expect_with(timeout, |line| {
+			if let Some(captures) = positive.iter().find_map(|p| p.captures(&line.text)) {
+				return Some(Ok((line.clone(), captures)));
+			}
+
+			if negative.iter().any(|n| n.captures(&line.text).is_some()) {
+				return Some(Err(format!("negative match: {}", line.text)));
+			}
+
+			None
+		})?;
+
+		Ok(Match {
+			line,
+			captures,
+			logs,
+		})
+	}
+
+	/// Reads lines until `f` decides on one of them by returning `Some`, or
+	/// until `timeout` is over (`Err`). Returns the decision together with
+	/// all lines read in the meantime.
+	pub fn expect_with<T>(
+		&mut self,
+		timeout: time::Duration,
+		mut f: impl FnMut(&Line) -> Option<Result<T, String>>,
+	) -> Result<(T, String), String> {
+		let deadline = (self.now)() + timeout;
+		let mut lines = Vec::new();
+
+		loop {
+			let line = match self.read_line(deadline) {
+				Ok(Some(line)) => line,
+				Ok(None) => {
+					let partial = String::from_utf8_lossy(&self.partial).to_string();
+
+					return Err(join_lines(&lines)
+						+ "\n"
+						+ &partial
+						+ &format!("\ntimed out after {}s", timeout.as_secs()));
+				}
+				Err(e) => {
+					return Err("Lines: ".to_string() + &join_lines(&lines) + "\nError: " + &e)
+				}
+			};
+
+			lines.push(line.clone());
+
+			match f(&line) {
+				Some(Ok(value)) => {
+					tracing::debug!("> {} (matched)", line.text);
+
+					return Ok((value, join_lines(&lines)));
+				}
+				Some(Err(e)) => {
+					tracing::debug!("> {} (failed)", line.text);
+
+					return Err(join_lines(&lines) + "\n" + &e);
+				}
+				None => tracing::debug!("> {}", line.text),
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+
+	use super::*;
+	use crate::fake::FakePort;
+
+	/// Records the traffic the reader passes on
+	#[derive(Clone, Default)]
+	struct Traffic(Arc<Mutex<Vec<String>>>);
+
+	impl Tap for Traffic {
+		fn received(&mut self, data: &[u8]) -> io::Result<()> {
+			self.0
+				.lock()
+				.unwrap()
+				.push(format!("< {}", data.escape_ascii()));
+			Ok(())
+		}
+
+		fn sent(&mut self, data: &[u8]) -> io::Result<()> {
+			self.0
+				.lock()
+				.unwrap()
+				.push(format!("> {}", data.escape_ascii()));
+			Ok(())
+		}
+	}
+
+	fn reader(chunks: &[&[u8]]) -> Reader {
+		Reader::new(Box::new(FakePort::with_chunks(chunks)))
+	}
+
+	fn soon() -> time::Instant {
+		time::Instant::now() + time::Duration::from_millis(50)
+	}
+
+	fn texts(reader: &mut Reader) -> Vec<String> {
+		let mut lines = Vec::new();
+		while let Some(line) = reader.read_line(soon()).unwrap() {
+			lines.push(line.text);
+		}
+
+		lines
+	}
+
+	#[test]
+	fn joins_lines_split_across_reads() {
+		let mut reader = reader(&[b"first li", b"ne\r", b"\nsecond\r\nthi", b"rd\n"]);
+
+		assert_eq!(texts(&mut reader), ["first line", "second", "third"]);
+	}
+
+	#[test]
+	fn keeps_unterminated_lines_back() {
+		let mut reader = reader(&[b"done\nstill typ", b"ing"]);
+
+		assert_eq!(texts(&mut reader), ["done"]);
+
+		let e = reader
+			.read_until(&["never"], &[], time::Duration::from_millis(50))
+			.unwrap_err();
+		assert!(e.contains("still typing"), "{}", e);
+		assert!(e.ends_with("timed out after 0s"), "{}", e);
+	}
+
+	#[test]
+	fn gives_up_at_the_deadline() {
+		let mut reader = reader(&[]);
+
+		let past = time::Instant::now();
+		assert!(reader.read_line(past).unwrap().is_none());
+	}
+
+	#[test]
+	fn stops_at_the_interrupt() {
+		let mut reader = reader(&[b"line\n"]);
+		reader.set_interrupt(|| Err("aborted".to_string()));
+
+		assert_eq!(reader.read_line(soon()).unwrap_err(), "aborted");
+	}
+
+	#[test]
+	fn matches_patterns_with_captures() {
+		let mut reader = reader(&[b"booting\n", b"[WSCAN] Found 3 networks\n"]);
+
+		let m = reader
+			.expect(
+				&[Pattern::regex(r"Found (?P<count>\d+) networks").unwrap()],
+				&["Scan failed".into()],
+				time::Duration::from_millis(50),
+			)
+			.unwrap();
+
+		assert_eq!(m.get("count"), Some("3"));
+		assert_eq!(m.logs, "booting\n[WSCAN] Found 3 networks");
+	}
+
+	#[test]
+	fn fails_on_negative_matches() {
+		let mut reader = reader(&[b"CMD SET WIFI ERROR\n"]);
+
+		let e = reader
+			.read_until(
+				&["CMD SET WIFI OK"],
+				&["ERROR"],
+				time::Duration::from_millis(50),
+			)
+			.unwrap_err();
+
+		assert!(e.ends_with("negative match: CMD SET WIFI ERROR"), "{}", e);
+	}
+
+	#[test]
+	fn keeps_a_bounded_history() {
+		let mut reader =
+			Reader::with_history_size(Box::new(FakePort::with_chunks(&[b"a\nb\nc\n"])), 2);
+
+		assert_eq!(texts(&mut reader).len(), 3);
+		assert_eq!(
+			reader
+				.history()
+				.map(|l| l.text.as_str())
+				.collect::<Vec<_>>(),
+			["b", "c"]
+		);
+	}
+
+	#[test]
+	fn passes_traffic_to_the_tap() {
+		let port = FakePort::with_chunks(&[b"pong\n"]);
+		let mut reader = Reader::new(Box::new(port.clone()));
+		let traffic = Traffic::default();
+		reader.set_tap(traffic.clone());
+
+		reader.write(b"ping\n").unwrap();
+		assert_eq!(texts(&mut reader), ["pong"]);
+
+		assert_eq!(*port.written.lock().unwrap(), b"ping\n");
+		assert_eq!(*traffic.0.lock().unwrap(), ["> ping\\n", "< pong\\n"]);
+	}
+}
@@ -0,0 +1,544 @@
+use std::env;
+
+use colored::Colorize;
+
+use crate::{
+    database::{self, Database},
+    export,
+    options::{Options, Output},
+    outbox,
+};
+
+const RESULTS_USAGE: &str = "\
+Usage: tester results [list] [FILTERS]
+       tester results show <REPORT ID>
+
+Filters:
+  --mac <MAC>         boards with this MAC address
+  --lot <LOT>         boards of this production lot (TESTER_LOT)
+  --from <DATE>       tested on or after, YYYY-MM-DD or RFC 3339
+  --to <DATE>         tested before, a plain date includes that day
+  --step <STEP>       boards that ran this step, --passed/--failed apply to it
+  --passed            boards (or the step) that passed
+  --failed            boards (or the step) that failed
+  --type <TYPE>       boards tested with this test plan
+  --limit <N>         at most N boards, newest first
+
+--output json prints one board per line.";
+
+const EXPORT_USAGE: &str = "\
+Usage: tester export --format <csv|junit|html> [--out <FILE>] [FILTERS]
+
+Writes the stored boards matching the filters of `tester results` to FILE,
+or to stdout.";
+
+const OUTBOX_USAGE: &str = "\
+Usage: tester outbox [list]
+       tester outbox drop <SINK>
+
+Lists how many reports wait for each sink. `drop` gives up on the reports
+waiting for SINK, e.g. one removed from TESTER_SINKS, and moves them to the
+dead letters. Only run it while the station is stopped.";
+
+/// Runs the command given as first argument, if any, and returns the exit
+/// code. Without a command the station starts testing.
+pub fn run(options: &Options) -> Option<i32> {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    // Global options may come before the command
+    let mut args = Args::new(&args);
+
+    let result = match args.next() {
+        Some("results") => results(options, args.rest()),
+        Some("export") => export(options, args.rest()),
+        Some("outbox") => outbox(options, args.rest()),
+        _ => return None,
+    };
+
+    match result {
+        Ok(()) => Some(0),
+        Err(e) => {
+            eprintln!("{}", e);
+
+            Some(1)
+        }
+    }
+}
+
+/// Flags and values of a command, with the global options left out
+struct Args<'a> {
+    args: std::slice::Iter<'a, String>,
+}
+
+impl<'a> Args<'a> {
+    fn new(args: &'a [String]) -> Args<'a> {
+        Args { args: args.iter() }
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        loop {
+            let arg = self.args.next()?;
+
+            // Read by `Options`
+            if arg == "--output" || arg == "--database" {
+                self.args.next();
+                continue;
+            }
+            if arg.starts_with("--output=") || arg.starts_with("--database=") {
+                continue;
+            }
+
+            return Some(arg);
+        }
+    }
+
+    fn value(&mut self, flag: &str) -> Result<&'a str, String> {
+        self.next().ok_or_else(|| format!("{} needs a value", flag))
+    }
+
+    /// What wasn't read yet, global options included
+    fn rest(&self) -> &'a [String] {
+        self.args.as_slice()
+    }
+}
+
+/// A plain date is the start of that day in local time
+fn parse_time(value: &str) -> Result<chrono::DateTime<chrono::Utc>, String> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Ok(time.into());
+    }
+
+    let date = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| format!("`{}` is not a date, expected YYYY-MM-DD", value))?;
+
+    date.and_hms_opt(0, 0, 0)
+        .and_then(|t| t.and_local_timezone(chrono::Local).earliest())
+        .map(|t| t.into())
+        .ok_or_else(|| format!("`{}` is not a valid local time", value))
+}
+
+/// Parses the filters shared by the commands reading the database, calls
+/// `other` for any other argument
+fn parse_query<'a>(
+    args: &'a [String],
+    mut other: impl FnMut(&'a str) -> Result<(), String>,
+) -> Result<database::Query, String> {
+    let mut query = database::Query::default();
+    let mut args = Args::new(args);
+
+    while let Some(arg) = args.next() {
+        match arg {
+            "--mac" => query.mac = Some(args.value(arg)?.to_string()),
+            "--lot" => query.lot = Some(args.value(arg)?.to_string()),
+            "--from" => query.from = Some(parse_time(args.value(arg)?)?),
+            "--to" => {
+                let value = args.value(arg)?;
+                let mut to = parse_time(value)?;
+                if !value.contains('T') {
+                    to += chrono::Duration::days(1);
+                }
+                query.to = Some(to);
+            }
+            "--step" => query.step = Some(args.value(arg)?.to_string()),
+            "--passed" => query.passed = Some(true),
+            "--failed" => query.passed = Some(false),
+            "--type" => query.report_type = Some(args.value(arg)?.to_string()),
+            "--limit" => {
+                let value = args.value(arg)?;
+                query.limit = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("`{}` is not a number", value))?,
+                );
+            }
+            _ => other(arg)?,
+        }
+    }
+
+    Ok(query)
+}
+
+fn open_database(options: &Options) -> Result<Database, String> {
+    let path = options
+        .database
+        .as_ref()
+        .ok_or("the results database is disabled (TESTER_DATABASE is empty)")?;
+
+    Database::open(path).map_err(|e| format!("could not open {}: {}", path.display(), e))
+}
+
+fn results(options: &Options, args: &[String]) -> Result<(), String> {
+    let (show, args) = match args.first().map(|a| a.as_str()) {
+        Some("show") => (true, &args[1..]),
+        Some("list") => (false, &args[1..]),
+        Some("help" | "--help" | "-h") => {
+            println!("{}", RESULTS_USAGE);
+            return Ok(());
+        }
+        _ => (false, args),
+    };
+
+    let database = open_database(options)?;
+
+    if show {
+        let mut id = None;
+        parse_query(args, |arg| {
+            if id.is_some() || arg.starts_with("--") {
+                return Err(format!(
+                    "unexpected argument `{}`\n\n{}",
+                    arg, RESULTS_USAGE
+                ));
+            }
+            id = Some(arg);
+            Ok(())
+        })?;
+        let id = id.ok_or(format!("missing report id\n\n{}", RESULTS_USAGE))?;
+
+        let record = database
+            .get(id)
+            .map_err(|e| e.to_string())?
+            .ok_or(format!("no report `{}`", id))?;
+
+        match options.output {
+            Output::Json => println!("{}", record_json(&record)),
+            Output::Tui => print_record(&record),
+        }
+
+        return Ok(());
+    }
+
+    let query = parse_query(args, |arg| {
+        Err(format!(
+            "unexpected argument `{}`\n\n{}",
+            arg, RESULTS_USAGE
+        ))
+    })?;
+    let records = database.query(&query).map_err(|e| e.to_string())?;
+
+    match options.output {
+        Output::Json => {
+            for record in &records {
+                println!("{}", record_json(record));
+            }
+        }
+        Output::Tui => {
+            for record in &records {
+                println!(
+                    "{}  {}  {:<17}  {:<9}  {}  {}",
+                    local_time(record.board.started_at),
+                    record.id.get(..8).unwrap_or(&record.id),
+                    record.board.id.as_deref().unwrap_or("-"),
+                    record.board.report_type.as_deref().unwrap_or("-"),
+                    result(record.passed),
+                    record.upload_status
+                );
+            }
+            println!("{} boards", records.len());
+        }
+    }
+
+    Ok(())
+}
+
+fn export(options: &Options, args: &[String]) -> Result<(), String> {
+    if matches!(
+        args.first().map(|a| a.as_str()),
+        Some("help" | "--help" | "-h")
+    ) {
+        println!("{}", EXPORT_USAGE);
+        return Ok(());
+    }
+
+    let mut format = None;
+    let mut out = None;
+    let mut pending = None;
+    let query = parse_query(args, |arg| {
+        match pending.take() {
+            Some("--format") => {
+                format = Some(
+                    export::Format::parse(arg)
+                        .ok_or(format!("unknown format `{}`\n\n{}", arg, EXPORT_USAGE))?,
+                );
+            }
+            Some(_) => out = Some(arg),
+            None if arg == "--format" || arg == "--out" => pending = Some(arg),
+            None => {
+                return Err(format!("unexpected argument `{}`\n\n{}", arg, EXPORT_USAGE));
+            }
+        }
+        Ok(())
+    })?;
+    if let Some(flag) = pending {
+        return Err(format!("{} needs a value", flag));
+    }
+    let format = format.ok_or(format!("missing --format\n\n{}", EXPORT_USAGE))?;
+
+    let records = open_database(options)?
+        .query(&query)
+        .map_err(|e| e.to_string())?;
+    let document = format.render(&records);
+
+    match out {
+        Some(path) => {
+            std::fs::write(path, document)
+                .map_err(|e| format!("could not write {}: {}", path, e))?;
+            eprintln!("Exported {} boards to {}", records.len(), path);
+        }
+        None => print!("{}", document),
+    }
+
+    Ok(())
+}
+
+fn outbox(options: &Options, args: &[String]) -> Result<(), String> {
+    let mut args = Args::new(args);
+    let drop = match (args.next(), args.next(), args.next()) {
+        (None | Some("list"), None, _) => None,
+        (Some("drop"), Some(sink), None) => Some(sink),
+        (Some("help" | "--help" | "-h"), None, _) => {
+            println!("{}", OUTBOX_USAGE);
+            return Ok(());
+        }
+        _ => return Err(OUTBOX_USAGE.to_string()),
+    };
+
+    let sinks = options.sinks.iter().map(|s| s.name()).collect::<Vec<_>>();
+    let mut outbox = outbox::Outbox::open(
+        &options.uploads.dir,
+        sinks.clone(),
+        outbox::Backoff {
+            min: options.uploads.retry_min,
+            max: options.uploads.retry_max,
+        },
+    )
+    .map_err(|e| format!("could not open the outbox: {}", e))?;
+
+    if let Some(sink) = drop {
+        let dropped = outbox
+            .drop_sink(sink)
+            .map_err(|e| format!("could not drop the reports for {}: {}", sink, e))?;
+        eprintln!("Moved {} reports for {} to the dead letters", dropped, sink);
+
+        return Ok(());
+    }
+
+    for (sink, reports) in outbox.pending() {
+        if sinks.contains(&sink) {
+            println!("{:>6}  {}", reports, sink);
+        } else {
+            println!("{:>6}  {} (not configured)", reports, sink);
+        }
+    }
+    println!("{:>6}  dead letters", outbox.dead_letters());
+
+    Ok(())
+}
+
+fn record_json(record: &database::Record) -> serde_json::Value {
+    serde_json::json!({
+        "id": record.id,
+        "testerName": record.tester_name,
+        "lot": record.lot,
+        "passed": record.passed,
+        "upload": {
+            "status": record.upload_status,
+            "attempts": record.upload_attempts,
+            "error": record.upload_error,
+        },
+        "board": record.board,
+    })
+}
+
+fn local_time(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.with_timezone(&chrono::Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+fn result(passed: bool) -> colored::ColoredString {
+    if passed {
+        "PASS".green()
+    } else {
+        "FAIL".red()
+    }
+}
+
+fn print_record(record: &database::Record) {
+    let board = &record.board;
+
+    println!("Report   {}", record.id);
+    println!("MAC      {}", board.id.as_deref().unwrap_or("-"));
+    println!("Plan     {}", board.report_type.as_deref().unwrap_or("-"));
+    println!("Tester   {}", record.tester_name);
+    println!("Lot      {}", record.lot.as_deref().unwrap_or("-"));
+    println!(
+        "Tested   {} - {}",
+        local_time(board.started_at),
+        local_time(board.ended_at)
+    );
+    println!("Result   {}", result(record.passed));
+    match &record.upload_error {
+        Some(error) => println!(
+            "Upload   {} after {} attempts: {}",
+            record.upload_status, record.upload_attempts, error
+        ),
+        None => println!("Upload   {}", record.upload_status),
+    }
+    println!();
+
+    for value in &board.values {
+        let mark = if value.failed {
+            "╳".red()
+        } else {
+            "✓".green()
+        };
+        println!(
+            "{} {}: {} = {}",
+            mark, value.step, value.condition, value.value
+        );
+
+        if let Some(logs) = &value.logs {
+            for line in logs.lines() {
+                println!("    {}", line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn query(values: &[&str]) -> Result<database::Query, String> {
+        parse_query(&args(values), |arg| Err(format!("unexpected `{}`", arg)))
+    }
+
+    fn local(date: &str) -> chrono::DateTime<chrono::Utc> {
+        chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_local_timezone(chrono::Local)
+            .earliest()
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn skips_global_options() {
+        let all = args(&[
+            "--output",
+            "json",
+            "results",
+            "--database=/tmp/results.db",
+            "show",
+            "--database",
+            "/tmp/results.db",
+            "a1",
+            "--output=json",
+        ]);
+        let mut args = Args::new(&all);
+
+        assert_eq!(args.next(), Some("results"));
+        assert_eq!(args.rest().len(), 6);
+        assert_eq!(args.next(), Some("show"));
+        assert_eq!(args.value("show"), Ok("a1"));
+        assert_eq!(args.next(), None);
+        assert_eq!(args.value("--mac"), Err("--mac needs a value".to_string()));
+    }
+
+    #[test]
+    fn parses_every_filter() {
+        let filters = query(&[
+            "--mac",
+            "84:F3:EB:00:00:01",
+            "--lot",
+            "L1",
+            "--from",
+            "2024-05-01T10:00:00Z",
+            "--step",
+            "Flashing",
+            "--failed",
+            "--type",
+            "auxboard",
+            "--limit",
+            "20",
+        ])
+        .unwrap();
+
+        assert_eq!(filters.id, None);
+        assert_eq!(filters.mac.as_deref(), Some("84:F3:EB:00:00:01"));
+        assert_eq!(filters.lot.as_deref(), Some("L1"));
+        assert_eq!(
+            filters.from,
+            Some(parse_time("2024-05-01T10:00:00Z").unwrap())
+        );
+        assert_eq!(filters.to, None);
+        assert_eq!(filters.step.as_deref(), Some("Flashing"));
+        assert_eq!(filters.passed, Some(false));
+        assert_eq!(filters.report_type.as_deref(), Some("auxboard"));
+        assert_eq!(filters.limit, Some(20));
+
+        // The last one wins
+        assert_eq!(query(&["--failed", "--passed"]).unwrap().passed, Some(true));
+    }
+
+    #[test]
+    fn includes_the_day_of_a_plain_to_date() {
+        assert_eq!(
+            query(&["--to", "2024-05-02"]).unwrap().to,
+            Some(local("2024-05-03"))
+        );
+        assert_eq!(
+            query(&["--to", "2024-05-02T12:00:00+02:00"]).unwrap().to,
+            Some(parse_time("2024-05-02T10:00:00Z").unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_filters() {
+        assert_eq!(query(&["--mac"]).unwrap_err(), "--mac needs a value");
+        assert_eq!(
+            query(&["--limit", "ten"]).unwrap_err(),
+            "`ten` is not a number"
+        );
+        assert_eq!(
+            query(&["--from", "yesterday"]).unwrap_err(),
+            "`yesterday` is not a date, expected YYYY-MM-DD"
+        );
+        assert_eq!(
+            query(&["--lot", "L1", "a1"]).unwrap_err(),
+            "unexpected `a1`"
+        );
+    }
+
+    #[test]
+    fn passes_other_arguments_on() {
+        let args = args(&[
+            "--format", "csv", "--output", "json", "--lot", "L1", "extra",
+        ]);
+        let mut other = Vec::new();
+        let query = parse_query(&args, |arg| {
+            other.push(arg);
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(query.lot.as_deref(), Some("L1"));
+        assert_eq!(other, ["--format", "csv", "extra"]);
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(
+            parse_time("2024-05-01T12:30:00+02:00").unwrap(),
+            chrono::DateTime::parse_from_rfc3339("2024-05-01T10:30:00Z").unwrap()
+        );
+        assert_eq!(parse_time("2024-05-01").unwrap(), local("2024-05-01"));
+        assert!(parse_time("2024-13-01").is_err());
+        assert!(parse_time("01.05.2024").is_err());
+    }
+}
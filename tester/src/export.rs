@@ -0,0 +1,309 @@
+use std::fmt::Write;
+
+use crate::database::Record;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One row per step value
+    Csv,
+    /// One test suite per board, one test case per step value
+    JUnit,
+    /// A single page summary without external resources
+    Html,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Option<Format> {
+        match value {
+            "csv" => Some(Format::Csv),
+            "junit" => Some(Format::JUnit),
+            "html" => Some(Format::Html),
+            _ => None,
+        }
+    }
+
+    pub fn render(&self, records: &[Record]) -> String {
+        match self {
+            Format::Csv => csv(records),
+            Format::JUnit => junit(records),
+            Format::Html => html(records),
+        }
+    }
+}
+
+fn time(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+fn seconds(start: chrono::DateTime<chrono::Utc>, end: chrono::DateTime<chrono::Utc>) -> f64 {
+    (end - start).num_milliseconds().max(0) as f64 / 1000.0
+}
+
+/// Quotes a field if needed, as in RFC 4180
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn csv(records: &[Record]) -> String {
+    let mut out = String::from(
+        "report_id,mac,lot,test_plan,tester,board_started_at,board_passed,\
+         step,condition,value,failed,started_at,ended_at\r\n",
+    );
+
+    for record in records {
+        let board = &record.board;
+
+        for value in &board.values {
+            let row = [
+                record.id.clone(),
+                board.id.clone().unwrap_or_default(),
+                record.lot.clone().unwrap_or_default(),
+                board.report_type.clone().unwrap_or_default(),
+                record.tester_name.clone(),
+                time(board.started_at),
+                record.passed.to_string(),
+                value.step.clone(),
+                value.condition.clone(),
+                value.value.clone(),
+                value.failed.to_string(),
+                time(value.started_at),
+                time(value.ended_at),
+            ];
+
+            let row = row.iter().map(|f| csv_field(f)).collect::<Vec<_>>();
+            out += &row.join(",");
+            out += "\r\n";
+        }
+    }
+
+    out
+}
+
+/// Escapes text for XML and HTML, in content as well as attributes
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '&' => out += "&amp;",
+            '<' => out += "&lt;",
+            '>' => out += "&gt;",
+            '"' => out += "&quot;",
+            '\'' => out += "&apos;",
+            // Not allowed in XML 1.0
+            c if (c as u32) < 0x20 && !matches!(c, '\t' | '\n' | '\r') => {}
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+fn junit(records: &[Record]) -> String {
+    let tests = records.iter().map(|r| r.board.values.len()).sum::<usize>();
+    let failures = records
+        .iter()
+        .flat_map(|r| &r.board.values)
+        .filter(|v| v.failed)
+        .count();
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        out,
+        "<testsuites name=\"SlimeVR hardware tests\" tests=\"{}\" failures=\"{}\">",
+        tests, failures
+    )
+    .unwrap();
+
+    for record in records {
+        let board = &record.board;
+        let mac = board.id.as_deref().unwrap_or("unknown");
+        let plan = board.report_type.as_deref().unwrap_or("board");
+
+        writeln!(
+            out,
+            "  <testsuite name=\"{}\" id=\"{}\" hostname=\"{}\" timestamp=\"{}\" time=\"{:.3}\" \
+             tests=\"{}\" failures=\"{}\" errors=\"0\">",
+            escape(mac),
+            escape(&record.id),
+            escape(&record.tester_name),
+            time(board.started_at),
+            seconds(board.started_at, board.ended_at),
+            board.values.len(),
+            board.values.iter().filter(|v| v.failed).count()
+        )
+        .unwrap();
+
+        out += "    <properties>\n";
+        let properties = [
+            ("mac", Some(mac)),
+            ("lot", record.lot.as_deref()),
+            ("test_plan", board.report_type.as_deref()),
+            ("upload_status", Some(&record.upload_status)),
+        ];
+        for (name, value) in properties {
+            if let Some(value) = value {
+                writeln!(
+                    out,
+                    "      <property name=\"{}\" value=\"{}\"/>",
+                    name,
+                    escape(value)
+                )
+                .unwrap();
+            }
+        }
+        out += "    </properties>\n";
+
+        for value in &board.values {
+            writeln!(
+                out,
+                "    <testcase name=\"{}\" classname=\"{}.{}\" time=\"{:.3}\">",
+                escape(&value.step),
+                escape(plan),
+                escape(&mac.replace(':', "")),
+                seconds(value.started_at, value.ended_at)
+            )
+            .unwrap();
+
+            if value.failed {
+                writeln!(
+                    out,
+                    "      <failure message=\"{}\">{}</failure>",
+                    escape(&value.condition),
+                    escape(&value.value)
+                )
+                .unwrap();
+            }
+            if let Some(logs) = &value.logs {
+                writeln!(out, "      <system-out>{}</system-out>", escape(logs)).unwrap();
+            }
+
+            out += "    </testcase>\n";
+        }
+
+        out += "  </testsuite>\n";
+    }
+
+    out += "</testsuites>\n";
+
+    out
+}
+
+const HTML_STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; width: 100%; margin-bottom: 1em; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; vertical-align: top; }
+th { background: #eee; }
+.pass { background: #d4f7d4; }
+.fail { background: #f9d0d0; }
+details { margin: 0.5em 0; }
+summary { cursor: pointer; padding: 0.3em; }
+pre { margin: 0; white-space: pre-wrap; font-size: 0.85em; }
+";
+
+fn html(records: &[Record]) -> String {
+    let passed = records.iter().filter(|r| r.passed).count();
+    let failed = records.len() - passed;
+
+    let mut out = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    out += "<title>SlimeVR hardware test results</title>\n";
+    writeln!(out, "<style>{}</style>", HTML_STYLE).unwrap();
+    out += "</head>\n<body>\n<h1>SlimeVR hardware test results</h1>\n";
+
+    writeln!(
+        out,
+        "<p>Generated {}. {} boards: <span class=\"pass\">{} passed</span>, \
+         <span class=\"fail\">{} failed</span>.</p>",
+        time(chrono::Utc::now()),
+        records.len(),
+        passed,
+        failed
+    )
+    .unwrap();
+
+    out += "<table>\n<tr><th>Tested</th><th>MAC</th><th>Lot</th><th>Test plan</th>\
+            <th>Tester</th><th>Result</th><th>Upload</th></tr>\n";
+    for record in records {
+        let board = &record.board;
+
+        writeln!(
+            out,
+            "<tr class=\"{}\"><td>{}</td><td><a href=\"#{}\">{}</a></td><td>{}</td><td>{}</td>\
+             <td>{}</td><td>{}</td><td>{}</td></tr>",
+            if record.passed { "pass" } else { "fail" },
+            time(board.started_at),
+            escape(&record.id),
+            escape(board.id.as_deref().unwrap_or("-")),
+            escape(record.lot.as_deref().unwrap_or("-")),
+            escape(board.report_type.as_deref().unwrap_or("-")),
+            escape(&record.tester_name),
+            if record.passed { "PASS" } else { "FAIL" },
+            escape(&record.upload_status)
+        )
+        .unwrap();
+    }
+    out += "</table>\n";
+
+    for record in records {
+        let board = &record.board;
+
+        writeln!(
+            out,
+            "<details id=\"{}\"{}>\n<summary class=\"{}\">{} &mdash; {} &mdash; {}</summary>",
+            escape(&record.id),
+            if record.passed { "" } else { " open" },
+            if record.passed { "pass" } else { "fail" },
+            escape(board.id.as_deref().unwrap_or("unknown")),
+            time(board.started_at),
+            if record.passed { "PASS" } else { "FAIL" }
+        )
+        .unwrap();
+
+        out += "<table>\n<tr><th>Step</th><th>Condition</th><th>Value</th><th>Logs</th></tr>\n";
+        for value in &board.values {
+            writeln!(
+                out,
+                "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td><pre>{}</pre></td></tr>",
+                if value.failed { "fail" } else { "pass" },
+                escape(&value.step),
+                escape(&value.condition),
+                escape(&value.value),
+                escape(value.logs.as_deref().unwrap_or(""))
+            )
+            .unwrap();
+        }
+        out += "</table>\n</details>\n";
+    }
+
+    out += "</body>\n</html>\n";
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_csv_fields_only_when_needed() {
+        assert_eq!(csv_field("plain value"), "plain value");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+        assert_eq!(csv_field(""), "");
+    }
+
+    #[test]
+    fn escapes_xml() {
+        assert_eq!(
+            escape(r#"<a href="x">Tom & 'Jerry'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; &apos;Jerry&apos;&lt;/a&gt;"
+        );
+        assert_eq!(escape("tab\tline\nbell\u{7}nul\0"), "tab\tline\nbellnul");
+    }
+}
@@ -37,3 +37,12 @@ where
         Ok(voltage)
     }
 }
+
+/// Describes a failed measurement the way it ends up in reports
+pub fn describe_error<E: std::fmt::Display>(e: nb::Error<ads1x1x::Error<E>>) -> String {
+    match e {
+        nb::Error::WouldBlock => "err: would block".to_string(),
+        nb::Error::Other(ads1x1x::Error::I2C(e)) => format!("err: i2c: {}", e),
+        nb::Error::Other(ads1x1x::Error::InvalidInputData) => "err: invalid input data".to_string(),
+    }
+}
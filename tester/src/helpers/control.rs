@@ -0,0 +1,109 @@
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Mutex, MutexGuard,
+};
+
+/// Something the operator did to the current board, added to its report
+#[derive(Debug, Clone)]
+pub struct Note {
+    pub message: String,
+    /// Whether the board can't pass because of it
+    pub fails: bool,
+}
+
+/// What the operator asked for on the keyboard, see
+/// [`crate::logger::Renderer`]
+#[derive(Debug, Default)]
+pub struct State {
+    /// A board is connected and its report is not queued yet
+    pub testing: bool,
+    /// A board is connected, it might still wait for a paused station
+    pub connected: bool,
+    pub paused: bool,
+    pub quit: bool,
+    /// Test the current board again once the run is over
+    pub retest: bool,
+    /// Test plan to switch to before the next board
+    pub plan: Option<String>,
+    pub notes: Vec<Note>,
+}
+
+impl State {
+    /// Whether the tester can exit without losing a report
+    pub fn can_quit(&self) -> bool {
+        self.quit && !self.testing
+    }
+
+    pub fn note(&mut self, message: impl ToString, fails: bool) {
+        self.notes.push(Note {
+            message: message.to_string(),
+            fails,
+        });
+    }
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    testing: false,
+    connected: false,
+    paused: false,
+    quit: false,
+    retest: false,
+    plan: None,
+    notes: Vec::new(),
+});
+
+const RUN: u8 = 0;
+/// Abort the running step, the next one runs as usual
+const ABORT_STEP: u8 = 1;
+/// Abort every step until the next board
+const ABORT_BOARD: u8 = 2;
+
+static ABORT: AtomicU8 = AtomicU8::new(RUN);
+
+pub fn state() -> MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Starts a new board, forgetting what the operator did to the last one
+pub fn start_board() {
+    let mut state = state();
+    state.testing = true;
+    state.retest = false;
+    state.notes.clear();
+
+    ABORT.store(RUN, Ordering::SeqCst);
+}
+
+/// Starts testing the current board again
+pub fn restart_board() {
+    state().retest = false;
+
+    ABORT.store(RUN, Ordering::SeqCst);
+}
+
+pub fn abort_step() {
+    let _ = ABORT.compare_exchange(RUN, ABORT_STEP, Ordering::SeqCst, Ordering::SeqCst);
+}
+
+pub fn abort_board() {
+    ABORT.store(ABORT_BOARD, Ordering::SeqCst);
+}
+
+/// Fails if the operator aborted, to be called by everything that blocks for
+/// a while (serial reads, subprocesses)
+pub fn check() -> Result<(), String> {
+    match ABORT.load(Ordering::SeqCst) {
+        ABORT_STEP => {
+            if ABORT
+                .compare_exchange(ABORT_STEP, RUN, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                state().note("step aborted by operator", true);
+            }
+
+            Err("aborted by operator".to_string())
+        }
+        ABORT_BOARD => Err("aborted by operator".to_string()),
+        _ => Ok(()),
+    }
+}
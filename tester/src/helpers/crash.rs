@@ -0,0 +1,392 @@
+use std::{fmt::Write, fs, path::Path};
+
+use addr2line::{
+    object::{self, Object, ObjectSymbol},
+    Context,
+};
+use regex::Regex;
+
+use super::serial;
+
+/// Code lives in IRAM and the memory mapped flash, stack words outside of
+/// this range can't be return addresses
+const CODE_RANGE: std::ops::Range<u64> = 0x4000_0000..0x4300_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip {
+    ESP8266,
+    ESP32,
+}
+
+/// An exception dump printed by the core after a crash
+#[derive(Debug, Clone, PartialEq)]
+pub struct Crash {
+    pub chip: Chip,
+    /// First line of the dump, like `Exception (28):`
+    pub title: String,
+    /// Program counter of the exception, followed by the code addresses from
+    /// the stack or backtrace
+    pub addresses: Vec<u64>,
+    /// The dump as printed
+    pub dump: String,
+}
+
+/// Name of an ESP8266 exception cause as printed in `Exception (N):`
+pub fn exception_cause(cause: u32) -> &'static str {
+    match cause {
+        0 => "IllegalInstruction",
+        1 => "SyscallCause",
+        2 => "InstructionFetchError",
+        3 => "LoadStoreError",
+        4 => "Level1Interrupt",
+        5 => "Alloca",
+        6 => "IntegerDivideByZero",
+        8 => "Privileged",
+        9 => "LoadStoreAlignment",
+        12 => "InstrPIFDataError",
+        13 => "LoadStorePIFDataError",
+        14 => "InstrPIFAddrError",
+        15 => "LoadStorePIFAddrError",
+        16 => "InstTLBMiss",
+        17 => "InstTLBMultiHit",
+        18 => "InstFetchPrivilege",
+        20 => "InstFetchProhibited",
+        24 => "LoadStoreTLBMiss",
+        25 => "LoadStoreTLBMultiHit",
+        26 => "LoadStorePrivilege",
+        28 => "LoadProhibited",
+        29 => "StoreProhibited",
+        _ => "Unknown",
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+/// Collects exception dumps from serial output, line by line
+#[derive(Default)]
+pub struct Detector {
+    /// Lines of the dump currently being read
+    dump: Option<(Chip, Vec<String>)>,
+}
+
+impl Detector {
+    /// Whether a dump started but is not complete yet
+    pub fn in_dump(&self) -> bool {
+        self.dump.is_some()
+    }
+
+    /// Returns the crash once its dump is complete
+    pub fn feed(&mut self, line: &str) -> Option<Crash> {
+        let line = line.trim_end();
+
+        if self.dump.is_none() {
+            if line.starts_with("Exception (")
+                || line.starts_with("Soft WDT reset")
+                || line.starts_with("Panic ")
+                || line.starts_with("User exception (panic/abort/assert)")
+                || line == ">>>stack>>>"
+            {
+                self.dump = Some((Chip::ESP8266, Vec::new()));
+            } else if line.starts_with("Guru Meditation Error")
+                || line.contains("abort() was called at PC")
+            {
+                self.dump = Some((Chip::ESP32, Vec::new()));
+            } else {
+                return None;
+            }
+        }
+
+        let (chip, lines) = self.dump.as_mut().unwrap();
+        lines.push(line.to_string());
+
+        let done = match chip {
+            Chip::ESP8266 => line == "<<<stack<<<",
+            Chip::ESP32 => line.starts_with("Backtrace:"),
+        };
+
+        if !done {
+            return None;
+        }
+
+        let (chip, lines) = self.dump.take().unwrap();
+        Some(Crash::parse(chip, &lines))
+    }
+}
+
+impl Crash {
+    fn parse(chip: Chip, lines: &[String]) -> Crash {
+        let mut addresses = Vec::new();
+
+        match chip {
+            Chip::ESP8266 => {
+                let epc = Regex::new(r"epc1=(0x[0-9a-fA-F]+)").unwrap();
+                let stack = Regex::new(r"^[0-9a-fA-F]{8}:((?:\s+[0-9a-fA-F]{8}){1,4})").unwrap();
+
+                for line in lines {
+                    if let Some(c) = epc.captures(line) {
+                        addresses.extend(parse_hex(&c[1]));
+                    } else if let Some(c) = stack.captures(line) {
+                        addresses.extend(
+                            c[1].split_whitespace()
+                                .filter_map(parse_hex)
+                                .filter(|a| CODE_RANGE.contains(a)),
+                        );
+                    }
+                }
+            }
+            Chip::ESP32 => {
+                let pc = Regex::new(r"^PC\s*:\s*(0x[0-9a-fA-F]+)").unwrap();
+                let frame = Regex::new(r"(0x[0-9a-fA-F]+):0x[0-9a-fA-F]+").unwrap();
+
+                for line in lines {
+                    if let Some(c) = pc.captures(line) {
+                        addresses.extend(parse_hex(&c[1]));
+                    } else if line.starts_with("Backtrace:") {
+                        addresses.extend(frame.captures_iter(line).filter_map(|c| parse_hex(&c[1])));
+                    }
+                }
+            }
+        }
+
+        let mut title = lines.first().cloned().unwrap_or_default();
+        if let Some(cause) = title
+            .strip_prefix("Exception (")
+            .and_then(|t| t.split(')').next())
+            .and_then(|c| c.parse().ok())
+        {
+            title = format!("{} {}", title.trim_end_matches(':'), exception_cause(cause));
+        }
+
+        Crash {
+            chip,
+            title,
+            addresses,
+            dump: lines.join("\n"),
+        }
+    }
+
+    /// The dump followed by the backtrace, symbolised against the firmware
+    /// `elf` if it can be read
+    pub fn decode(&self, elf: &Path) -> String {
+        let mut out = format!("{}\n\n{}\n\nDecoded backtrace:\n", self.title, self.dump);
+
+        match Symbolizer::open(elf) {
+            Ok(symbolizer) => {
+                for address in &self.addresses {
+                    let _ = writeln!(out, "0x{:08x}: {}", address, symbolizer.symbolize(*address));
+                }
+            }
+            Err(e) => {
+                let _ = writeln!(out, "could not read {}: {}", elf.display(), e);
+
+                for address in &self.addresses {
+                    let _ = writeln!(out, "0x{:08x}", address);
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// The first complete exception dump in `lines`
+pub fn find<'a>(lines: impl IntoIterator<Item = &'a serial::Line>) -> Option<Crash> {
+    let mut detector = Detector::default();
+
+    lines.into_iter().find_map(|l| detector.feed(&l.text))
+}
+
+/// Resolves code addresses to functions and source lines using the debug
+/// info of the firmware ELF, falling back to its symbol table
+pub struct Symbolizer {
+    context: Context<addr2line::gimli::EndianRcSlice<addr2line::gimli::RunTimeEndian>>,
+    symbols: Vec<(u64, String)>,
+}
+
+impl Symbolizer {
+    pub fn open(elf: &Path) -> Result<Symbolizer, String> {
+        let data = fs::read(elf).map_err(|e| e.to_string())?;
+        let file = object::File::parse(&*data).map_err(|e| e.to_string())?;
+
+        let context = Context::new(&file).map_err(|e| e.to_string())?;
+        let mut symbols = file
+            .symbols()
+            .filter(|s| s.kind() == object::SymbolKind::Text && s.address() != 0)
+            .filter_map(|s| Some((s.address(), s.name().ok()?.to_string())))
+            .collect::<Vec<_>>();
+        symbols.sort();
+
+        Ok(Symbolizer { context, symbols })
+    }
+
+    pub fn symbolize(&self, address: u64) -> String {
+        let mut frames = Vec::new();
+
+        if let Ok(mut iter) = self.context.find_frames(address).skip_all_loads() {
+            while let Ok(Some(frame)) = iter.next() {
+                let function = frame
+                    .function
+                    .as_ref()
+                    .and_then(|f| f.demangle().ok())
+                    .map(|f| f.to_string())
+                    .unwrap_or_else(|| "??".to_string());
+
+                let location = frame
+                    .location
+                    .map(|l| format!("{}:{}", l.file.unwrap_or("??"), l.line.unwrap_or(0)))
+                    .unwrap_or_else(|| "??:?".to_string());
+
+                frames.push(format!("{} at {}", function, location));
+            }
+        }
+
+        if !frames.is_empty() {
+            // Inlined functions first, the outermost function last
+            return frames.join("\n    inlined into ");
+        }
+
+        let index = self.symbols.partition_point(|(a, _)| *a <= address);
+        match index.checked_sub(1).map(|i| &self.symbols[i]) {
+            Some((start, name)) => format!(
+                "{}+0x{:x}",
+                addr2line::demangle_auto(name.into(), None),
+                address - start
+            ),
+            None => "??".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ESP8266_DUMP: &str = "\
+[INFO ] [BNO080Sensor] Connected to BNO085 on 0x4a
+--------------- CUT HERE FOR EXCEPTION DECODER ---------------
+
+Exception (28):
+epc1=0x4020a5e8 epc2=0x00000000 epc3=0x00000000 excvaddr=0x00000000 depc=0x00000000
+
+>>>stack>>>
+
+ctx: cont
+sp: 3ffffd80 end: 3fffffc0 offset: 0190
+3fffff10:  3fffdad0 00000000 3ffee6d8 4020a5e0
+3fffff20:  3fffdad0 3ffee4fc 3ffee6d8 40201cb1
+3fffff30:  00000000 00000000 00000001 40100170
+3fffffb0:  feefeffe feefeffe 3ffe85d8 40100c3d
+<<<stack<<<
+
+--------------- CUT HERE FOR EXCEPTION DECODER ---------------
+
+ ets Jan  8 2013,rst cause:2, boot mode:(3,6)";
+
+    const ESP32_DUMP: &str = "\
+[INFO ] [BNO080Sensor] Connected to BNO085 on 0x4a
+Guru Meditation Error: Core  1 panic'ed (LoadProhibited). Exception was unhandled.
+
+Core  1 register dump:
+PC      : 0x400d1e6c  PS      : 0x00060830  A0      : 0x800d2f1a  A1      : 0x3ffb1f60
+A2      : 0x00000000  A3      : 0x3ffc0d7c  A4      : 0x00000001  A5      : 0x3ffb1f80
+EXCVADDR: 0x00000000  LBEG    : 0x4000c2e0  LEND    : 0x4000c2f6  LCOUNT  : 0xffffffff
+
+Backtrace: 0x400d1e69:0x3ffb1f60 0x400d2f17:0x3ffb1f80 0x40088b9d:0x3ffb1fb0
+
+ELF file SHA256: 0000000000000000
+
+Rebooting...";
+
+    /// Feeds `output` line by line, returns the crashes and the lines that
+    /// were not part of a dump
+    fn feed(output: &str) -> (Vec<Crash>, Vec<&str>) {
+        let mut detector = Detector::default();
+        let mut crashes = Vec::new();
+        let mut passed = Vec::new();
+
+        for line in output.lines() {
+            match detector.feed(line) {
+                Some(crash) => crashes.push(crash),
+                None if !detector.in_dump() => passed.push(line),
+                None => {}
+            }
+        }
+
+        (crashes, passed)
+    }
+
+    #[test]
+    fn parses_an_esp8266_exception() {
+        let (crashes, _) = feed(ESP8266_DUMP);
+
+        assert_eq!(crashes.len(), 1);
+        assert_eq!(crashes[0].chip, Chip::ESP8266);
+        assert_eq!(crashes[0].title, "Exception (28) LoadProhibited");
+        assert_eq!(
+            crashes[0].addresses,
+            [0x4020a5e8, 0x4020a5e0, 0x40201cb1, 0x40100170, 0x40100c3d]
+        );
+        assert!(crashes[0].dump.starts_with("Exception (28):"));
+        assert!(crashes[0].dump.ends_with("<<<stack<<<"));
+    }
+
+    #[test]
+    fn parses_an_esp32_guru_meditation() {
+        let (crashes, _) = feed(ESP32_DUMP);
+
+        assert_eq!(crashes.len(), 1);
+        assert_eq!(crashes[0].chip, Chip::ESP32);
+        assert!(crashes[0].title.starts_with("Guru Meditation Error"));
+        assert_eq!(
+            crashes[0].addresses,
+            [0x400d1e6c, 0x400d1e69, 0x400d2f17, 0x40088b9d]
+        );
+        assert!(crashes[0].dump.ends_with("0x40088b9d:0x3ffb1fb0"));
+    }
+
+    #[test]
+    fn swallows_the_dump_lines() {
+        let (_, passed) = feed(ESP8266_DUMP);
+        assert_eq!(
+            passed,
+            [
+                "[INFO ] [BNO080Sensor] Connected to BNO085 on 0x4a",
+                "--------------- CUT HERE FOR EXCEPTION DECODER ---------------",
+                "",
+                "",
+                "--------------- CUT HERE FOR EXCEPTION DECODER ---------------",
+                "",
+                " ets Jan  8 2013,rst cause:2, boot mode:(3,6)",
+            ]
+        );
+
+        let (_, passed) = feed(ESP32_DUMP);
+        assert_eq!(
+            passed,
+            [
+                "[INFO ] [BNO080Sensor] Connected to BNO085 on 0x4a",
+                "",
+                "ELF file SHA256: 0000000000000000",
+                "",
+                "Rebooting...",
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_the_first_crash_in_the_history() {
+        let lines = ESP32_DUMP
+            .lines()
+            .chain(ESP8266_DUMP.lines())
+            .map(|text| serial::Line {
+                received_at: chrono::Utc::now(),
+                text: text.to_string(),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(find(&lines).unwrap().chip, Chip::ESP32);
+        assert!(find(&lines[..2]).is_none());
+    }
+}
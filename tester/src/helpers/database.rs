@@ -0,0 +1,720 @@
+use std::path::Path;
+
+use rusqlite::{params, params_from_iter, Connection, ToSql};
+
+use crate::{api, Board};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS boards (
+    id TEXT PRIMARY KEY,
+    mac TEXT,
+    report_type TEXT,
+    tester_name TEXT NOT NULL,
+    lot TEXT,
+    started_at TEXT NOT NULL,
+    ended_at TEXT NOT NULL,
+    passed INTEGER NOT NULL,
+    firmware TEXT,
+    upload_status TEXT NOT NULL,
+    upload_attempts INTEGER NOT NULL DEFAULT 0,
+    upload_error TEXT
+);
+CREATE INDEX IF NOT EXISTS boards_mac ON boards (mac);
+CREATE INDEX IF NOT EXISTS boards_started_at ON boards (started_at);
+CREATE INDEX IF NOT EXISTS boards_lot ON boards (lot);
+
+CREATE TABLE IF NOT EXISTS test_values (
+    board_id TEXT NOT NULL REFERENCES boards (id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    step TEXT NOT NULL,
+    condition TEXT NOT NULL,
+    value TEXT NOT NULL,
+    logs TEXT,
+    failed INTEGER NOT NULL,
+    started_at TEXT NOT NULL,
+    ended_at TEXT NOT NULL,
+    PRIMARY KEY (board_id, position)
+);
+CREATE INDEX IF NOT EXISTS test_values_step ON test_values (step);
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadStatus {
+    Pending,
+    Uploaded,
+    /// The server will never accept the report
+    Rejected,
+}
+
+impl UploadStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UploadStatus::Pending => "pending",
+            UploadStatus::Uploaded => "uploaded",
+            UploadStatus::Rejected => "rejected",
+        }
+    }
+}
+
+/// A stored board report
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub id: String,
+    pub board: Board,
+    pub tester_name: String,
+    pub lot: Option<String>,
+    pub passed: bool,
+    pub upload_status: String,
+    pub upload_attempts: u32,
+    pub upload_error: Option<String>,
+}
+
+/// Which boards to return, everything is optional
+#[derive(Debug, Default, Clone)]
+pub struct Query {
+    /// Report id or the start of one
+    pub id: Option<String>,
+    pub mac: Option<String>,
+    pub lot: Option<String>,
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    /// Exclusive
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only boards that ran this step, `passed` then applies to the step
+    pub step: Option<String>,
+    pub passed: Option<bool>,
+    pub report_type: Option<String>,
+    pub limit: Option<usize>,
+}
+
+fn insert_values(connection: &Connection, id: &str, board: &Board) -> rusqlite::Result<()> {
+    let mut insert = connection.prepare(
+        "INSERT INTO test_values (board_id, position, step, condition, value, logs, \
+         failed, started_at, ended_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+    )?;
+    for (position, value) in board.values.iter().enumerate() {
+        insert.execute(params![
+            id,
+            position,
+            value.step,
+            value.condition,
+            value.value,
+            value.logs,
+            value.failed,
+            value.started_at,
+            value.ended_at,
+        ])?;
+    }
+
+    Ok(())
+}
+
+/// Every tested board with its values and upload status, so the history is
+/// on the station even when the server is unreachable
+pub struct Database {
+    connection: Connection,
+}
+
+impl Database {
+    pub fn open(path: &Path) -> rusqlite::Result<Database> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            let _ = std::fs::create_dir_all(dir);
+        }
+
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+
+        Database::with_connection(connection)
+    }
+
+    fn with_connection(connection: Connection) -> rusqlite::Result<Database> {
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection.execute_batch(SCHEMA)?;
+
+        Ok(Database { connection })
+    }
+
+    /// Stores a tested board under `id`, the id of its report in the outbox
+    pub fn insert(
+        &mut self,
+        id: &str,
+        board: &Board,
+        tester_name: &str,
+        lot: Option<&str>,
+        passed: bool,
+        upload_status: UploadStatus,
+    ) -> rusqlite::Result<()> {
+        let tx = self.connection.transaction()?;
+
+        tx.execute(
+            "INSERT INTO boards (id, mac, report_type, tester_name, lot, started_at, ended_at, \
+             passed, firmware, upload_status) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                id,
+                board.id,
+                board.report_type,
+                tester_name,
+                lot,
+                board.started_at,
+                board.ended_at,
+                passed,
+                board
+                    .firmware
+                    .as_ref()
+                    .map(|f| serde_json::to_string(f).unwrap()),
+                upload_status.as_str(),
+            ],
+        )?;
+
+        insert_values(&tx, id, board)?;
+
+        tx.commit()
+    }
+
+    /// Replaces the values and result of the board stored under `id`, e.g.
+    /// with notes the operator added after its result
+    pub fn update(
+        &mut self,
+        id: &str,
+        board: &Board,
+        passed: bool,
+        upload_status: UploadStatus,
+    ) -> rusqlite::Result<()> {
+        let tx = self.connection.transaction()?;
+
+        tx.execute(
+            "UPDATE boards SET ended_at = ?2, passed = ?3, upload_status = ?4 WHERE id = ?1",
+            params![id, board.ended_at, passed, upload_status.as_str()],
+        )?;
+        tx.execute("DELETE FROM test_values WHERE board_id = ?1", [id])?;
+        insert_values(&tx, id, board)?;
+
+        tx.commit()
+    }
+
+    /// Records an upload attempt, keeping the last error unless the report
+    /// was uploaded. Boards stored before the database existed are ignored.
+    pub fn set_upload_status(
+        &self,
+        id: &str,
+        status: UploadStatus,
+        error: Option<&str>,
+    ) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "UPDATE boards SET upload_status = ?2, \
+             upload_error = CASE ?2 WHEN 'uploaded' THEN NULL ELSE COALESCE(?3, upload_error) END, \
+             upload_attempts = upload_attempts + 1 WHERE id = ?1",
+            params![id, status.as_str(), error],
+        )?;
+
+        Ok(())
+    }
+
+    /// Boards matching `query`, newest first
+    pub fn query(&self, query: &Query) -> rusqlite::Result<Vec<Record>> {
+        let mut conditions = Vec::new();
+        let mut params = Vec::<Box<dyn ToSql>>::new();
+
+        if let Some(id) = &query.id {
+            conditions.push("id LIKE ? || '%'");
+            params.push(Box::new(id.clone()));
+        }
+        if let Some(mac) = &query.mac {
+            conditions.push("mac = ? COLLATE NOCASE");
+            params.push(Box::new(mac.clone()));
+        }
+        if let Some(lot) = &query.lot {
+            conditions.push("lot = ?");
+            params.push(Box::new(lot.clone()));
+        }
+        if let Some(from) = query.from {
+            conditions.push("started_at >= ?");
+            params.push(Box::new(from));
+        }
+        if let Some(to) = query.to {
+            conditions.push("started_at < ?");
+            params.push(Box::new(to));
+        }
+        if let Some(report_type) = &query.report_type {
+            conditions.push("report_type = ?");
+            params.push(Box::new(report_type.clone()));
+        }
+        match (&query.step, query.passed) {
+            (Some(step), passed) => {
+                conditions.push(match passed {
+                    None => "id IN (SELECT board_id FROM test_values WHERE step = ?)",
+                    Some(true) => {
+                        "id IN (SELECT board_id FROM test_values WHERE step = ? \
+                         GROUP BY board_id HAVING MAX(failed) = 0)"
+                    }
+                    Some(false) => {
+                        "id IN (SELECT board_id FROM test_values WHERE step = ? AND failed)"
+                    }
+                });
+                params.push(Box::new(step.clone()));
+            }
+            (None, Some(passed)) => {
+                conditions.push("passed = ?");
+                params.push(Box::new(passed));
+            }
+            (None, None) => {}
+        }
+
+        let mut sql = "SELECT id, mac, report_type, tester_name, started_at, ended_at, passed, \
+                       firmware, upload_status, upload_attempts, upload_error, lot FROM boards"
+            .to_string();
+        if !conditions.is_empty() {
+            sql += " WHERE ";
+            sql += &conditions.join(" AND ");
+        }
+        sql += " ORDER BY started_at DESC";
+        if let Some(limit) = query.limit {
+            sql += &format!(" LIMIT {}", limit);
+        }
+
+        let mut records = self
+            .connection
+            .prepare(&sql)?
+            .query_map(params_from_iter(params.iter()), |row| {
+                Ok(Record {
+                    id: row.get(0)?,
+                    board: Board {
+                        id: row.get(1)?,
+                        values: Vec::new(),
+                        started_at: row.get(4)?,
+                        ended_at: row.get(5)?,
+                        firmware: row
+                            .get::<_, Option<String>>(7)?
+                            .and_then(|f| serde_json::from_str(&f).ok()),
+                        report_type: row.get(2)?,
+                    },
+                    tester_name: row.get(3)?,
+                    lot: row.get(11)?,
+                    passed: row.get(6)?,
+                    upload_status: row.get(8)?,
+                    upload_attempts: row.get(9)?,
+                    upload_error: row.get(10)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut values = self.connection.prepare(
+            "SELECT step, condition, value, logs, failed, started_at, ended_at \
+             FROM test_values WHERE board_id = ? ORDER BY position",
+        )?;
+        for record in &mut records {
+            record.board.values = values
+                .query_map([&record.id], |row| {
+                    Ok(api::TestReportValue {
+                        step: row.get(0)?,
+                        condition: row.get(1)?,
+                        value: row.get(2)?,
+                        logs: row.get(3)?,
+                        failed: row.get(4)?,
+                        started_at: row.get(5)?,
+                        ended_at: row.get(6)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+        }
+
+        Ok(records)
+    }
+
+    /// Whether a board is stored under exactly this report id
+    pub fn contains(&self, id: &str) -> rusqlite::Result<bool> {
+        self.connection
+            .prepare("SELECT 1 FROM boards WHERE id = ?")?
+            .exists([id])
+    }
+
+    /// A single board by its report id, or the start of one
+    pub fn get(&self, id: &str) -> rusqlite::Result<Option<Record>> {
+        let mut records = self.query(&Query {
+            id: Some(id.to_string()),
+            limit: Some(1),
+            ..Default::default()
+        })?;
+
+        Ok(records.pop())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> Database {
+        Database::with_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn time(value: &str) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(value).unwrap().into()
+    }
+
+    fn board(mac: &str, started_at: &str, report_type: &str, steps: &[(&str, bool)]) -> Board {
+        let started_at = time(started_at);
+
+        Board {
+            id: Some(mac.to_string()),
+            values: steps
+                .iter()
+                .map(|(step, failed)| {
+                    api::TestReportValue::new(
+                        step,
+                        "none",
+                        "value",
+                        Some("logs"),
+                        *failed,
+                        started_at,
+                        started_at,
+                    )
+                })
+                .collect(),
+            started_at,
+            ended_at: started_at + chrono::Duration::seconds(40),
+            firmware: None,
+            report_type: Some(report_type.to_string()),
+        }
+    }
+
+    /// Boards of two lots, tested on four days
+    fn tested_boards() -> Database {
+        let mut database = database();
+
+        let boards = [
+            (
+                "a1",
+                "L1",
+                board(
+                    "84:F3:EB:00:00:01",
+                    "2024-05-01T10:00:00Z",
+                    "mainboard",
+                    &[("Flashing", false), ("Serial", true)],
+                ),
+            ),
+            (
+                "b2",
+                "L2",
+                board(
+                    "84:F3:EB:00:00:02",
+                    "2024-05-02T10:00:00Z",
+                    "mainboard",
+                    &[("Flashing", true)],
+                ),
+            ),
+            (
+                "c3",
+                "L1",
+                board(
+                    "84:F3:EB:00:00:01",
+                    "2024-05-03T10:00:00Z",
+                    "auxboard",
+                    &[("Flashing", true), ("Flashing", false), ("Serial", false)],
+                ),
+            ),
+            (
+                "d4",
+                "L2",
+                board(
+                    "84:F3:EB:00:00:04",
+                    "2024-05-04T10:00:00Z",
+                    "mainboard",
+                    &[("Flashing", false)],
+                ),
+            ),
+        ];
+        for (id, lot, board) in &boards {
+            let passed = !board.values.iter().any(|v| v.failed);
+            database
+                .insert(
+                    id,
+                    board,
+                    "station-1",
+                    Some(lot),
+                    passed,
+                    UploadStatus::Pending,
+                )
+                .unwrap();
+        }
+
+        database
+    }
+
+    fn ids(database: &Database, query: Query) -> Vec<String> {
+        database
+            .query(&query)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.id)
+            .collect()
+    }
+
+    #[test]
+    fn creates_the_schema_once() {
+        let database = database();
+        database.connection.execute_batch(SCHEMA).unwrap();
+
+        let tables = database
+            .connection
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap()
+            .query_map([], |row| row.get::<_, String>(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(tables, ["boards", "test_values"]);
+    }
+
+    #[test]
+    fn stores_boards_with_their_values() {
+        let database = tested_boards();
+
+        let record = database.get("c3").unwrap().unwrap();
+        assert_eq!(record.id, "c3");
+        assert_eq!(record.board.id.as_deref(), Some("84:F3:EB:00:00:01"));
+        assert_eq!(record.board.report_type.as_deref(), Some("auxboard"));
+        assert_eq!(record.board.started_at, time("2024-05-03T10:00:00Z"));
+        assert_eq!(record.board.ended_at, time("2024-05-03T10:00:40Z"));
+        assert_eq!(record.tester_name, "station-1");
+        assert_eq!(record.lot.as_deref(), Some("L1"));
+        assert!(!record.passed);
+        assert_eq!(record.upload_status, "pending");
+        assert_eq!(record.upload_attempts, 0);
+
+        let values = record
+            .board
+            .values
+            .iter()
+            .map(|v| (v.step.as_str(), v.failed, v.logs.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            [
+                ("Flashing", true, Some("logs")),
+                ("Flashing", false, Some("logs")),
+                ("Serial", false, Some("logs")),
+            ]
+        );
+
+        assert!(database.get("e5").unwrap().is_none());
+    }
+
+    #[test]
+    fn keeps_the_last_upload_error() {
+        let database = tested_boards();
+        let upload = |id| {
+            let record = database.get(id).unwrap().unwrap();
+            (
+                record.upload_status,
+                record.upload_attempts,
+                record.upload_error,
+            )
+        };
+
+        database
+            .set_upload_status("a1", UploadStatus::Pending, Some("api: timeout"))
+            .unwrap();
+        database
+            .set_upload_status("a1", UploadStatus::Pending, None)
+            .unwrap();
+        assert_eq!(
+            upload("a1"),
+            ("pending".to_string(), 2, Some("api: timeout".to_string()))
+        );
+
+        database
+            .set_upload_status("a1", UploadStatus::Uploaded, Some("file: full"))
+            .unwrap();
+        assert_eq!(upload("a1"), ("uploaded".to_string(), 3, None));
+
+        database
+            .set_upload_status("b2", UploadStatus::Rejected, Some("api: 422"))
+            .unwrap();
+        assert_eq!(
+            upload("b2"),
+            ("rejected".to_string(), 1, Some("api: 422".to_string()))
+        );
+
+        // Boards stored before the database existed are not there
+        database
+            .set_upload_status("z9", UploadStatus::Uploaded, None)
+            .unwrap();
+    }
+
+    #[test]
+    fn replaces_the_values_of_an_updated_board() {
+        let mut database = tested_boards();
+        database
+            .set_upload_status("a1", UploadStatus::Uploaded, None)
+            .unwrap();
+
+        let mut board = database.get("a1").unwrap().unwrap().board;
+        board.values.truncate(1);
+        board.add_value(api::TestReportValue::new(
+            "Operator",
+            "none",
+            "scrapped by operator: cracked",
+            None::<&str>,
+            true,
+            board.ended_at,
+            board.ended_at,
+        ));
+        database
+            .update("a1", &board, false, UploadStatus::Pending)
+            .unwrap();
+
+        let record = database.get("a1").unwrap().unwrap();
+        let steps = record
+            .board
+            .values
+            .iter()
+            .map(|v| v.step.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(steps, ["Flashing", "Operator"]);
+        assert_eq!(record.upload_status, "pending");
+        assert_eq!(record.lot.as_deref(), Some("L1"));
+    }
+
+    #[test]
+    fn finds_reports_by_the_start_of_their_id() {
+        let mut database = tested_boards();
+        database
+            .insert(
+                "b2-retest",
+                &Board::new(),
+                "station-1",
+                None,
+                true,
+                UploadStatus::Pending,
+            )
+            .unwrap();
+
+        assert_eq!(database.get("a").unwrap().unwrap().id, "a1");
+        assert!(database.contains("b2").unwrap());
+        assert!(!database.contains("b").unwrap());
+        assert_eq!(
+            ids(
+                &database,
+                Query {
+                    id: Some("b2".to_string()),
+                    ..Default::default()
+                }
+            ),
+            ["b2-retest", "b2"]
+        );
+    }
+
+    #[test]
+    fn filters_boards() {
+        let database = tested_boards();
+
+        assert_eq!(ids(&database, Query::default()), ["d4", "c3", "b2", "a1"]);
+        assert_eq!(
+            ids(
+                &database,
+                Query {
+                    mac: Some("84:f3:eb:00:00:01".to_string()),
+                    ..Default::default()
+                }
+            ),
+            ["c3", "a1"]
+        );
+        assert_eq!(
+            ids(
+                &database,
+                Query {
+                    lot: Some("L2".to_string()),
+                    ..Default::default()
+                }
+            ),
+            ["d4", "b2"]
+        );
+        assert_eq!(
+            ids(
+                &database,
+                Query {
+                    report_type: Some("auxboard".to_string()),
+                    ..Default::default()
+                }
+            ),
+            ["c3"]
+        );
+        assert_eq!(
+            ids(
+                &database,
+                Query {
+                    passed: Some(false),
+                    ..Default::default()
+                }
+            ),
+            ["c3", "b2", "a1"]
+        );
+        assert_eq!(
+            ids(
+                &database,
+                Query {
+                    passed: Some(true),
+                    ..Default::default()
+                }
+            ),
+            ["d4"]
+        );
+        assert_eq!(
+            ids(
+                &database,
+                Query {
+                    limit: Some(2),
+                    ..Default::default()
+                }
+            ),
+            ["d4", "c3"]
+        );
+    }
+
+    #[test]
+    fn ends_before_the_to_time() {
+        let database = tested_boards();
+
+        assert_eq!(
+            ids(
+                &database,
+                Query {
+                    from: Some(time("2024-05-02T10:00:00Z")),
+                    to: Some(time("2024-05-03T10:00:00Z")),
+                    ..Default::default()
+                }
+            ),
+            ["b2"]
+        );
+        assert_eq!(
+            ids(
+                &database,
+                Query {
+                    from: Some(time("2024-05-02T10:00:01Z")),
+                    ..Default::default()
+                }
+            ),
+            ["d4", "c3"]
+        );
+    }
+
+    #[test]
+    fn applies_passed_to_the_step() {
+        let database = tested_boards();
+        let step = |step: &str, passed| Query {
+            step: Some(step.to_string()),
+            passed,
+            ..Default::default()
+        };
+
+        assert_eq!(ids(&database, step("Serial", None)), ["c3", "a1"]);
+        assert_eq!(ids(&database, step("Serial", Some(true))), ["c3"]);
+        assert_eq!(ids(&database, step("Serial", Some(false))), ["a1"]);
+
+        // A step that failed once failed, even if a retry passed
+        assert_eq!(ids(&database, step("Flashing", Some(true))), ["d4", "a1"]);
+        assert_eq!(ids(&database, step("Flashing", Some(false))), ["c3", "b2"]);
+
+        assert!(ids(&database, step("Provisioning", None)).is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+use std::{fmt::Write, path::Path, sync::Mutex};
+
+use tracing::{field, Level, Subscriber};
+use tracing_appender::{non_blocking::WorkerGuard, rolling};
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
+};
+
+use crate::logger;
+
+/// Target of the messages shown to the operator through [`logger::Logger`],
+/// they are logged to the files as well
+pub const OPERATOR: &str = "operator";
+
+/// Collects the message and fields of an event into one line
+#[derive(Default)]
+struct Message(String);
+
+impl field::Visit for Message {
+    fn record_debug(&mut self, field: &field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{:?}", value);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Shows diagnostic warnings and errors of the tester on the screen, where
+/// `println!` would corrupt the display. Dependencies report what they
+/// recover from as warnings too, the files are enough for those.
+struct UiLayer {
+    logger: Mutex<logger::Logger>,
+}
+
+impl<S: Subscriber> Layer<S> for UiLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+        let target = event.metadata().target();
+        if target == OPERATOR || !(target == "tester" || target.starts_with("tester::")) {
+            return;
+        }
+
+        let mut message = Message::default();
+        event.record(&mut message);
+
+        let mut l = self.logger.lock().unwrap();
+        if *event.metadata().level() == Level::ERROR {
+            l.error(&message.0);
+        } else {
+            l.warning(&message.0);
+        }
+    }
+}
+
+/// Logs everything allowed by `filter` (`EnvFilter` syntax, e.g.
+/// `info,tester::helpers::usb=debug`) to daily files in `dir`, keeping the
+/// last `keep_files`. Warnings and errors passing `filter` are shown through
+/// `logger` too.
+/// Logs are written in the background until the returned guard is dropped.
+pub fn init(
+    dir: &Path,
+    filter: &str,
+    keep_files: usize,
+    logger: logger::Logger,
+) -> Result<WorkerGuard, String> {
+    let appender = rolling::Builder::new()
+        .rotation(rolling::Rotation::DAILY)
+        .filename_prefix("tester")
+        .filename_suffix("log")
+        .max_log_files(keep_files)
+        .build(dir)
+        .map_err(|e| format!("could not create log files in {}: {}", dir.display(), e))?;
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+
+    let ui_filter = EnvFilter::try_new(filter).map_err(|e| format!("invalid log filter: {}", e))?;
+    let filter = EnvFilter::try_new(filter).map_err(|e| format!("invalid log filter: {}", e))?;
+
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(writer)
+                .with_ansi(false)
+                .with_filter(filter),
+        )
+        .with(
+            UiLayer {
+                logger: Mutex::new(logger),
+            }
+            .with_filter(LevelFilter::WARN)
+            .with_filter(ui_filter),
+        )
+        .try_init()
+        .map_err(|e| e.to_string())?;
+
+    Ok(guard)
+}
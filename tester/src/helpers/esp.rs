@@ -1,23 +1,60 @@
-use std::{thread, time};
+use std::time;
 
 use rppal::gpio;
 
+use crate::{session, usb::ResetMethod};
+
 pub struct ESP {
-    pub rst_pin: gpio::OutputPin,
-    pub flash_pin: gpio::OutputPin,
+    /// `None` when replaying a session without the jig
+    pub rst_pin: Option<gpio::OutputPin>,
+    pub flash_pin: Option<gpio::OutputPin>,
+    /// Serial port of the USB serial adapter the ESP is connected to
+    pub port: String,
+    /// How the ESP is reset, depends on the USB serial bridge of the board
+    pub reset_method: ResetMethod,
+    /// Chip as named by `esptool`
+    pub chip: String,
 }
 
 impl ESP {
-    pub fn new(rst_pin: gpio::OutputPin, flash_pin: gpio::OutputPin) -> Self {
-        ESP { rst_pin, flash_pin }
+    pub fn new(rst_pin: Option<gpio::OutputPin>, flash_pin: Option<gpio::OutputPin>) -> Self {
+        ESP {
+            rst_pin,
+            flash_pin,
+            port: "/dev/ttyUSB0".to_string(),
+            reset_method: ResetMethod::Gpio,
+            chip: "esp8266".to_string(),
+        }
+    }
+
+    /// `--before`/`--after` arguments for `esptool`. Only GPIO resets are done
+    /// by us, esptool knows how to reset through DTR/RTS and USB-JTAG itself.
+    pub fn esptool_reset_args(&self) -> (&'static str, &'static str) {
+        match self.reset_method {
+            ResetMethod::Gpio => ("no_reset", "no_reset"),
+            ResetMethod::DtrRts => ("default_reset", "hard_reset"),
+            ResetMethod::UsbJtag => ("usb_reset", "hard_reset"),
+        }
+    }
+
+    fn set_pin(pin: &mut Option<gpio::OutputPin>, name: &str, high: bool) {
+        session::interact("gpio", &format!("{} {}", name, high), || {
+            if let Some(pin) = pin {
+                pin.write(high.into());
+            }
+        })
     }
 
     pub fn reset_no_delay(&mut self) -> Result<(), gpio::Error> {
-        self.rst_pin.set_low();
+        if self.reset_method != ResetMethod::Gpio {
+            return Ok(());
+        }
+
+        Self::set_pin(&mut self.rst_pin, "rst", false);
 
-        thread::sleep(time::Duration::from_millis(200));
+        session::sleep(time::Duration::from_millis(200));
 
-        self.rst_pin.set_high();
+        Self::set_pin(&mut self.rst_pin, "rst", true);
 
         Ok(())
     }
@@ -25,18 +62,48 @@ impl ESP {
     pub fn reset(&mut self) -> Result<(), gpio::Error> {
         self.reset_no_delay()?;
 
-        thread::sleep(time::Duration::from_millis(100));
+        session::sleep(time::Duration::from_millis(100));
 
         Ok(())
     }
 
     pub fn reset_for_upload(&mut self) -> gpio::Result<()> {
-        self.flash_pin.set_low();
+        if self.reset_method != ResetMethod::Gpio {
+            return Ok(());
+        }
+
+        Self::set_pin(&mut self.flash_pin, "flash", false);
 
         self.reset()?;
 
-        self.flash_pin.set_high();
+        Self::set_pin(&mut self.flash_pin, "flash", true);
 
         Ok(())
     }
+
+    /// Resets the ESP into the application while `serial` is open, which is
+    /// the only way to reset it through DTR/RTS or USB-JTAG without closing
+    /// the port.
+    pub fn reset_with_serial(
+        &mut self,
+        serial: &mut Box<dyn serialport::SerialPort>,
+    ) -> Result<(), String> {
+        match self.reset_method {
+            ResetMethod::Gpio => self.reset_no_delay().map_err(|e| e.to_string()),
+            ResetMethod::DtrRts | ResetMethod::UsbJtag => {
+                // RTS is wired to EN and DTR to GPIO0 (inverted), same as the
+                // `hard_reset` of esptool
+                serial
+                    .write_data_terminal_ready(false)
+                    .and_then(|_| serial.write_request_to_send(true))
+                    .map_err(|e| format!("could not reset through serial port: {}", e))?;
+
+                session::sleep(time::Duration::from_millis(100));
+
+                serial
+                    .write_request_to_send(false)
+                    .map_err(|e| format!("could not reset through serial port: {}", e))
+            }
+        }
+    }
 }
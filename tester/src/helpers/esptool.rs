@@ -1,36 +1,56 @@
-use std::{io, process};
+use std::{env, fs, io, process};
 
 use rppal::gpio;
 
-use crate::esp;
+use crate::{esp, options::FlashMode, session, subprocess};
+
+/// Output fragments of `esptool` that point to a bad serial link, which
+/// usually goes away at a lower baud rate.
+const SYNC_ERRORS: [&str; 5] = [
+    "Failed to connect",
+    "Timed out waiting for packet",
+    "Invalid head of packet",
+    "Serial data stream stopped",
+    "Packet content transfer stopped",
+];
 
 pub struct ReadMacAddressResult {
     pub mac: String,
     pub log: String,
 }
 
-pub fn read_mac_address(esp: &mut esp::ESP) -> gpio::Result<ReadMacAddressResult> {
+pub fn read_mac_address(
+    esp: &mut esp::ESP,
+    step: &subprocess::Step,
+) -> gpio::Result<ReadMacAddressResult> {
     esp.reset_for_upload()?;
 
-    let c = process::Command::new("esptool")
-        .arg("--port")
-        .arg("/dev/ttyUSB0")
-        .arg("read_mac")
-        .output()?;
-
-    let output = String::from_utf8_lossy(&c.stdout);
-    println!("{}", output);
+    let (before, after) = esp.esptool_reset_args();
+    let c = subprocess::run(
+        process::Command::new("esptool")
+            .arg("--before")
+            .arg(before)
+            .arg("--after")
+            .arg(after)
+            .arg("--port")
+            .arg(&esp.port)
+            .arg("read_mac"),
+        step,
+    );
 
     esp.reset()?;
 
+    let c = c?;
+    let output = c.log;
+    tracing::debug!("{}", output);
+
     if !c.status.success() {
-        return Err(gpio::Error::Io(io::Error::new(
-            io::ErrorKind::Other,
-            format!("`esptool` exited with non-zero exit code: {output}"),
-        )));
+        return Err(gpio::Error::Io(io::Error::other(format!(
+            "`esptool` exited with non-zero exit code: {output}"
+        ))));
     }
 
-    let mac_address = output.lines().filter(|l| l.contains("MAC:")).nth(0);
+    let mac_address = output.lines().find(|l| l.contains("MAC:"));
     if mac_address.is_none() {
         return Err(gpio::Error::Io(io::Error::new(
             io::ErrorKind::Interrupted,
@@ -54,39 +74,222 @@ pub fn read_mac_address(esp: &mut esp::ESP) -> gpio::Result<ReadMacAddressResult
     })
 }
 
-pub fn write_flash(file: &str, esp: &mut esp::ESP, baudrate: u32) -> gpio::Result<String> {
+fn run_esptool_py(
+    esp: &mut esp::ESP,
+    baudrate: u32,
+    args: &[&str],
+    step: &subprocess::Step,
+) -> gpio::Result<String> {
     esp.reset_for_upload()?;
 
-    let c = process::Command::new("/usr/bin/python3")
-        .arg("/home/pi/.platformio/packages/tool-esptoolpy/esptool.py")
-        .arg("--before")
-        .arg("no_reset")
-        .arg("--after")
-        .arg("no_reset")
-        .arg("--chip")
-        .arg("esp8266")
-        .arg("--port")
-        .arg("/dev/ttyUSB0")
-        .arg("--baud")
-        .arg(baudrate.to_string())
-        .arg("write_flash")
-        .arg("-fm")
-        .arg("qio")
-        .arg("0x0000")
-        .arg(file)
-        .output()?;
-
-    let output = String::from_utf8_lossy(&c.stdout);
-    println!("{}", output);
+    let (before, after) = esp.esptool_reset_args();
+    let c = subprocess::run(
+        process::Command::new("/usr/bin/python3")
+            .arg("/home/pi/.platformio/packages/tool-esptoolpy/esptool.py")
+            .arg("--before")
+            .arg(before)
+            .arg("--after")
+            .arg(after)
+            .arg("--chip")
+            .arg(&esp.chip)
+            .arg("--port")
+            .arg(&esp.port)
+            .arg("--baud")
+            .arg(baudrate.to_string())
+            .args(args),
+        step,
+    );
 
     esp.reset()?;
 
+    let c = c?;
+    let output = c.log;
+    tracing::debug!("{}", output);
+
     if !c.status.success() {
-        return Err(gpio::Error::Io(io::Error::new(
-            io::ErrorKind::Other,
-            format!("`esptool` exited with non-zero exit code: {output}"),
-        )));
+        return Err(gpio::Error::Io(io::Error::other(format!(
+            "`esptool` exited with non-zero exit code: {output}"
+        ))));
+    }
+
+    Ok(output)
+}
+
+pub fn write_flash(
+    file: &str,
+    esp: &mut esp::ESP,
+    baudrate: u32,
+    mode: FlashMode,
+    step: &subprocess::Step,
+) -> gpio::Result<String> {
+    run_esptool_py(
+        esp,
+        baudrate,
+        &["write_flash", "-fm", mode.as_str(), "0x0000", file],
+        step,
+    )
+}
+
+/// Writes `data` to the flash at `offset`, leaving the rest of the flash untouched
+pub fn write_flash_region(
+    data: &[u8],
+    offset: u32,
+    esp: &mut esp::ESP,
+    baudrate: u32,
+    step: &subprocess::Step,
+) -> gpio::Result<String> {
+    let file = env::temp_dir().join(format!("tester-region-{offset:#x}.bin"));
+    fs::write(&file, data)?;
+
+    let result = run_esptool_py(
+        esp,
+        baudrate,
+        &[
+            "write_flash",
+            &format!("{offset:#x}"),
+            &file.to_string_lossy(),
+        ],
+        step,
+    );
+
+    let _ = fs::remove_file(&file);
+
+    result
+}
+
+pub fn read_flash_region(
+    offset: u32,
+    size: u32,
+    esp: &mut esp::ESP,
+    baudrate: u32,
+    step: &subprocess::Step,
+) -> gpio::Result<(Vec<u8>, String)> {
+    let file = env::temp_dir().join(format!("tester-region-{offset:#x}-read.bin"));
+
+    let result = run_esptool_py(
+        esp,
+        baudrate,
+        &[
+            "read_flash",
+            &format!("{offset:#x}"),
+            &format!("{size:#x}"),
+            &file.to_string_lossy(),
+        ],
+        step,
+    )
+    .and_then(|log| {
+        let data = session::interact("file", "read", || {
+            fs::read(&file).map_err(session::RecordedError::from)
+        })
+        .map_err(io::Error::from)?;
+
+        Ok((data, log))
+    });
+
+    let _ = fs::remove_file(&file);
+
+    result
+}
+
+pub struct WriteFlashAttempt {
+    pub baudrate: u32,
+    pub mode: FlashMode,
+    pub error: Option<String>,
+}
+
+pub struct WriteFlashResult {
+    pub baudrate: u32,
+    pub mode: FlashMode,
+    pub attempts: Vec<WriteFlashAttempt>,
+    pub log: String,
+}
+
+impl WriteFlashResult {
+    /// Whether the first (fastest, preferred) combination did not work
+    pub fn degraded(&self) -> bool {
+        self.attempts.len() > 1
+    }
+
+    pub fn summary(&self) -> String {
+        summarize_attempts(&self.attempts)
+    }
+}
+
+fn summarize_attempts(attempts: &[WriteFlashAttempt]) -> String {
+    attempts
+        .iter()
+        .map(|a| match &a.error {
+            Some(e) => format!(
+                "{} baud, {}: failed ({})",
+                a.baudrate,
+                a.mode,
+                e.lines().last().unwrap_or("")
+            ),
+            None => format!("{} baud, {}: ok", a.baudrate, a.mode),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Flashes `file`, falling back to the next baud rate on sync errors and to
+/// the next flash mode on write errors. Every mode starts at the first baud
+/// rate again.
+pub fn write_flash_with_fallback(
+    file: &str,
+    esp: &mut esp::ESP,
+    baudrates: &[u32],
+    modes: &[FlashMode],
+    step: &subprocess::Step,
+) -> gpio::Result<WriteFlashResult> {
+    let mut attempts = Vec::new();
+
+    for &mode in modes {
+        for &baudrate in baudrates {
+            match write_flash(file, esp, baudrate, mode, step) {
+                Ok(log) => {
+                    attempts.push(WriteFlashAttempt {
+                        baudrate,
+                        mode,
+                        error: None,
+                    });
+
+                    return Ok(WriteFlashResult {
+                        baudrate,
+                        mode,
+                        attempts,
+                        log,
+                    });
+                }
+                // The command could not be spawned at all, retrying won't help
+                Err(gpio::Error::Io(e))
+                    if !matches!(e.kind(), io::ErrorKind::Other | io::ErrorKind::TimedOut) =>
+                {
+                    return Err(gpio::Error::Io(e));
+                }
+                Err(e) => {
+                    let error = e.to_string();
+
+                    // A hanging transfer is most likely a bad link as well
+                    let timed_out =
+                        matches!(&e, gpio::Error::Io(e) if e.kind() == io::ErrorKind::TimedOut);
+                    let sync_error = timed_out || SYNC_ERRORS.iter().any(|s| error.contains(s));
+
+                    attempts.push(WriteFlashAttempt {
+                        baudrate,
+                        mode,
+                        error: Some(error),
+                    });
+
+                    if !sync_error {
+                        break;
+                    }
+                }
+            }
+        }
     }
 
-    Ok(output.clone().to_string())
+    Err(gpio::Error::Io(io::Error::other(format!(
+        "all flash attempts failed:\n{}",
+        summarize_attempts(&attempts)
+    ))))
 }
@@ -0,0 +1,266 @@
+use std::fmt;
+
+use dut::regex;
+
+use super::serial;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Fatal,
+}
+
+impl Level {
+    pub fn parse(level: &str) -> Option<Level> {
+        match level.trim() {
+            "TRACE" => Some(Level::Trace),
+            "DEBUG" => Some(Level::Debug),
+            "INFO" => Some(Level::Info),
+            "WARN" => Some(Level::Warn),
+            "ERROR" => Some(Level::Error),
+            "FATAL" => Some(Level::Fatal),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Trace => "TRACE",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+            Level::Fatal => "FATAL",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// One line of firmware output. The firmware logs as
+/// `[LEVEL] [Prefix:Tag] message`, anything else (ROM output, command
+/// answers without logger) has no level or tag and the whole line as
+/// message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub level: Option<Level>,
+    pub tag: Option<String>,
+    /// Index of the sensor for tags like `BNO080Sensor:0`
+    pub sensor: Option<u32>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    SensorFound {
+        sensor: Option<u32>,
+        imu: String,
+        address: String,
+    },
+    SensorError {
+        sensor: Option<u32>,
+        message: String,
+    },
+    WifiConnected {
+        ssid: String,
+        ip: String,
+    },
+    Battery {
+        voltage: f32,
+        level: f32,
+    },
+    /// The firmware started, `version` as printed in the startup message
+    Boot {
+        version: String,
+    },
+    /// Reset reason as printed by the ROM bootloader
+    Reset {
+        cause: u32,
+        reason: Option<String>,
+    },
+}
+
+impl Entry {
+    pub fn parse(line: &str) -> Entry {
+        let re = regex!(r"^\[(?P<level>[A-Z]+) *\] \[(?P<tag>[^\]]*)\] (?P<message>.*)$");
+
+        let Some(c) = re.captures(line.trim_end()) else {
+            return Entry {
+                level: None,
+                tag: None,
+                sensor: None,
+                message: line.trim_end().to_string(),
+            };
+        };
+
+        let tag = c["tag"].to_string();
+        let sensor = tag
+            .split_once(':')
+            .filter(|(prefix, _)| prefix.contains("Sensor"))
+            .and_then(|(_, index)| index.parse().ok());
+
+        Entry {
+            level: Level::parse(&c["level"]),
+            tag: Some(tag),
+            sensor,
+            message: c["message"].to_string(),
+        }
+    }
+
+    /// Whether this is a warning or worse
+    pub fn is_problem(&self) -> bool {
+        self.level.is_some_and(|l| l >= Level::Warn)
+    }
+
+    pub fn event(&self) -> Option<Event> {
+        let message = self.message.as_str();
+
+        if let Some(c) = regex!(r"Connected to (?P<imu>\S+) on (?P<address>0x[0-9a-fA-F]+)")
+            .captures(message)
+        {
+            return Some(Event::SensorFound {
+                sensor: self.sensor,
+                imu: c["imu"].to_string(),
+                address: c["address"].to_string(),
+            });
+        }
+
+        if (self.sensor.is_some() && self.level.is_some_and(|l| l >= Level::Error))
+            || message.starts_with("Can't find I2C device")
+        {
+            return Some(Event::SensorError {
+                sensor: self.sensor,
+                message: message.to_string(),
+            });
+        }
+
+        if let Some(c) = regex!(r"Connected successfully to SSID '(?P<ssid>.*)', IP address (?P<ip>\S+)")
+            .captures(message)
+        {
+            return Some(Event::WifiConnected {
+                ssid: c["ssid"].to_string(),
+                ip: c["ip"].to_string(),
+            });
+        }
+
+        if let Some(c) = regex!(r"Battery voltage: (?P<voltage>[\d.]+), level: (?P<level>[\d.]+)%")
+            .captures(message)
+        {
+            return Some(Event::Battery {
+                voltage: c["voltage"].parse().ok()?,
+                level: c["level"].parse().ok()?,
+            });
+        }
+
+        if let Some(c) = regex!(r"SlimeVR (?P<version>\S+) starting up").captures(message) {
+            return Some(Event::Boot {
+                version: c["version"].to_string(),
+            });
+        }
+
+        // ESP8266: `rst cause:2, boot mode:(3,6)`,
+        // ESP32: `rst:0x1 (POWERON_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)`
+        if let Some(c) = regex!(r"rst cause:(?P<cause>\d+)").captures(message) {
+            return Some(Event::Reset {
+                cause: c["cause"].parse().ok()?,
+                reason: None,
+            });
+        }
+
+        if let Some(c) = regex!(r"^rst:0x(?P<cause>[0-9a-fA-F]+) \((?P<reason>\w+)\)").captures(message) {
+            return Some(Event::Reset {
+                cause: u32::from_str_radix(&c["cause"], 16).ok()?,
+                reason: Some(c["reason"].to_string()),
+            });
+        }
+
+        None
+    }
+}
+
+/// Warnings and errors in `lines`, with their level
+pub fn problems<'a>(lines: impl IntoIterator<Item = &'a serial::Line>) -> Vec<(Level, String)> {
+    lines
+        .into_iter()
+        .filter_map(|l| {
+            Entry::parse(&l.text)
+                .level
+                .filter(|level| *level >= Level::Warn)
+                .map(|level| (level, l.text.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_level_tag_and_sensor() {
+        let entry = Entry::parse("[INFO ] [BNO080Sensor:0] Connected to BNO085 on 0x4a\r\n");
+
+        assert_eq!(entry.level, Some(Level::Info));
+        assert_eq!(entry.tag.as_deref(), Some("BNO080Sensor:0"));
+        assert_eq!(entry.sensor, Some(0));
+        assert_eq!(
+            entry.event(),
+            Some(Event::SensorFound {
+                sensor: Some(0),
+                imu: "BNO085".to_string(),
+                address: "0x4a".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_lines_without_logger_as_message() {
+        let entry = Entry::parse("[TEST] Board: 1, hardware: 2");
+
+        assert_eq!(entry.level, None);
+        assert_eq!(entry.tag, None);
+        assert_eq!(entry.message, "[TEST] Board: 1, hardware: 2");
+        assert!(!entry.is_problem());
+    }
+
+    #[test]
+    fn parses_esp8266_rom_reset() {
+        let entry = Entry::parse(" ets Jan  8 2013,rst cause:2, boot mode:(3,6)");
+
+        assert_eq!(
+            entry.event(),
+            Some(Event::Reset {
+                cause: 2,
+                reason: None,
+            })
+        );
+    }
+
+    #[test]
+    fn parses_esp32_rom_reset() {
+        let entry = Entry::parse("rst:0x1 (POWERON_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)");
+
+        assert_eq!(
+            entry.event(),
+            Some(Event::Reset {
+                cause: 1,
+                reason: Some("POWERON_RESET".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn warnings_are_problems() {
+        let entry = Entry::parse("[WARN ] [WiFiHandler] Can't connect from any credentials");
+
+        assert_eq!(entry.level, Some(Level::Warn));
+        assert!(entry.is_problem());
+    }
+}
@@ -0,0 +1,168 @@
+use std::{sync, time};
+
+use crate::usb;
+
+const CLEAR_FEATURE: u8 = 0x01;
+const SET_FEATURE: u8 = 0x03;
+const PORT_POWER: u16 = 8;
+
+/// Something that can switch VBUS of a single downstream port
+pub trait PortPower: Send {
+    fn set_port_power(&mut self, port: u8, on: bool) -> Result<(), String>;
+}
+
+/// A USB hub supporting per-port power switching (PPPS)
+pub struct UsbHub {
+    handle: rusb::DeviceHandle<rusb::GlobalContext>,
+}
+
+impl UsbHub {
+    pub fn open(vendor_id: u16, product_id: u16, port_path: Option<&str>) -> Result<Self, String> {
+        let devices = rusb::devices().map_err(|e| format!("could not list USB devices: {}", e))?;
+
+        for device in devices.iter() {
+            let Ok(device_desc) = device.device_descriptor() else {
+                continue;
+            };
+
+            if (device_desc.vendor_id() != vendor_id) || (device_desc.product_id() != product_id) {
+                continue;
+            }
+
+            if let Some(port_path) = port_path {
+                let matches = usb::UsbDeviceInfo::from_device(&device)
+                    .is_some_and(|d| d.port_path == port_path);
+                if !matches {
+                    continue;
+                }
+            }
+
+            let handle = device
+                .open()
+                .map_err(|e| format!("could not open USB hub: {}", e))?;
+
+            return Ok(UsbHub { handle });
+        }
+
+        Err(format!(
+            "USB hub {:04x}:{:04x} not found",
+            vendor_id, product_id
+        ))
+    }
+}
+
+impl PortPower for UsbHub {
+    fn set_port_power(&mut self, port: u8, on: bool) -> Result<(), String> {
+        let request_type = rusb::request_type(
+            rusb::Direction::Out,
+            rusb::RequestType::Class,
+            rusb::Recipient::Other,
+        );
+        let request = if on { SET_FEATURE } else { CLEAR_FEATURE };
+
+        self.handle
+            .write_control(
+                request_type,
+                request,
+                PORT_POWER,
+                port as u16,
+                &[],
+                time::Duration::from_secs(1),
+            )
+            .map(|_| ())
+            .map_err(|e| format!("could not switch power of hub port {}: {}", port, e))
+    }
+}
+
+/// Hub without hardware behind it, records every switch for inspection.
+/// Nothing is disconnected, so it is only useful for replays and tests.
+#[derive(Default, Clone)]
+pub struct SimulatedHub {
+    /// Shared between clones, so switches can be inspected after the hub
+    /// was handed to a [`PowerSwitch`]
+    pub switches: sync::Arc<sync::Mutex<Vec<(u8, bool)>>>,
+}
+
+impl PortPower for SimulatedHub {
+    fn set_port_power(&mut self, port: u8, on: bool) -> Result<(), String> {
+        self.switches.lock().unwrap().push((port, on));
+
+        Ok(())
+    }
+}
+
+/// Maps test slots to hub ports and power cycles them
+pub struct PowerSwitch {
+    hub: Box<dyn PortPower>,
+    ports: Vec<u8>,
+    off_time: time::Duration,
+}
+
+impl PowerSwitch {
+    pub fn new(hub: Box<dyn PortPower>, ports: Vec<u8>, off_time: time::Duration) -> Self {
+        PowerSwitch {
+            hub,
+            ports,
+            off_time,
+        }
+    }
+
+    fn port(&self, slot: usize) -> Result<u8, String> {
+        self.ports
+            .get(slot)
+            .copied()
+            .ok_or_else(|| format!("no hub port configured for slot {}", slot))
+    }
+
+    /// How long a port stays off when power cycling
+    pub fn off_time(&self) -> time::Duration {
+        self.off_time
+    }
+
+    /// Switches off the port of `slot`, it should stay off for at least
+    /// [`PowerSwitch::off_time`]
+    pub fn power_off(&mut self, slot: usize) -> Result<(), String> {
+        let port = self.port(slot)?;
+
+        self.hub.set_port_power(port, false)
+    }
+
+    pub fn power_on(&mut self, slot: usize) -> Result<(), String> {
+        let port = self.port(slot)?;
+
+        self.hub.set_port_power(port, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switches_the_port_of_the_slot() {
+        let hub = SimulatedHub::default();
+        let mut power = PowerSwitch::new(
+            Box::new(hub.clone()),
+            vec![3, 1],
+            time::Duration::from_millis(0),
+        );
+
+        power.power_off(1).unwrap();
+        power.power_on(1).unwrap();
+
+        assert_eq!(*hub.switches.lock().unwrap(), vec![(1, false), (1, true)]);
+    }
+
+    #[test]
+    fn rejects_slots_without_port() {
+        let hub = SimulatedHub::default();
+        let mut power = PowerSwitch::new(
+            Box::new(hub.clone()),
+            vec![3],
+            time::Duration::from_millis(0),
+        );
+
+        assert!(power.power_off(1).is_err());
+        assert!(hub.switches.lock().unwrap().is_empty());
+    }
+}
@@ -1,14 +1,44 @@
 use colored::Colorize;
-use std::sync::mpsc;
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use std::{
+    collections::VecDeque,
+    io::Write,
+    sync::{mpsc, Arc},
+    time::Duration,
+};
+
+use crate::{control, diagnostics::OPERATOR, options::Output, server, Board};
+
+/// How many finished boards the dashboard lists
+const RECENT_RESULTS: usize = 8;
+
+/// How long to wait for log events before checking the keyboard again
+const KEYBOARD_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 enum Event {
     LogEvent(LogEvent),
     ResetRenderer,
+    Plan {
+        name: String,
+        steps: Vec<String>,
+    },
+    Step {
+        step: String,
+        failed: bool,
+    },
+    Finished(BoardResult),
+    Uploads {
+        pending: usize,
+        failed: usize,
+    },
+    /// Final report of a board
+    Report(Box<Board>),
 }
 
 enum LogEvent {
     Success(String),
     Error(String),
+    Warning(String),
     InProgress(String),
     Action(String),
     Fill(Color),
@@ -18,13 +48,168 @@ pub enum Color {
     Green,
     Red,
 }
+
+impl Color {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Color::Green => "green",
+            Color::Red => "red",
+        }
+    }
+}
+
+struct BoardResult {
+    id: Option<String>,
+    passed: bool,
+    failed_step: Option<String>,
+    cycle_time: Duration,
+}
+
+/// Counters since the tester started, and the progress of the current board
+#[derive(Default)]
+struct Dashboard {
+    passed: u32,
+    failed: u32,
+    last_cycle_time: Option<Duration>,
+    total_cycle_time: Duration,
+    plan: String,
+    /// Planned steps and whether they failed, `None` until they ran
+    steps: Vec<(String, Option<bool>)>,
+    recent: VecDeque<BoardResult>,
+    pending_uploads: usize,
+    failed_uploads: usize,
+}
+
+impl Dashboard {
+    fn lines(&self, columns: usize, running: bool) -> Vec<String> {
+        let tested = self.passed + self.failed;
+        let yield_ = if tested == 0 {
+            "-".to_string()
+        } else {
+            format!("{:.1}%", self.passed as f32 * 100.0 / tested as f32)
+        };
+        let average = if tested == 0 {
+            "-".to_string()
+        } else {
+            format_duration(self.total_cycle_time / tested)
+        };
+
+        let mut lines = vec![
+            format!(
+                "Passed: {}  Failed: {}  Yield: {}",
+                self.passed.to_string().color(colored::Color::Green),
+                self.failed.to_string().color(colored::Color::Red),
+                yield_
+            ),
+            format!(
+                "Cycle time: {} last, {} average",
+                self.last_cycle_time
+                    .map(format_duration)
+                    .unwrap_or("-".to_string()),
+                average
+            ),
+            format!(
+                "Uploads: {} pending, {} rejected",
+                self.pending_uploads, self.failed_uploads
+            ),
+        ];
+
+        if !self.steps.is_empty() {
+            let done = self.steps.iter().filter(|(_, r)| r.is_some()).count();
+            let width = self.steps.len() * 2;
+            let filled = done * width / self.steps.len();
+
+            lines.push(
+                "─"
+                    .repeat(columns)
+                    .color(colored::Color::BrightBlack)
+                    .to_string(),
+            );
+            lines.push(format!(
+                "{} [{}{}] {}/{}",
+                self.plan,
+                "#".repeat(filled),
+                "-".repeat(width - filled),
+                done,
+                self.steps.len()
+            ));
+
+            // Steps report when they are done, so the first one without a
+            // result is the one running
+            let current = self.steps.iter().position(|(_, r)| r.is_none());
+            for (i, (step, result)) in self.steps.iter().enumerate() {
+                lines.push(match result {
+                    Some(false) => format!("✓ {}", step)
+                        .color(colored::Color::Green)
+                        .to_string(),
+                    Some(true) => format!("╳ {}", step).color(colored::Color::Red).to_string(),
+                    None if running && current == Some(i) => format!("▶ {}", step),
+                    None => format!("· {}", step)
+                        .color(colored::Color::BrightBlack)
+                        .to_string(),
+                });
+            }
+        }
+
+        if !self.recent.is_empty() {
+            lines.push(
+                "─"
+                    .repeat(columns)
+                    .color(colored::Color::BrightBlack)
+                    .to_string(),
+            );
+            for board in &self.recent {
+                let id = board.id.as_deref().unwrap_or("unknown");
+                let time = format_duration(board.cycle_time);
+
+                lines.push(if board.passed {
+                    format!("✓ {} {}", id, time)
+                        .color(colored::Color::Green)
+                        .to_string()
+                } else {
+                    format!(
+                        "╳ {} {} {}",
+                        id,
+                        time,
+                        board.failed_step.as_deref().unwrap_or("")
+                    )
+                    .color(colored::Color::Red)
+                    .to_string()
+                });
+            }
+        }
+
+        lines.push(
+            "─"
+                .repeat(columns)
+                .color(colored::Color::BrightBlack)
+                .to_string(),
+        );
+
+        lines
+    }
+}
+
+fn format_duration(d: Duration) -> String {
+    format!("{:.1}s", d.as_secs_f32())
+}
+
 pub struct LoggerBuilder {}
 
 pub struct Renderer {
     rx: mpsc::Receiver<Event>,
     events: Vec<LogEvent>,
+    dashboard: Dashboard,
+    /// Whether keys are read, see [`Renderer::run`]
+    keyboard: bool,
+    /// Reason typed so far while scrapping a board
+    scrap_reason: Option<String>,
+    output: Output,
+    /// Gets all events for the HTTP API
+    hub: Option<Arc<server::Hub>>,
 }
 
+#[derive(Clone)]
 pub struct Logger {
     tx: mpsc::Sender<Event>,
 }
@@ -37,6 +222,11 @@ impl LoggerBuilder {
             Renderer {
                 rx,
                 events: Vec::new(),
+                dashboard: Dashboard::default(),
+                keyboard: false,
+                scrap_reason: None,
+                output: Output::Tui,
+                hub: None,
             },
             Logger { tx },
         )
@@ -53,21 +243,39 @@ impl Renderer {
 
         let (columns, rows) = crossterm::terminal::size().unwrap();
 
-        let lines = self
-            .events
-            .iter()
-            .map(|e| match &e {
-                LogEvent::Success(s) => Some((colored::Color::Green, s)),
-                LogEvent::Error(s) => Some((colored::Color::Red, s)),
-                LogEvent::InProgress(s) => Some((colored::Color::White, s)),
-                LogEvent::Action(s) => Some((colored::Color::BrightBlue, s)),
-                LogEvent::Fill(_) => None,
-            })
-            .filter_map(|e| e)
-            .map(|(color, s)| format!("{}", s.color(color)))
-            .collect::<Vec<String>>();
+        let running = matches!(self.events.last(), Some(LogEvent::InProgress(_)));
+        let mut lines = self.dashboard.lines(columns as usize, running);
+        if self.keyboard {
+            lines.push(
+                "[R]etest  [A]bort step  [S]kip  [X] scrap  [P]ause  [Q]uit"
+                    .color(colored::Color::BrightBlack)
+                    .to_string(),
+            );
+        }
+        if control::state().paused {
+            lines.push("Station paused".color(colored::Color::Yellow).to_string());
+        }
 
-        println!("{}", lines.join("\n"));
+        lines.extend(
+            self.events
+                .iter()
+                .filter_map(|e| match &e {
+                    LogEvent::Success(s) => Some((colored::Color::Green, "✓ ".to_string() + s)),
+                    LogEvent::Error(s) => Some((colored::Color::Red, "╳ ".to_string() + s)),
+                    LogEvent::Warning(s) => Some((colored::Color::Yellow, "! ".to_string() + s)),
+                    LogEvent::InProgress(s) => Some((colored::Color::White, s.clone())),
+                    LogEvent::Action(s) => Some((colored::Color::BrightBlue, s.clone())),
+                    LogEvent::Fill(_) => None,
+                })
+                .map(|(color, s)| format!("{}", s.color(color))),
+        );
+
+        if let Some(reason) = &self.scrap_reason {
+            lines.push(format!(
+                "Scrap reason (Enter to confirm, Esc to cancel): {}_",
+                reason
+            ));
+        }
 
         if let Some(LogEvent::Fill(color)) = self.events.last() {
             let color = match color {
@@ -77,67 +285,342 @@ impl Renderer {
 
             let s = "X".repeat(columns as usize);
 
-            let rows = rows as usize - lines.len() - 1;
+            let rows = (rows as usize).saturating_sub(lines.len() + 1);
             for _ in 0..rows {
-                println!("{}", s.on_color(color).color(color));
+                lines.push(s.on_color(color).color(color).to_string());
             }
         }
 
+        // Raw mode needs the carriage return
+        let mut stdout = std::io::stdout().lock();
+        write!(stdout, "{}\r\n", lines.join("\r\n"))?;
+        stdout.flush()?;
+
         Ok(())
     }
 
+    /// Applies a key the operator pressed, see [`control`]
+    fn key(&mut self, key: KeyEvent) {
+        if let Some(reason) = &mut self.scrap_reason {
+            match key.code {
+                KeyCode::Enter => {
+                    let reason = std::mem::take(reason);
+                    self.scrap_reason = None;
+                    self.scrap(reason.trim());
+                }
+                KeyCode::Esc => self.scrap_reason = None,
+                KeyCode::Backspace => {
+                    reason.pop();
+                }
+                KeyCode::Char(c) => reason.push(c),
+                _ => {}
+            }
+
+            return;
+        }
+
+        let mut state = control::state();
+        let message = match key.code {
+            // Raw mode swallows the signal of Ctrl+C
+            KeyCode::Char('q') | KeyCode::Char('c')
+                if key.code == KeyCode::Char('q')
+                    || key.modifiers.contains(KeyModifiers::CONTROL) =>
+            {
+                state.quit = true;
+                "Quitting after the current board"
+            }
+            KeyCode::Char('p') => {
+                state.paused = !state.paused;
+                if state.paused {
+                    "Station pauses after the current board"
+                } else {
+                    "Station resumed"
+                }
+            }
+            KeyCode::Char('r' | 'a' | 's' | 'x') if !state.testing => "No board is being tested",
+            KeyCode::Char('r') => {
+                state.retest = true;
+                "Board will be tested again"
+            }
+            KeyCode::Char('a') => {
+                control::abort_step();
+                "Aborting the running step"
+            }
+            KeyCode::Char('s') => {
+                control::abort_board();
+                state.note("skipped by operator", true);
+                "Skipping the board"
+            }
+            KeyCode::Char('x') => {
+                self.scrap_reason = Some(String::new());
+                return;
+            }
+            _ => return,
+        };
+        drop(state);
+
+        self.operator(message.to_string());
+    }
+
+    fn scrap(&mut self, reason: &str) {
+        if reason.is_empty() {
+            return;
+        }
+
+        {
+            let mut state = control::state();
+            if !state.testing {
+                drop(state);
+                self.operator("No board is being tested".to_string());
+                return;
+            }
+
+            control::abort_board();
+            state.note(format!("scrapped by operator: {}", reason), true);
+        }
+
+        self.operator(format!("Board scrapped: {}", reason));
+    }
+
+    /// Shows the answer to a key press
+    fn operator(&mut self, message: String) {
+        tracing::info!(target: OPERATOR, "{}", message);
+
+        self.dispatch(Event::LogEvent(LogEvent::Action(message)));
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::LogEvent(msg) => match msg {
+                LogEvent::InProgress(_) => {
+                    self.events.push(msg);
+                }
+                _ => {
+                    if let Some(LogEvent::InProgress(_)) = self.events.last() {
+                        self.events.pop();
+                    }
+
+                    self.events.push(msg);
+                }
+            },
+            Event::ResetRenderer => {
+                self.events.clear();
+                for (_, result) in &mut self.dashboard.steps {
+                    *result = None;
+                }
+            }
+            Event::Plan { name, steps } => {
+                self.dashboard.plan = name;
+                self.dashboard.steps = steps.into_iter().map(|s| (s, None)).collect();
+            }
+            Event::Step { step, failed } => {
+                // Steps can report several values, one failure fails it
+                if let Some((_, result)) = self.dashboard.steps.iter_mut().find(|(s, _)| *s == step)
+                {
+                    *result = Some(result.unwrap_or(false) || failed);
+                }
+            }
+            Event::Finished(board) => {
+                let dashboard = &mut self.dashboard;
+                if board.passed {
+                    dashboard.passed += 1;
+                } else {
+                    dashboard.failed += 1;
+                }
+                dashboard.last_cycle_time = Some(board.cycle_time);
+                dashboard.total_cycle_time += board.cycle_time;
+
+                dashboard.recent.push_front(board);
+                dashboard.recent.truncate(RECENT_RESULTS);
+            }
+            Event::Uploads { pending, failed } => {
+                self.dashboard.pending_uploads = pending;
+                self.dashboard.failed_uploads = failed;
+            }
+            // Reports are for other front ends, the operator saw the result
+            Event::Report(_) => {}
+        }
+    }
+
+    /// Handles `event` and passes it on to the HTTP API
+    fn dispatch(&mut self, event: Event) {
+        if let Some(hub) = &self.hub {
+            hub.publish(&json(&event));
+        }
+
+        self.handle(event);
+    }
+
+    /// Emits events as JSON lines instead of drawing them, see
+    /// [`Output::Json`]
+    pub fn set_output(&mut self, output: Output) {
+        self.output = output;
+    }
+
+    pub fn set_hub(&mut self, hub: Arc<server::Hub>) {
+        self.hub = Some(hub);
+    }
+
+    fn run_json(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        loop {
+            let event = self.rx.recv()?;
+            let line = json(&event);
+            if let Some(hub) = &self.hub {
+                hub.publish(&line);
+            }
+
+            let mut stdout = std::io::stdout().lock();
+            writeln!(stdout, "{}", line)?;
+            stdout.flush()?;
+        }
+    }
+
+    /// Draws events and reads the keyboard until the operator quits
     pub fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.output == Output::Json {
+            return self.run_json();
+        }
+
+        // Without a terminal (e.g. under systemd) there is no keyboard
+        self.keyboard = crossterm::terminal::enable_raw_mode().is_ok();
+
+        let result = self.run_until_quit();
+
+        if self.keyboard {
+            crossterm::terminal::disable_raw_mode()?;
+        }
+
+        result
+    }
+
+    fn run_until_quit(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         loop {
-            match self.rx.recv()? {
-                Event::LogEvent(msg) => {
-                    match msg {
-                        LogEvent::InProgress(_) => {
-                            self.events.push(msg);
-                        }
-                        _ => {
-                            if let Some(last_event) = self.events.last() {
-                                if let LogEvent::InProgress(_) = last_event {
-                                    self.events.pop();
-                                }
-                            }
-
-                            self.events.push(msg);
-                        }
-                    }
+            if control::state().can_quit() {
+                return Ok(());
+            }
+
+            let mut redraw = false;
 
-                    self.draw()?;
+            while self.keyboard && crossterm::event::poll(Duration::ZERO)? {
+                if let crossterm::event::Event::Key(key) = crossterm::event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        self.key(key);
+                        redraw = true;
+                    }
                 }
-                Event::ResetRenderer => {
-                    self.events.clear();
-                    self.draw()?;
+            }
+
+            match self.rx.recv_timeout(KEYBOARD_POLL_INTERVAL) {
+                Ok(event) => {
+                    self.dispatch(event);
+                    redraw = true;
                 }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            // Draw bursts of events at once
+            while let Ok(event) = self.rx.try_recv() {
+                self.dispatch(event);
+            }
+
+            if redraw {
+                self.draw()?;
             }
         }
     }
 }
 
+/// `event` as JSON object with its `time`
+fn json(event: &Event) -> serde_json::Value {
+    let mut json = match event {
+        Event::LogEvent(LogEvent::Success(message)) => {
+            serde_json::json!({ "event": "success", "message": message })
+        }
+        Event::LogEvent(LogEvent::Error(message)) => {
+            serde_json::json!({ "event": "error", "message": message })
+        }
+        Event::LogEvent(LogEvent::Warning(message)) => {
+            serde_json::json!({ "event": "warning", "message": message })
+        }
+        Event::LogEvent(LogEvent::InProgress(message)) => {
+            serde_json::json!({ "event": "in_progress", "message": message })
+        }
+        Event::LogEvent(LogEvent::Action(message)) => {
+            serde_json::json!({ "event": "action", "message": message })
+        }
+        Event::LogEvent(LogEvent::Fill(color)) => {
+            serde_json::json!({ "event": "fill", "color": color.as_str() })
+        }
+        Event::ResetRenderer => serde_json::json!({ "event": "reset" }),
+        Event::Plan { name, steps } => {
+            serde_json::json!({ "event": "plan", "name": name, "steps": steps })
+        }
+        Event::Step { step, failed } => {
+            serde_json::json!({ "event": "step", "step": step, "failed": failed })
+        }
+        Event::Finished(board) => serde_json::json!({
+            "event": "finished",
+            "id": board.id,
+            "passed": board.passed,
+            "failed_step": board.failed_step,
+            "cycle_time": board.cycle_time.as_secs_f64(),
+        }),
+        Event::Uploads { pending, failed } => {
+            serde_json::json!({ "event": "uploads", "pending": pending, "failed": failed })
+        }
+        Event::Report(board) => serde_json::json!({ "event": "report", "board": board }),
+    };
+
+    json["time"] = chrono::Utc::now()
+        .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+        .into();
+
+    json
+}
+
 impl Logger {
     pub fn success(&mut self, msg: &str) {
+        tracing::info!(target: OPERATOR, "{}", msg);
+
         self.tx
-            .send(Event::LogEvent(LogEvent::Success("✓ ".to_string() + msg)))
+            .send(Event::LogEvent(LogEvent::Success(msg.to_string())))
             .unwrap();
     }
 
     pub fn error(&mut self, msg: &str) {
+        tracing::error!(target: OPERATOR, "{}", msg);
+
         self.tx
-            .send(Event::LogEvent(LogEvent::Error("╳ ".to_string() + msg)))
+            .send(Event::LogEvent(LogEvent::Error(msg.to_string())))
+            .unwrap();
+    }
+
+    /// Shows a problem that doesn't fail the board
+    pub fn warning(&mut self, msg: &str) {
+        tracing::warn!(target: OPERATOR, "{}", msg);
+
+        self.tx
+            .send(Event::LogEvent(LogEvent::Warning(msg.to_string())))
             .unwrap();
     }
 
     pub fn in_progress(&mut self, msg: impl ToString) {
+        let msg = msg.to_string();
+        // Progress updates of subprocesses are frequent
+        tracing::debug!(target: OPERATOR, "{}", msg);
+
         self.tx
-            .send(Event::LogEvent(LogEvent::InProgress(msg.to_string())))
+            .send(Event::LogEvent(LogEvent::InProgress(msg)))
             .unwrap();
     }
 
     pub fn action(&mut self, msg: impl ToString) {
+        let msg = msg.to_string();
+        tracing::info!(target: OPERATOR, "{}", msg);
+
         self.tx
-            .send(Event::LogEvent(LogEvent::Action(msg.to_string())))
+            .send(Event::LogEvent(LogEvent::Action(msg)))
             .unwrap();
     }
 
@@ -150,4 +633,53 @@ impl Logger {
     pub fn reset(&mut self) {
         self.tx.send(Event::ResetRenderer).unwrap();
     }
+
+    /// Sets the test plan and the steps of its checklist
+    pub fn plan(&mut self, name: &str, steps: &[&str]) {
+        self.tx
+            .send(Event::Plan {
+                name: name.to_string(),
+                steps: steps.iter().map(|s| s.to_string()).collect(),
+            })
+            .unwrap();
+    }
+
+    /// Ticks `step` off the checklist
+    pub fn step(&mut self, step: &str, failed: bool) {
+        self.tx
+            .send(Event::Step {
+                step: step.to_string(),
+                failed,
+            })
+            .unwrap();
+    }
+
+    /// Counts a tested board, `cycle_time` is the time from connecting it to
+    /// its result
+    pub fn finished(&mut self, board: &Board, passed: bool, cycle_time: Duration) {
+        self.tx
+            .send(Event::Finished(BoardResult {
+                id: board.id.clone(),
+                passed,
+                failed_step: board
+                    .values
+                    .iter()
+                    .find(|v| v.failed)
+                    .map(|v| v.step.clone()),
+                cycle_time,
+            }))
+            .unwrap();
+    }
+
+    /// Publishes the final report of a board
+    pub fn report(&mut self, board: &Board) {
+        self.tx
+            .send(Event::Report(Box::new(board.clone())))
+            .unwrap();
+    }
+
+    /// Sets the number of reports waiting for upload and rejected by the server
+    pub fn uploads(&mut self, pending: usize, failed: usize) {
+        self.tx.send(Event::Uploads { pending, failed }).unwrap();
+    }
 }
@@ -1,7 +1,20 @@
 pub mod adc;
+pub mod control;
+pub mod crash;
+pub mod database;
+pub mod diagnostics;
 pub mod esp;
 pub mod esptool;
+pub mod firmware_log;
+pub mod hub;
 pub mod logger;
+pub mod outbox;
 pub mod pio;
-pub mod serial;
+pub mod provisioning;
+pub mod rom;
+pub mod serial_log;
+pub mod session;
+pub mod subprocess;
 pub mod usb;
+
+pub use dut::{firmware, serial};
@@ -0,0 +1,693 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{database::UploadStatus, Board};
+
+/// Sink of reports written before there were several
+const LEGACY_SINK: &str = "api";
+
+/// Retry state of a report for one sink
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub sink: String,
+    /// Failed attempts so far
+    pub attempts: u32,
+    pub next_attempt: chrono::DateTime<chrono::Utc>,
+    pub last_error: Option<String>,
+}
+
+/// A board whose report was not delivered to every sink yet
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub id: String,
+    pub board: Board,
+    /// Sinks still to deliver to
+    pub deliveries: Vec<Delivery>,
+    /// Whether a sink rejected the report
+    pub rejected: bool,
+}
+
+/// A report to send to a sink now
+#[derive(Debug, Clone)]
+pub struct Due {
+    pub id: String,
+    pub sink: String,
+    pub board: Board,
+    pub attempts: u32,
+}
+
+/// One line of the outbox file
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Add {
+        id: String,
+        board: Board,
+        #[serde(default)]
+        sinks: Option<Vec<String>>,
+        /// A sink rejected the report before the outbox was compacted
+        #[serde(default)]
+        rejected: bool,
+    },
+    /// The board of a report changed, every sink gets it again
+    Update {
+        id: String,
+        board: Board,
+        sinks: Vec<String>,
+    },
+    Retry {
+        id: String,
+        #[serde(default)]
+        sink: Option<String>,
+        attempts: u32,
+        next_attempt: chrono::DateTime<chrono::Utc>,
+        error: String,
+    },
+    Done {
+        id: String,
+        #[serde(default)]
+        sink: Option<String>,
+        #[serde(default)]
+        rejected: bool,
+    },
+}
+
+/// A report a sink rejected for good, kept for a human to look at
+#[derive(Serialize, Deserialize)]
+struct DeadLetter {
+    #[serde(default)]
+    sink: Option<String>,
+    board: Board,
+    attempts: u32,
+    error: String,
+    rejected_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// How long to wait before retrying a failed upload
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// Doubles with every failed attempt, starting at `min`
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+
+        self.min.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Reports waiting for delivery, kept in an append-only file so they survive
+/// crashes and restarts. Every change is written before it is applied, the
+/// file is compacted when it is opened.
+pub struct Outbox {
+    dead_letters_path: PathBuf,
+    file: fs::File,
+    entries: Vec<Entry>,
+    /// Names of the configured sinks, every report goes to each of them
+    sinks: Vec<String>,
+    dead_letters: usize,
+    backoff: Backoff,
+}
+
+fn append(file: &mut fs::File, line: &impl Serialize) -> io::Result<()> {
+    let mut line = serde_json::to_vec(line)?;
+    line.push(b'\n');
+
+    file.write_all(&line)?;
+    file.sync_data()
+}
+
+fn delivery(sink: String) -> Delivery {
+    Delivery {
+        sink,
+        attempts: 0,
+        next_attempt: chrono::Utc::now(),
+        last_error: None,
+    }
+}
+
+/// Replaces the board of report `id`, queueing it again for the sinks that
+/// already got the old one
+fn update(entries: &mut Vec<Entry>, id: String, board: Board, sinks: &[String]) {
+    let Some(entry) = entries.iter_mut().find(|e| e.id == id) else {
+        entries.push(Entry {
+            id,
+            board,
+            deliveries: sinks.iter().cloned().map(delivery).collect(),
+            rejected: false,
+        });
+        return;
+    };
+
+    entry.board = board;
+    for sink in sinks {
+        if !entry.deliveries.iter().any(|d| d.sink == *sink) {
+            entry.deliveries.push(delivery(sink.clone()));
+        }
+    }
+}
+
+/// Applies the records of an outbox file, skipping lines that can't be
+/// read, e.g. one cut off by a crash
+fn load(path: &Path) -> io::Result<Vec<Entry>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries = Vec::<Entry>::new();
+
+    for line in io::BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record = match serde_json::from_str::<Record>(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                tracing::warn!("skipping unreadable outbox record: {}", e);
+                continue;
+            }
+        };
+
+        match record {
+            Record::Add {
+                id,
+                board,
+                sinks,
+                rejected,
+            } => entries.push(Entry {
+                id,
+                board,
+                deliveries: sinks
+                    .unwrap_or(vec![LEGACY_SINK.to_string()])
+                    .into_iter()
+                    .map(delivery)
+                    .collect(),
+                rejected,
+            }),
+            Record::Update { id, board, sinks } => update(&mut entries, id, board, &sinks),
+            Record::Retry {
+                id,
+                sink,
+                attempts,
+                next_attempt,
+                error,
+            } => {
+                let sink = sink.as_deref().unwrap_or(LEGACY_SINK);
+                let delivery = entries
+                    .iter_mut()
+                    .filter(|e| e.id == id)
+                    .flat_map(|e| &mut e.deliveries)
+                    .find(|d| d.sink == sink);
+
+                if let Some(delivery) = delivery {
+                    delivery.attempts = attempts;
+                    delivery.next_attempt = next_attempt;
+                    delivery.last_error = Some(error);
+                }
+            }
+            Record::Done { id, sink, rejected } => {
+                let sink = sink.as_deref().unwrap_or(LEGACY_SINK);
+
+                if let Some(entry) = entries.iter_mut().find(|e| e.id == id) {
+                    entry.deliveries.retain(|d| d.sink != sink);
+                    entry.rejected |= rejected;
+                }
+                entries.retain(|e| !e.deliveries.is_empty());
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+impl Outbox {
+    /// Opens `outbox.jsonl` in `dir`, rejected reports go to
+    /// `dead_letters.jsonl`. Deliveries to a sink that is no longer
+    /// configured are kept until it is configured again or they are dropped
+    /// with [`Outbox::drop_sink`].
+    pub fn open(dir: &Path, sinks: Vec<String>, backoff: Backoff) -> io::Result<Outbox> {
+        fs::create_dir_all(dir)?;
+
+        let path = dir.join("outbox.jsonl");
+        let dead_letters_path = dir.join("dead_letters.jsonl");
+
+        let entries = load(&path)?;
+
+        // Only what is still pending is kept
+        let compacted = path.with_extension("jsonl.tmp");
+        {
+            let mut file = fs::File::create(&compacted)?;
+            for entry in &entries {
+                append(
+                    &mut file,
+                    &Record::Add {
+                        id: entry.id.clone(),
+                        board: entry.board.clone(),
+                        sinks: Some(entry.deliveries.iter().map(|d| d.sink.clone()).collect()),
+                        rejected: entry.rejected,
+                    },
+                )?;
+
+                for delivery in &entry.deliveries {
+                    if let Some(error) = &delivery.last_error {
+                        append(
+                            &mut file,
+                            &Record::Retry {
+                                id: entry.id.clone(),
+                                sink: Some(delivery.sink.clone()),
+                                attempts: delivery.attempts,
+                                next_attempt: delivery.next_attempt,
+                                error: error.clone(),
+                            },
+                        )?;
+                    }
+                }
+            }
+        }
+        fs::rename(&compacted, &path)?;
+
+        let dead_letters = match fs::File::open(&dead_letters_path) {
+            Ok(file) => io::BufReader::new(file).lines().count(),
+            Err(_) => 0,
+        };
+
+        let outbox = Outbox {
+            file: fs::OpenOptions::new().append(true).open(&path)?,
+            dead_letters_path,
+            entries,
+            sinks,
+            dead_letters,
+            backoff,
+        };
+
+        for (sink, reports) in outbox.pending() {
+            if !outbox.sinks.contains(&sink) {
+                tracing::warn!(
+                    "{} reports wait for sink {}, which is no longer configured. They are \
+                     kept until it is configured again or dropped with `tester outbox drop {}`.",
+                    reports,
+                    sink,
+                    sink
+                );
+            }
+        }
+
+        Ok(outbox)
+    }
+
+    /// Number of reports waiting for each sink, including sinks that are no
+    /// longer configured
+    pub fn pending(&self) -> BTreeMap<String, usize> {
+        let mut pending = BTreeMap::new();
+        for delivery in self.entries.iter().flat_map(|e| &e.deliveries) {
+            *pending.entry(delivery.sink.clone()).or_default() += 1;
+        }
+
+        pending
+    }
+
+    /// Gives up delivering to `sink`, its reports go to the dead letters.
+    /// Returns how many there were.
+    pub fn drop_sink(&mut self, sink: &str) -> io::Result<usize> {
+        let ids = self
+            .entries
+            .iter()
+            .filter(|e| e.deliveries.iter().any(|d| d.sink == sink))
+            .map(|e| e.id.clone())
+            .collect::<Vec<_>>();
+
+        for id in &ids {
+            self.reject(id, sink, "dropped by operator")?;
+        }
+
+        Ok(ids.len())
+    }
+
+    /// Stores `board` for delivery to every sink, it is on disk once this
+    /// returns. Returns the id of the report.
+    pub fn push(&mut self, board: Board) -> io::Result<String> {
+        let id = uuid::Uuid::new_v4().to_string();
+
+        append(
+            &mut self.file,
+            &Record::Add {
+                id: id.clone(),
+                board: board.clone(),
+                sinks: Some(self.sinks.clone()),
+                rejected: false,
+            },
+        )?;
+
+        self.entries.push(Entry {
+            id: id.clone(),
+            board,
+            deliveries: self.sinks.iter().cloned().map(delivery).collect(),
+            rejected: false,
+        });
+
+        Ok(id)
+    }
+
+    /// Replaces the board of report `id`, e.g. with notes the operator added
+    /// after its result. Every sink gets the report again under the same id,
+    /// including those that already got the old board.
+    pub fn update(&mut self, id: &str, board: Board) -> io::Result<()> {
+        append(
+            &mut self.file,
+            &Record::Update {
+                id: id.to_string(),
+                board: board.clone(),
+                sinks: self.sinks.clone(),
+            },
+        )?;
+
+        update(&mut self.entries, id.to_string(), board, &self.sinks);
+
+        Ok(())
+    }
+
+    /// Deliveries to configured sinks whose next attempt is due, oldest
+    /// report first
+    pub fn due(&self) -> Vec<Due> {
+        let now = chrono::Utc::now();
+
+        self.entries
+            .iter()
+            .flat_map(|e| {
+                e.deliveries
+                    .iter()
+                    .filter(|d| d.next_attempt <= now && self.sinks.contains(&d.sink))
+                    .map(|d| Due {
+                        id: e.id.clone(),
+                        sink: d.sink.clone(),
+                        board: e.board.clone(),
+                        attempts: d.attempts,
+                    })
+            })
+            .collect()
+    }
+
+    /// Ends the delivery of a report to `sink`. Returns the status of the
+    /// report once every sink is done.
+    fn done(&mut self, id: &str, sink: &str, rejected: bool) -> io::Result<Option<UploadStatus>> {
+        append(
+            &mut self.file,
+            &Record::Done {
+                id: id.to_string(),
+                sink: Some(sink.to_string()),
+                rejected,
+            },
+        )?;
+
+        let Some(index) = self.entries.iter().position(|e| e.id == id) else {
+            return Ok(None);
+        };
+
+        let entry = &mut self.entries[index];
+        entry.deliveries.retain(|d| d.sink != sink);
+        entry.rejected |= rejected;
+
+        if !entry.deliveries.is_empty() {
+            return Ok(None);
+        }
+
+        let entry = self.entries.remove(index);
+        Ok(Some(if entry.rejected {
+            UploadStatus::Rejected
+        } else {
+            UploadStatus::Uploaded
+        }))
+    }
+
+    /// `sink` accepted the report
+    pub fn delivered(&mut self, id: &str, sink: &str) -> io::Result<Option<UploadStatus>> {
+        self.done(id, sink, false)
+    }
+
+    /// Delivering to `sink` failed but might work later, retries after a
+    /// back-off
+    pub fn failed(&mut self, id: &str, sink: &str, error: &str) -> io::Result<()> {
+        let delivery = self
+            .entries
+            .iter_mut()
+            .filter(|e| e.id == id)
+            .flat_map(|e| &mut e.deliveries)
+            .find(|d| d.sink == sink);
+        let Some(delivery) = delivery else {
+            return Ok(());
+        };
+
+        let attempts = delivery.attempts + 1;
+        let next_attempt = chrono::Utc::now()
+            + chrono::Duration::from_std(self.backoff.delay(attempts))
+                .unwrap_or(chrono::Duration::zero());
+
+        append(
+            &mut self.file,
+            &Record::Retry {
+                id: id.to_string(),
+                sink: Some(sink.to_string()),
+                attempts,
+                next_attempt,
+                error: error.to_string(),
+            },
+        )?;
+
+        delivery.attempts = attempts;
+        delivery.next_attempt = next_attempt;
+        delivery.last_error = Some(error.to_string());
+
+        Ok(())
+    }
+
+    /// `sink` rejected the report for good, adds it to the dead letters
+    pub fn reject(
+        &mut self,
+        id: &str,
+        sink: &str,
+        error: &str,
+    ) -> io::Result<Option<UploadStatus>> {
+        let Some(entry) = self.entries.iter().find(|e| e.id == id) else {
+            return Ok(None);
+        };
+        let attempts = entry
+            .deliveries
+            .iter()
+            .find(|d| d.sink == sink)
+            .map(|d| d.attempts)
+            .unwrap_or(0);
+
+        let mut dead_letters = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.dead_letters_path)?;
+        append(
+            &mut dead_letters,
+            &DeadLetter {
+                sink: Some(sink.to_string()),
+                board: entry.board.clone(),
+                attempts: attempts + 1,
+                error: error.to_string(),
+                rejected_at: chrono::Utc::now(),
+            },
+        )?;
+        self.dead_letters += 1;
+
+        self.done(id, sink, true)
+    }
+
+    /// Reports waiting for delivery to at least one sink
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Reports the sinks rejected, including earlier runs
+    pub fn dead_letters(&self) -> usize {
+        self.dead_letters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKOFF: Backoff = Backoff {
+        min: Duration::from_secs(1),
+        max: Duration::from_secs(10),
+    };
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(uuid::Uuid::new_v4().to_string())
+    }
+
+    fn sinks(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn skips_a_cut_off_line() {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir).unwrap();
+
+        let add = |id: &str| Record::Add {
+            id: id.to_string(),
+            board: Board::new(),
+            sinks: Some(sinks(&["api"])),
+            rejected: false,
+        };
+        let retry = Record::Retry {
+            id: "b".to_string(),
+            sink: Some("api".to_string()),
+            attempts: 2,
+            next_attempt: chrono::Utc::now(),
+            error: "timeout".to_string(),
+        };
+
+        let mut file = fs::File::create(dir.join("outbox.jsonl")).unwrap();
+        append(&mut file, &add("a")).unwrap();
+        file.write_all(br#"{"op":"done","id":"a","si"#).unwrap();
+        file.write_all(b"\n").unwrap();
+        append(&mut file, &add("b")).unwrap();
+        append(&mut file, &retry).unwrap();
+
+        let entries = load(&dir.join("outbox.jsonl")).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].id, "a");
+        assert_eq!(entries[1].deliveries[0].attempts, 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_pending_deliveries_when_compacted() {
+        let dir = temp_dir();
+
+        let mut outbox = Outbox::open(&dir, sinks(&["api", "file"]), BACKOFF).unwrap();
+        let a = outbox.push(Board::new()).unwrap();
+        let b = outbox.push(Board::new()).unwrap();
+        outbox.failed(&b, "api", "timeout").unwrap();
+        assert_eq!(outbox.delivered(&a, "api").unwrap(), None);
+        assert_eq!(
+            outbox.delivered(&a, "file").unwrap(),
+            Some(UploadStatus::Uploaded)
+        );
+        drop(outbox);
+
+        let outbox = Outbox::open(&dir, sinks(&["api", "file"]), BACKOFF).unwrap();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox.entries[0].id, b);
+
+        let api = &outbox.entries[0].deliveries[0];
+        assert_eq!(api.sink, "api");
+        assert_eq!(api.attempts, 1);
+        assert_eq!(api.last_error.as_deref(), Some("timeout"));
+
+        // Only the pending report and its retry are left
+        let lines = fs::read_to_string(dir.join("outbox.jsonl")).unwrap();
+        assert_eq!(lines.lines().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sends_updated_reports_again() {
+        let dir = temp_dir();
+
+        let mut outbox = Outbox::open(&dir, sinks(&["api", "file"]), BACKOFF).unwrap();
+        let a = outbox.push(Board::new()).unwrap();
+        let b = outbox.push(Board::new()).unwrap();
+        outbox.delivered(&a, "api").unwrap();
+        outbox.delivered(&b, "api").unwrap();
+        outbox.delivered(&b, "file").unwrap();
+
+        let mut noted = Board::new();
+        noted.id = Some("84:f3:eb:12:34:56".to_string());
+        outbox.update(&a, noted.clone()).unwrap();
+        outbox.update(&b, noted).unwrap();
+        drop(outbox);
+
+        let outbox = Outbox::open(&dir, sinks(&["api", "file"]), BACKOFF).unwrap();
+        let due = outbox
+            .due()
+            .into_iter()
+            .map(|d| (d.id, d.sink, d.board.id))
+            .collect::<Vec<_>>();
+        let mac = Some("84:f3:eb:12:34:56".to_string());
+        assert_eq!(
+            due,
+            [
+                (a.clone(), "file".to_string(), mac.clone()),
+                (a, "api".to_string(), mac.clone()),
+                (b.clone(), "api".to_string(), mac.clone()),
+                (b, "file".to_string(), mac),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_deliveries_to_removed_sinks() {
+        let dir = temp_dir();
+
+        let mut outbox = Outbox::open(&dir, sinks(&["api", "file"]), BACKOFF).unwrap();
+        let id = outbox.push(Board::new()).unwrap();
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&dir, sinks(&["api"]), BACKOFF).unwrap();
+        assert_eq!(outbox.dead_letters(), 0);
+        assert_eq!(outbox.due().len(), 1);
+        assert_eq!(outbox.due()[0].sink, "api");
+        assert_eq!(outbox.delivered(&id, "api").unwrap(), None);
+        assert!(outbox.due().is_empty());
+        assert_eq!(outbox.len(), 1);
+        drop(outbox);
+
+        // Until the sink is back
+        let outbox = Outbox::open(&dir, sinks(&["api", "file"]), BACKOFF).unwrap();
+        assert_eq!(outbox.due().len(), 1);
+        assert_eq!(outbox.due()[0].sink, "file");
+        drop(outbox);
+
+        // Or dropped
+        let mut outbox = Outbox::open(&dir, sinks(&[]), BACKOFF).unwrap();
+        assert_eq!(outbox.pending(), BTreeMap::from([("file".to_string(), 1)]));
+        assert_eq!(outbox.drop_sink("file").unwrap(), 1);
+        assert_eq!(outbox.dead_letters(), 1);
+        assert!(outbox.is_empty());
+        drop(outbox);
+
+        let outbox = Outbox::open(&dir, sinks(&["api", "file"]), BACKOFF).unwrap();
+        assert!(outbox.is_empty());
+        assert_eq!(outbox.dead_letters(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        assert_eq!(BACKOFF.delay(1), Duration::from_secs(1));
+        assert_eq!(BACKOFF.delay(2), Duration::from_secs(2));
+        assert_eq!(BACKOFF.delay(4), Duration::from_secs(8));
+        assert_eq!(BACKOFF.delay(5), Duration::from_secs(10));
+        assert_eq!(BACKOFF.delay(u32::MAX), Duration::from_secs(10));
+    }
+}
@@ -1,55 +1,189 @@
-use std::{io, process};
+use std::{fs, io, path, process};
 
 use rppal::gpio;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::esp;
+use crate::{esp, subprocess};
 
-pub fn build(environment: &str) -> gpio::Result<()> {
-    let c = process::Command::new("pio")
-        .arg("run")
-        .arg("-e")
-        .arg(environment)
-        .current_dir("/home/pi/slimevr-tracker-esp")
+const BUILD_INFO_FILE: &str = "tester-build.json";
+
+/// Commit and version of firmware built outside of a git checkout
+const UNKNOWN: &str = "unknown";
+
+/// What firmware was built, stored next to the artifact so unchanged builds
+/// can be skipped
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildInfo {
+    pub environment: String,
+    pub commit: String,
+    pub dirty: bool,
+    pub version: String,
+    pub built_at: chrono::DateTime<chrono::Utc>,
+    pub artifact: String,
+    pub artifact_sha256: String,
+}
+
+pub fn build_dir(project: &str, environment: &str) -> path::PathBuf {
+    path::Path::new(project)
+        .join(".pio")
+        .join("build")
+        .join(environment)
+}
+
+pub fn artifact_path(project: &str, environment: &str) -> path::PathBuf {
+    build_dir(project, environment).join("firmware.bin")
+}
+
+/// ELF with the debug info of the artifact, used to decode crashes
+pub fn elf_path(project: &str, environment: &str) -> path::PathBuf {
+    build_dir(project, environment).join("firmware.elf")
+}
+
+fn git(project: &str, args: &[&str]) -> gpio::Result<String> {
+    let c = process::Command::new("git")
+        .args(args)
+        .current_dir(project)
         .output()?;
 
-    let output = String::from_utf8_lossy(&c.stdout);
-    println!("{}", output);
+    if !c.status.success() {
+        return Err(gpio::Error::Io(io::Error::other(format!(
+            "`git {}` exited with non-zero exit code: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&c.stderr)
+        ))));
+    }
+
+    Ok(String::from_utf8_lossy(&c.stdout).trim().to_string())
+}
+
+fn sha256_file(file: &path::Path) -> gpio::Result<String> {
+    let data = fs::read(file)?;
+
+    Ok(format!("{:x}", Sha256::digest(data)))
+}
+
+fn read_build_info(project: &str, environment: &str) -> Option<BuildInfo> {
+    let file = build_dir(project, environment).join(BUILD_INFO_FILE);
+
+    serde_json::from_str(&fs::read_to_string(file).ok()?).ok()
+}
+
+/// Describes the artifact currently on disk. The build time is taken from the
+/// last build done by us if the commit matches, from the artifact otherwise.
+pub fn build_info(project: &str, environment: &str) -> gpio::Result<BuildInfo> {
+    let artifact = artifact_path(project, environment);
+
+    // The firmware does not have to be a git checkout. Such builds count as
+    // dirty, so they are never skipped.
+    let commit = git(project, &["rev-parse", "HEAD"]).ok();
+    let dirty = match &commit {
+        Some(_) => git(project, &["status", "--porcelain"]).map_or(true, |s| !s.is_empty()),
+        None => true,
+    };
+    let version = git(project, &["describe", "--tags", "--always", "--dirty"]).ok();
+    let artifact_sha256 = sha256_file(&artifact)?;
+
+    let built_at = match read_build_info(project, environment) {
+        Some(info) if info.artifact_sha256 == artifact_sha256 => info.built_at,
+        _ => fs::metadata(&artifact)?.modified()?.into(),
+    };
+
+    Ok(BuildInfo {
+        environment: environment.to_string(),
+        commit: commit.unwrap_or(UNKNOWN.to_string()),
+        dirty,
+        version: version.unwrap_or(UNKNOWN.to_string()),
+        built_at,
+        artifact: artifact.to_string_lossy().to_string(),
+        artifact_sha256,
+    })
+}
+
+/// Builds the firmware unless the last build was done from the same clean
+/// commit and environment and the artifact is unchanged since then.
+/// Returns whether the build was skipped.
+pub fn build(
+    project: &str,
+    environment: &str,
+    step: &subprocess::Step,
+) -> gpio::Result<(BuildInfo, bool)> {
+    if let Some(cached) = read_build_info(project, environment) {
+        let current = build_info(project, environment).ok();
+
+        if let Some(current) = current {
+            if !current.dirty
+                && !cached.dirty
+                && current.environment == cached.environment
+                && current.commit == cached.commit
+                && current.artifact_sha256 == cached.artifact_sha256
+            {
+                return Ok((cached, true));
+            }
+        }
+    }
+
+    let c = subprocess::run(
+        process::Command::new("pio")
+            .arg("run")
+            .arg("-e")
+            .arg(environment)
+            .current_dir(project),
+        step,
+    )?;
+
+    let output = c.log;
+    tracing::debug!("{}", output);
 
     if !c.status.success() {
-        return Err(gpio::Error::Io(io::Error::new(
-            io::ErrorKind::Other,
-            format!("`pio` exited with non-zero exit code: {output}"),
-        )));
+        return Err(gpio::Error::Io(io::Error::other(format!(
+            "`pio` exited with non-zero exit code: {output}"
+        ))));
     }
 
-    Ok(())
+    let mut info = build_info(project, environment)?;
+    info.built_at = chrono::Utc::now();
+
+    fs::write(
+        build_dir(project, environment).join(BUILD_INFO_FILE),
+        serde_json::to_string_pretty(&info).map_err(io::Error::from)?,
+    )?;
+
+    Ok((info, false))
 }
 
-pub fn flash(environment: &str, esp: &mut esp::ESP) -> gpio::Result<String> {
+pub fn flash(
+    project: &str,
+    environment: &str,
+    esp: &mut esp::ESP,
+    step: &subprocess::Step,
+) -> gpio::Result<String> {
     esp.reset_for_upload()?;
 
-    let c = process::Command::new("pio")
-        .arg("run")
-        .arg("-t")
-        .arg("upload")
-        .arg("-e")
-        .arg(environment)
-        .arg("--upload-port")
-        .arg("/dev/ttyUSB0")
-        .current_dir("/home/pi/slimevr-tracker-esp")
-        .output()?;
-
-    let output = String::from_utf8_lossy(&c.stdout);
-    println!("{}", output);
+    let c = subprocess::run(
+        process::Command::new("pio")
+            .arg("run")
+            .arg("-t")
+            .arg("upload")
+            .arg("-e")
+            .arg(environment)
+            .arg("--upload-port")
+            .arg(&esp.port)
+            .current_dir(project),
+        step,
+    );
 
     esp.reset()?;
 
+    let c = c?;
+    let output = c.log;
+    tracing::debug!("{}", output);
+
     if !c.status.success() {
-        return Err(gpio::Error::Io(io::Error::new(
-            io::ErrorKind::Other,
-            format!("`pio` exited with non-zero exit code: {output}"),
-        )));
+        return Err(gpio::Error::Io(io::Error::other(format!(
+            "`pio` exited with non-zero exit code: {output}"
+        ))));
     }
 
-    Ok(output.clone().to_string())
+    Ok(output)
 }
@@ -0,0 +1,115 @@
+use std::{collections::BTreeMap, io};
+
+use rppal::gpio;
+use serde::{Deserialize, Serialize};
+
+use crate::{esp, esptool, session, subprocess};
+
+const MAGIC: &[u8; 4] = b"SVRP";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 7;
+
+/// Size of the reserved flash region, one ESP8266 flash sector
+pub const REGION_SIZE: u32 = 0x1000;
+
+/// Per-device record written into the reserved flash region after flashing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProvisioningRecord {
+    pub serial_number: String,
+    pub hardware_revision: String,
+    pub tested_at: chrono::DateTime<chrono::Utc>,
+    pub calibration: BTreeMap<String, f32>,
+}
+
+impl ProvisioningRecord {
+    pub fn new(
+        serial_prefix: &str,
+        mac: &str,
+        hardware_revision: impl ToString,
+        calibration: BTreeMap<String, f32>,
+    ) -> Self {
+        ProvisioningRecord {
+            serial_number: format!("{}{}", serial_prefix, mac.replace(':', "").to_uppercase()),
+            hardware_revision: hardware_revision.to_string(),
+            // Part of the record that is read back, so it has to replay
+            tested_at: session::interact("clock", "tested at", chrono::Utc::now),
+            calibration,
+        }
+    }
+
+    /// Encodes the record as `MAGIC | VERSION | length (u16 LE) | JSON`,
+    /// padded with 0xFF (erased flash) to the region size.
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let payload = serde_json::to_vec(self)?;
+
+        if HEADER_SIZE + payload.len() > REGION_SIZE as usize {
+            return Err(io::Error::other(format!(
+                "provisioning record is too large ({} bytes)",
+                payload.len()
+            )));
+        }
+
+        let mut buf = Vec::with_capacity(REGION_SIZE as usize);
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        buf.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&payload);
+        buf.resize(REGION_SIZE as usize, 0xff);
+
+        Ok(buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() < HEADER_SIZE || &buf[..4] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no provisioning record found",
+            ));
+        }
+
+        if buf[4] != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported provisioning record version {}", buf[4]),
+            ));
+        }
+
+        let len = u16::from_le_bytes([buf[5], buf[6]]) as usize;
+        let payload = buf.get(HEADER_SIZE..HEADER_SIZE + len).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "truncated provisioning record")
+        })?;
+
+        Ok(serde_json::from_slice(payload)?)
+    }
+}
+
+/// Writes `record` to `offset` and reads it back, failing if the stored
+/// record does not match.
+pub fn provision(
+    record: &ProvisioningRecord,
+    offset: u32,
+    esp: &mut esp::ESP,
+    baudrate: u32,
+    step: &subprocess::Step,
+) -> gpio::Result<String> {
+    let data = record.encode()?;
+
+    let mut log = esptool::write_flash_region(&data, offset, esp, baudrate, step)?;
+
+    let (read_back, read_log) =
+        esptool::read_flash_region(offset, REGION_SIZE, esp, baudrate, step)?;
+    log.push_str(&read_log);
+
+    let stored = ProvisioningRecord::decode(&read_back)?;
+    if &stored != record {
+        return Err(gpio::Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "provisioning record mismatch: wrote {:?}, read back {:?}",
+                record, stored
+            ),
+        )));
+    }
+
+    Ok(log)
+}
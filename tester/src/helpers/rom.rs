@@ -0,0 +1,285 @@
+use std::time;
+
+use dut::regex;
+
+use super::serial;
+
+/// ROM bootloader of a chip family, they print the boot message at
+/// different baud rates and in different formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rom {
+    /// ` ets Jan  8 2013,rst cause:2, boot mode:(3,6)`
+    ESP8266,
+    /// `rst:0x1 (POWERON_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)`
+    ESP32,
+}
+
+impl Rom {
+    /// The ROM of `chip` as in `TESTER_CHIP`, every chip but the ESP8266
+    /// has the ESP32 one
+    pub fn of_chip(chip: &str) -> Rom {
+        if chip == "esp8266" {
+            Rom::ESP8266
+        } else {
+            Rom::ESP32
+        }
+    }
+
+    /// Baud rate of the boot message, the ESP8266 one assumes the 26 MHz
+    /// crystal of the ESP-12 modules
+    pub fn baudrate(&self) -> u32 {
+        match self {
+            Rom::ESP8266 => 74880,
+            Rom::ESP32 => 115200,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BootMode {
+    /// Strapping of GPIO15, GPIO0 and GPIO2, followed by the boot mode
+    /// details
+    ESP8266(u32, u32),
+    /// Value of the strapping register and the boot mode it selects, like
+    /// `SPI_FAST_FLASH_BOOT`
+    ESP32 { strapping: u32, name: String },
+}
+
+/// Reset cause and boot mode the ROM prints after every reset
+#[derive(Debug, Clone, PartialEq)]
+pub struct Banner {
+    pub reset_cause: u32,
+    /// Name of the reset cause, only printed by the ESP32 ROM
+    pub reset_name: Option<String>,
+    pub boot_mode: BootMode,
+    pub logs: String,
+}
+
+impl Banner {
+    /// Parses the line with the reset cause and boot mode
+    fn parse(rom: Rom, line: &str) -> Option<Banner> {
+        match rom {
+            Rom::ESP8266 => {
+                let c = regex!(
+                    r"rst cause:(?P<cause>\d+), boot mode:\((?P<mode>\d+),(?P<detail>\d+)\)"
+                )
+                .captures(line)?;
+
+                Some(Banner {
+                    reset_cause: c["cause"].parse().ok()?,
+                    reset_name: None,
+                    boot_mode: BootMode::ESP8266(
+                        c["mode"].parse().ok()?,
+                        c["detail"].parse().ok()?,
+                    ),
+                    logs: String::new(),
+                })
+            }
+            Rom::ESP32 => {
+                let c = regex!(
+                    r"rst:0x(?P<cause>[0-9a-fA-F]+) \((?P<reset>\w+)\),boot:0x(?P<strapping>[0-9a-fA-F]+) \((?P<mode>[^)(]+)"
+                )
+                .captures(line)?;
+
+                Some(Banner {
+                    reset_cause: u32::from_str_radix(&c["cause"], 16).ok()?,
+                    reset_name: Some(c["reset"].to_string()),
+                    boot_mode: BootMode::ESP32 {
+                        strapping: u32::from_str_radix(&c["strapping"], 16).ok()?,
+                        name: c["mode"].to_string(),
+                    },
+                    logs: String::new(),
+                })
+            }
+        }
+    }
+
+    pub fn reset_reason(&self) -> &str {
+        if let Some(name) = &self.reset_name {
+            return name;
+        }
+
+        match self.reset_cause {
+            1 => "power on",
+            2 => "external reset",
+            3 => "software reset",
+            4 => "watchdog reset",
+            5 => "deep sleep wake",
+            6 => "external reset (RTC)",
+            _ => "unknown",
+        }
+    }
+
+    pub fn boot_mode_name(&self) -> &str {
+        match &self.boot_mode {
+            BootMode::ESP8266(1, _) => "UART download",
+            BootMode::ESP8266(2, _) => "SDIO",
+            BootMode::ESP8266(3, _) => "flash",
+            BootMode::ESP8266(..) => "unknown",
+            BootMode::ESP32 { name, .. } => name,
+        }
+    }
+
+    /// Whether the ROM boots the application from flash, anything else means
+    /// the strapping pins are wrong, e.g. GPIO0 shorted to ground
+    pub fn is_flash_boot(&self) -> bool {
+        match &self.boot_mode {
+            BootMode::ESP8266(mode, _) => *mode == 3,
+            BootMode::ESP32 { name, .. } => name.contains("FLASH_BOOT"),
+        }
+    }
+}
+
+impl std::fmt::Display for Banner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "rst cause: {} ({}), boot mode: {} ",
+            self.reset_cause,
+            self.reset_reason(),
+            self.boot_mode_name(),
+        )?;
+
+        match &self.boot_mode {
+            BootMode::ESP8266(mode, detail) => write!(f, "({},{})", mode, detail),
+            BootMode::ESP32 { strapping, .. } => write!(f, "(0x{:x})", strapping),
+        }
+    }
+}
+
+/// Reads the ROM output after a reset until the bootloader is done (`~ld`,
+/// `entry 0x...`) or waits for a download. `serial` has to run at
+/// [`Rom::baudrate`].
+pub fn read_banner(
+    serial: &mut serial::Reader,
+    rom: Rom,
+    timeout: time::Duration,
+) -> Result<Banner, String> {
+    let mut banner: Option<Banner> = None;
+
+    let result = serial.expect_with(timeout, |line| {
+        if let Some(b) = Banner::parse(rom, &line.text) {
+            banner = Some(b);
+        }
+
+        let done = match rom {
+            Rom::ESP8266 => line.text.contains("~ld") || line.text.contains("waiting for host"),
+            Rom::ESP32 => {
+                line.text.starts_with("entry 0x") || line.text.contains("waiting for download")
+            }
+        };
+        (done && banner.is_some()).then_some(Ok(()))
+    });
+
+    // The end of the bootloader output is not needed to know the boot mode
+    let logs = match result {
+        Ok(((), logs)) => logs,
+        Err(logs) if banner.is_some() => logs,
+        Err(logs) => return Err(format!("{}\nno ROM boot message received", logs)),
+    };
+
+    let mut banner = banner.unwrap();
+    banner.logs = logs;
+
+    Ok(banner)
+}
+
+#[cfg(test)]
+mod tests {
+    use dut::fake::FakePort;
+
+    use super::*;
+
+    const ESP8266_FLASH: &[u8] = b"\r\n ets Jan  8 2013,rst cause:2, boot mode:(3,6)\r\n\r\n\
+load 0x4010f000, len 3460, room 16 \r\ntail 4\r\nchksum 0xcc\r\n\
+load 0x3fff20b8, len 40, room 4 \r\ntail 4\r\nchksum 0xc9\r\ncsum 0xc9\r\nv00059ea0\r\n~ld\r\n";
+
+    const ESP8266_DOWNLOAD: &[u8] =
+        b"\r\n ets Jan  8 2013,rst cause:2, boot mode:(1,7)\r\n\r\nwaiting for host\r\n";
+
+    const ESP32_FLASH: &[u8] = b"ets Jun  8 2016 00:22:57\r\n\r\n\
+rst:0x1 (POWERON_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)\r\n\
+configsip: 0, SPIWP:0xee\r\n\
+clk_drv:0x00,q_drv:0x00,d_drv:0x00,cs0_drv:0x00,hd_drv:0x00,wp_drv:0x00\r\n\
+mode:DIO, clock div:2\r\nload:0x3fff0030,len:1184\r\nload:0x40078000,len:13232\r\n\
+load:0x40080400,len:3028\r\nentry 0x400805e4\r\n";
+
+    const ESP32_DOWNLOAD: &[u8] = b"ets Jun  8 2016 00:22:57\r\n\r\n\
+rst:0x1 (POWERON_RESET),boot:0x3 (DOWNLOAD_BOOT(UART0/UART1/SDIO_REI_REO_V2))\r\n\
+waiting for download\r\n";
+
+    const ESP32S3_FLASH: &[u8] = b"ESP-ROM:esp32s3-20210327\r\nBuild:Mar 27 2021\r\n\
+rst:0x1 (POWERON),boot:0x8 (SPI_FAST_FLASH_BOOT)\r\nSPIWP:0xee\r\nmode:DIO, clock div:1\r\n\
+load:0x3fce3808,len:0x44c\r\nload:0x403c9700,len:0xbe4\r\nload:0x403cc700,len:0x2a68\r\n\
+entry 0x403c98d4\r\n";
+
+    /// Reads the banner from `output`, split into chunks like a serial port
+    /// would deliver it
+    fn read(rom: Rom, output: &[u8]) -> Result<Banner, String> {
+        let chunks = output.chunks(16).collect::<Vec<_>>();
+        let mut serial = serial::Reader::new(Box::new(FakePort::with_chunks(&chunks)));
+
+        read_banner(&mut serial, rom, time::Duration::from_millis(100))
+    }
+
+    #[test]
+    fn reads_an_esp8266_flash_boot() {
+        let banner = read(Rom::ESP8266, ESP8266_FLASH).unwrap();
+
+        assert_eq!(banner.reset_cause, 2);
+        assert_eq!(banner.boot_mode, BootMode::ESP8266(3, 6));
+        assert!(banner.is_flash_boot());
+        assert!(banner.logs.ends_with("~ld"));
+        assert_eq!(
+            banner.to_string(),
+            "rst cause: 2 (external reset), boot mode: flash (3,6)"
+        );
+    }
+
+    #[test]
+    fn reads_an_esp8266_download_boot() {
+        let banner = read(Rom::ESP8266, ESP8266_DOWNLOAD).unwrap();
+
+        assert!(!banner.is_flash_boot());
+        assert_eq!(banner.boot_mode_name(), "UART download");
+    }
+
+    #[test]
+    fn reads_an_esp32_flash_boot() {
+        let banner = read(Rom::ESP32, ESP32_FLASH).unwrap();
+
+        assert_eq!(banner.reset_cause, 1);
+        assert!(banner.is_flash_boot());
+        assert!(banner.logs.ends_with("entry 0x400805e4"));
+        assert_eq!(
+            banner.to_string(),
+            "rst cause: 1 (POWERON_RESET), boot mode: SPI_FAST_FLASH_BOOT (0x13)"
+        );
+
+        let banner = read(Rom::ESP32, ESP32S3_FLASH).unwrap();
+        assert_eq!(banner.reset_reason(), "POWERON");
+        assert!(banner.is_flash_boot());
+    }
+
+    #[test]
+    fn reads_an_esp32_download_boot() {
+        let banner = read(Rom::ESP32, ESP32_DOWNLOAD).unwrap();
+
+        assert!(!banner.is_flash_boot());
+        assert_eq!(banner.boot_mode_name(), "DOWNLOAD_BOOT");
+    }
+
+    #[test]
+    fn needs_the_format_of_the_chip() {
+        let e = read(Rom::ESP8266, ESP32_FLASH).unwrap_err();
+        assert!(e.ends_with("no ROM boot message received"), "{}", e);
+
+        assert!(read(Rom::ESP32, ESP8266_FLASH).is_err());
+    }
+
+    #[test]
+    fn picks_the_rom_of_the_chip() {
+        assert_eq!(Rom::of_chip("esp8266").baudrate(), 74880);
+        assert_eq!(Rom::of_chip("esp32c3").baudrate(), 115200);
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use super::serial;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Received from the DUT
+    Rx,
+    /// Sent to the DUT
+    Tx,
+    /// Output of a tool that opens the port itself, like `esptool`. Its raw
+    /// traffic can't be seen, so what it printed is logged instead.
+    Tool,
+}
+
+impl Direction {
+    fn marker(&self) -> &'static str {
+        match self {
+            Direction::Rx => "<",
+            Direction::Tx => ">",
+            Direction::Tool => "#",
+        }
+    }
+}
+
+/// Raw log of all serial traffic with a DUT. Every chunk is written on its
+/// own line as `<timestamp> <direction> <escaped bytes>`. Once a file is
+/// larger than `max_size` the log continues in `<name>.1.log`, `<name>.2.log`
+/// and so on, up to `max_parts` files. Traffic after that is dropped.
+pub struct Transcript {
+    dir: PathBuf,
+    name: String,
+    max_size: u64,
+    max_parts: u32,
+    part: u32,
+    file: fs::File,
+    written: u64,
+    /// Whether the log reached `max_parts` and the truncation was noted
+    truncated: bool,
+}
+
+fn part_path(dir: &Path, name: &str, part: u32) -> PathBuf {
+    if part == 0 {
+        dir.join(format!("{}.log", name))
+    } else {
+        dir.join(format!("{}.{}.log", name, part))
+    }
+}
+
+impl Transcript {
+    /// Creates `<dir>/<id>_<timestamp>.log`
+    pub fn create(dir: &Path, id: &str, max_size: u64, max_parts: u32) -> io::Result<Transcript> {
+        fs::create_dir_all(dir)?;
+
+        let id = id.replace([':', '/'], "");
+        let name = format!("{}_{}", id, chrono::Utc::now().format("%Y%m%dT%H%M%SZ"));
+        let file = fs::File::create(part_path(dir, &name, 0))?;
+
+        Ok(Transcript {
+            dir: dir.to_path_buf(),
+            name,
+            max_size,
+            max_parts,
+            part: 0,
+            file,
+            written: 0,
+            truncated: false,
+        })
+    }
+
+    /// Path of the first file of the log
+    pub fn path(&self) -> PathBuf {
+        part_path(&self.dir, &self.name, 0)
+    }
+
+    pub fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        if self.truncated {
+            return Ok(());
+        }
+
+        if self.written >= self.max_size {
+            if self.part + 1 >= self.max_parts {
+                self.truncated = true;
+
+                return writeln!(
+                    self.file,
+                    "log truncated after {} files of {} bytes",
+                    self.max_parts, self.max_size
+                );
+            }
+
+            self.part += 1;
+            self.file = fs::File::create(part_path(&self.dir, &self.name, self.part))?;
+            self.written = 0;
+        }
+
+        let line = format!(
+            "{} {} {}\n",
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            direction.marker(),
+            data.escape_ascii()
+        );
+
+        self.file.write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+
+        Ok(())
+    }
+}
+
+impl serial::Tap for Transcript {
+    fn received(&mut self, data: &[u8]) -> io::Result<()> {
+        self.record(Direction::Rx, data)
+    }
+
+    fn sent(&mut self, data: &[u8]) -> io::Result<()> {
+        self.record(Direction::Tx, data)
+    }
+}
+
+/// The log a file of `dir` belongs to, without the part number
+fn log_name(file_name: &str) -> Option<&str> {
+    let name = file_name.strip_suffix(".log")?;
+
+    match name.rsplit_once('.') {
+        Some((log, part)) if part.parse::<u32>().is_ok() => Some(log),
+        _ => Some(name),
+    }
+}
+
+/// Deletes the oldest logs in `dir`, with all their parts, until at most
+/// `keep` are left
+pub fn cleanup(dir: &Path, keep: usize) -> io::Result<()> {
+    let mut logs = BTreeMap::<String, (SystemTime, Vec<PathBuf>)>::new();
+
+    for entry in fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let Some(name) = log_name(&file_name) else {
+            continue;
+        };
+        let Ok(modified) = entry.metadata().and_then(|m| m.modified()) else {
+            continue;
+        };
+
+        let (last_modified, paths) = logs
+            .entry(name.to_string())
+            .or_insert((modified, Vec::new()));
+        *last_modified = modified.max(*last_modified);
+        paths.push(entry.path());
+    }
+
+    if logs.len() <= keep {
+        return Ok(());
+    }
+
+    let mut logs = logs.into_values().collect::<Vec<_>>();
+    logs.sort();
+    for (_, paths) in &logs[..logs.len() - keep] {
+        for path in paths {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(uuid::Uuid::new_v4().to_string())
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+
+        names
+    }
+
+    #[test]
+    fn rotates_up_to_max_parts() {
+        let dir = temp_dir();
+
+        let mut transcript = Transcript::create(&dir, "84:F3:EB:12:34:56", 10, 3).unwrap();
+        for _ in 0..10 {
+            transcript.record(Direction::Rx, b"hello\n").unwrap();
+        }
+
+        let name = transcript.name.clone();
+        assert!(name.starts_with("84F3EB123456_"));
+        assert_eq!(
+            file_names(&dir),
+            [
+                format!("{}.1.log", name),
+                format!("{}.2.log", name),
+                format!("{}.log", name),
+            ]
+        );
+
+        let last = fs::read_to_string(dir.join(format!("{}.2.log", name))).unwrap();
+        assert!(last.contains(" < hello\\n\n"), "{}", last);
+        assert!(last.ends_with("log truncated after 3 files of 10 bytes\n"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cleans_up_whole_boards() {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir).unwrap();
+
+        let now = SystemTime::now();
+        let files = [
+            ("oldest.log", 3),
+            ("oldest.1.log", 3),
+            ("older.log", 2),
+            ("older.1.log", 2),
+            ("older.2.log", 2),
+            ("newest.log", 1),
+            ("notes.txt", 4),
+        ];
+        for (name, age) in files {
+            fs::File::create(dir.join(name))
+                .unwrap()
+                .set_modified(now - Duration::from_secs(age * 60))
+                .unwrap();
+        }
+
+        cleanup(&dir, 2).unwrap();
+        assert_eq!(
+            file_names(&dir),
+            [
+                "newest.log",
+                "notes.txt",
+                "older.1.log",
+                "older.2.log",
+                "older.log"
+            ]
+        );
+
+        cleanup(&dir, 1).unwrap();
+        assert_eq!(file_names(&dir), ["newest.log", "notes.txt"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
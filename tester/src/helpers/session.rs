@@ -0,0 +1,765 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, BufRead, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread, time,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::serial::Serial;
+
+/// One interaction with the hardware, as stored in the session file (one
+/// JSON object per line)
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    /// Milliseconds since the session started
+    at: u64,
+    kind: String,
+    key: String,
+    value: serde_json::Value,
+}
+
+enum Mode {
+    Record(io::BufWriter<fs::File>),
+    Replay(VecDeque<Record>),
+}
+
+struct Session {
+    mode: Mode,
+    start: time::Instant,
+    /// Number of interactions recorded or replayed so far
+    position: usize,
+    /// Why the replay stopped matching the recording
+    diverged: Option<String>,
+}
+
+/// Payload the thread of a replay unwinds with once an interaction no longer
+/// matches the recording, [`divergence`] tells why. There is no value to
+/// return in that case, but unlike a panic this does not reach the panic
+/// hook.
+pub struct Diverged;
+
+static SESSION: Mutex<Option<Arc<Mutex<Session>>>> = Mutex::new(None);
+
+/// The running session, if any. Cloned so the global lock is not held while
+/// interacting.
+fn current() -> Option<Arc<Mutex<Session>>> {
+    SESSION.lock().unwrap().clone()
+}
+
+fn start(mode: Mode) -> io::Result<()> {
+    let mut session = SESSION.lock().unwrap();
+    if session.is_some() {
+        return Err(io::Error::other("a session was already started"));
+    }
+
+    *session = Some(Arc::new(Mutex::new(Session {
+        mode,
+        start: time::Instant::now(),
+        position: 0,
+        diverged: None,
+    })));
+
+    Ok(())
+}
+
+/// Ends the session, the hardware is used directly again until the next one
+/// is started
+pub fn stop() {
+    SESSION.lock().unwrap().take();
+}
+
+/// Only one session runs per process, tests starting one take turns and stop
+/// it when the guard is dropped
+#[cfg(test)]
+pub(crate) struct TestGuard {
+    _turn: std::sync::MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+impl TestGuard {
+    pub(crate) fn take() -> TestGuard {
+        static TURN: Mutex<()> = Mutex::new(());
+
+        TestGuard {
+            _turn: TURN.lock().unwrap_or_else(|e| e.into_inner()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestGuard {
+    fn drop(&mut self) {
+        stop();
+    }
+}
+
+/// Records every hardware interaction from now on to `path`
+pub fn record(path: &Path) -> io::Result<()> {
+    start(Mode::Record(io::BufWriter::new(fs::File::create(path)?)))
+}
+
+/// Replays the interactions recorded in `path` instead of talking to the
+/// hardware
+pub fn replay(path: &Path) -> io::Result<()> {
+    let records = io::BufReader::new(fs::File::open(path)?)
+        .lines()
+        .filter(|l| l.as_ref().map_or(true, |l| !l.trim().is_empty()))
+        .map(|l| Ok(serde_json::from_str(&l?)?))
+        .collect::<io::Result<VecDeque<Record>>>()?;
+
+    start(Mode::Replay(records))
+}
+
+fn replaying(session: &Mutex<Session>) -> bool {
+    matches!(session.lock().unwrap().mode, Mode::Replay(_))
+}
+
+pub fn is_replaying() -> bool {
+    current().is_some_and(|s| replaying(&s))
+}
+
+/// Whether a replay used up all recorded interactions
+pub fn is_finished() -> bool {
+    current().is_some_and(|s| match &s.lock().unwrap().mode {
+        Mode::Replay(records) => records.is_empty(),
+        Mode::Record(_) => false,
+    })
+}
+
+/// Why the replay stopped matching the recording, if it did
+pub fn divergence() -> Option<String> {
+    current()?.lock().unwrap().diverged.clone()
+}
+
+fn diverge(session: &mut Session, reason: String) -> String {
+    session.diverged.get_or_insert(reason).clone()
+}
+
+fn next(session: &mut Session, kind: &str, key: &str) -> Result<Record, String> {
+    if let Some(reason) = &session.diverged {
+        return Err(reason.clone());
+    }
+
+    let Mode::Replay(records) = &mut session.mode else {
+        unreachable!()
+    };
+
+    let position = session.position;
+    session.position += 1;
+
+    match records.pop_front() {
+        Some(record) if record.kind == kind && record.key == key => Ok(record),
+        Some(record) => Err(diverge(
+            session,
+            format!(
+                "replay diverged at interaction {}: expected {} `{}`, but the recording has {} `{}`",
+                position, kind, key, record.kind, record.key
+            ),
+        )),
+        None => Err(diverge(
+            session,
+            format!(
+                "replay diverged at interaction {}: expected {} `{}`, but the recording ended",
+                position, kind, key
+            ),
+        )),
+    }
+}
+
+fn write(session: &mut Session, kind: &str, key: &str, value: serde_json::Value) {
+    let record = Record {
+        at: session.start.elapsed().as_millis() as u64,
+        kind: kind.to_string(),
+        key: key.to_string(),
+        value,
+    };
+
+    let Mode::Record(file) = &mut session.mode else {
+        unreachable!()
+    };
+    session.position += 1;
+
+    let result = serde_json::to_writer(&mut *file, &record)
+        .map_err(io::Error::from)
+        .and_then(|_| file.write_all(b"\n"))
+        .and_then(|_| file.flush());
+    if let Err(e) = result {
+        tracing::warn!("could not write session: {}", e);
+    }
+}
+
+/// Runs `live` and records its result, or returns the recorded result
+/// without running it when replaying. `kind` and `key` identify the
+/// interaction, a replay unwinds with [`Diverged`] as soon as they don't
+/// match the recording.
+pub fn interact<T: Serialize + DeserializeOwned>(
+    kind: &str,
+    key: &str,
+    live: impl FnOnce() -> T,
+) -> T {
+    let Some(session) = current() else {
+        return live();
+    };
+
+    if replaying(&session) {
+        // The lock is released before unwinding
+        let value = {
+            let mut session = session.lock().unwrap();
+
+            next(&mut session, kind, key).and_then(|record| {
+                serde_json::from_value(record.value).map_err(|e| {
+                    diverge(
+                        &mut session,
+                        format!("recorded {} `{}` can't be replayed: {}", kind, key, e),
+                    )
+                })
+            })
+        };
+
+        return value.unwrap_or_else(|_| std::panic::resume_unwind(Box::new(Diverged)));
+    }
+
+    // Not locked while running, other threads might interact meanwhile
+    let value = live();
+    write(
+        &mut session.lock().unwrap(),
+        kind,
+        key,
+        serde_json::to_value(&value).unwrap(),
+    );
+
+    value
+}
+
+/// Records `value`, or compares it with the recorded one when replaying
+pub fn checkpoint<T: Serialize>(key: &str, value: &T) -> Result<(), String> {
+    let Some(session) = current() else {
+        return Ok(());
+    };
+    let value = serde_json::to_value(value).unwrap();
+
+    if replaying(&session) {
+        let record = next(&mut session.lock().unwrap(), "checkpoint", key)?;
+        if record.value != value {
+            return Err(format!(
+                "{} differs from the recording:\nrecorded: {}\nreplayed: {}",
+                key, record.value, value
+            ));
+        }
+    } else {
+        write(&mut session.lock().unwrap(), "checkpoint", key, value);
+    }
+
+    Ok(())
+}
+
+/// `Instant::now()`, but replayed from the recording so timeouts expire at
+/// the same point
+pub fn now() -> time::Instant {
+    let Some(start) = current().map(|s| s.lock().unwrap().start) else {
+        return time::Instant::now();
+    };
+
+    let elapsed = interact("clock", "", || start.elapsed().as_micros() as u64);
+
+    start + time::Duration::from_micros(elapsed)
+}
+
+/// `thread::sleep`, but without sleeping when replaying
+pub fn sleep(duration: time::Duration) {
+    interact("sleep", &duration.as_millis().to_string(), || {
+        thread::sleep(duration)
+    })
+}
+
+/// An error as stored in the session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedError {
+    pub kind: String,
+    pub message: String,
+}
+
+fn io_kind_name(kind: io::ErrorKind) -> &'static str {
+    match kind {
+        io::ErrorKind::NotFound => "not_found",
+        io::ErrorKind::PermissionDenied => "permission_denied",
+        io::ErrorKind::BrokenPipe => "broken_pipe",
+        io::ErrorKind::TimedOut => "timed_out",
+        io::ErrorKind::Interrupted => "interrupted",
+        io::ErrorKind::WouldBlock => "would_block",
+        io::ErrorKind::InvalidInput => "invalid_input",
+        _ => "other",
+    }
+}
+
+fn io_kind(name: &str) -> io::ErrorKind {
+    match name {
+        "not_found" => io::ErrorKind::NotFound,
+        "permission_denied" => io::ErrorKind::PermissionDenied,
+        "broken_pipe" => io::ErrorKind::BrokenPipe,
+        "timed_out" => io::ErrorKind::TimedOut,
+        "interrupted" => io::ErrorKind::Interrupted,
+        "would_block" => io::ErrorKind::WouldBlock,
+        "invalid_input" => io::ErrorKind::InvalidInput,
+        _ => io::ErrorKind::Other,
+    }
+}
+
+impl From<io::Error> for RecordedError {
+    fn from(e: io::Error) -> Self {
+        RecordedError {
+            kind: io_kind_name(e.kind()).to_string(),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<RecordedError> for io::Error {
+    fn from(e: RecordedError) -> Self {
+        io::Error::new(io_kind(&e.kind), e.message)
+    }
+}
+
+impl From<serialport::Error> for RecordedError {
+    fn from(e: serialport::Error) -> Self {
+        let kind = match e.kind {
+            serialport::ErrorKind::NoDevice => "no_device",
+            serialport::ErrorKind::InvalidInput => "serial_invalid_input",
+            serialport::ErrorKind::Unknown => "unknown",
+            serialport::ErrorKind::Io(kind) => io_kind_name(kind),
+        };
+
+        RecordedError {
+            kind: kind.to_string(),
+            message: e.description,
+        }
+    }
+}
+
+impl From<RecordedError> for serialport::Error {
+    fn from(e: RecordedError) -> Self {
+        let kind = match e.kind.as_str() {
+            "no_device" => serialport::ErrorKind::NoDevice,
+            "serial_invalid_input" => serialport::ErrorKind::InvalidInput,
+            "unknown" => serialport::ErrorKind::Unknown,
+            kind => serialport::ErrorKind::Io(io_kind(kind)),
+        };
+
+        serialport::Error::new(kind, e.message)
+    }
+}
+
+/// Opens a serial port at `baud_rate` whose traffic is part of the session
+pub fn open_serial(path: &str, baud_rate: u32, timeout: time::Duration) -> serialport::Result<Serial> {
+    let live = || {
+        serialport::new(path, baud_rate)
+            .timeout(timeout)
+            .data_bits(serialport::DataBits::Eight)
+            .open()
+    };
+
+    if current().is_none() {
+        return live();
+    }
+
+    let mut port = None;
+    interact("serial", &format!("open {}", baud_rate), || {
+        live().map(|p| port = Some(p)).map_err(RecordedError::from)
+    })?;
+
+    Ok(Box::new(SessionPort {
+        inner: port,
+        name: path.to_string(),
+        baud_rate,
+        timeout,
+    }))
+}
+
+/// Serial port whose traffic goes through [`interact`], `inner` is `None`
+/// when replaying
+struct SessionPort {
+    inner: Option<Serial>,
+    name: String,
+    baud_rate: u32,
+    timeout: time::Duration,
+}
+
+impl SessionPort {
+    fn inner(&mut self) -> &mut Serial {
+        self.inner.as_mut().expect("serial port is not open")
+    }
+
+    fn interact<T: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &str,
+        live: impl FnOnce(&mut Serial) -> serialport::Result<T>,
+    ) -> serialport::Result<T> {
+        interact("serial", key, || live(self.inner()).map_err(RecordedError::from))
+            .map_err(serialport::Error::from)
+    }
+}
+
+impl Read for SessionPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len();
+        let data = interact("serial", "read", || {
+            let mut data = vec![0u8; len];
+            let n = self.inner().read(&mut data)?;
+            data.truncate(n);
+
+            Ok::<_, RecordedError>(data)
+        })?;
+
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+}
+
+impl Write for SessionPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(interact("serial", "write", || {
+            self.inner().write(buf).map_err(RecordedError::from)
+        })?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(interact("serial", "flush", || {
+            self.inner().flush().map_err(RecordedError::from)
+        })?)
+    }
+}
+
+impl serialport::SerialPort for SessionPort {
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn data_bits(&self) -> serialport::Result<serialport::DataBits> {
+        Ok(serialport::DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<serialport::FlowControl> {
+        Ok(serialport::FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<serialport::Parity> {
+        Ok(serialport::Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<serialport::StopBits> {
+        Ok(serialport::StopBits::One)
+    }
+
+    fn timeout(&self) -> time::Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.interact(&format!("baud rate {}", baud_rate), |s| s.set_baud_rate(baud_rate))?;
+        self.baud_rate = baud_rate;
+
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, data_bits: serialport::DataBits) -> serialport::Result<()> {
+        self.interact("data bits", |s| s.set_data_bits(data_bits))
+    }
+
+    fn set_flow_control(&mut self, flow_control: serialport::FlowControl) -> serialport::Result<()> {
+        self.interact("flow control", |s| s.set_flow_control(flow_control))
+    }
+
+    fn set_parity(&mut self, parity: serialport::Parity) -> serialport::Result<()> {
+        self.interact("parity", |s| s.set_parity(parity))
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: serialport::StopBits) -> serialport::Result<()> {
+        self.interact("stop bits", |s| s.set_stop_bits(stop_bits))
+    }
+
+    // Only affects how long a read may block, which is part of the recorded
+    // reads already
+    fn set_timeout(&mut self, timeout: time::Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+
+        match &mut self.inner {
+            Some(inner) => inner.set_timeout(timeout),
+            None => Ok(()),
+        }
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.interact(&format!("rts {}", level), |s| s.write_request_to_send(level))
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.interact(&format!("dtr {}", level), |s| s.write_data_terminal_ready(level))
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        self.interact("cts", |s| s.read_clear_to_send())
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        self.interact("dsr", |s| s.read_data_set_ready())
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        self.interact("ri", |s| s.read_ring_indicator())
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        self.interact("cd", |s| s.read_carrier_detect())
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        interact("serial", "bytes to read", || {
+            self.inner.as_ref().unwrap().bytes_to_read().map_err(RecordedError::from)
+        })
+        .map_err(serialport::Error::from)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        interact("serial", "bytes to write", || {
+            self.inner.as_ref().unwrap().bytes_to_write().map_err(RecordedError::from)
+        })
+        .map_err(serialport::Error::from)
+    }
+
+    fn clear(&self, buffer_to_clear: serialport::ClearBuffer) -> serialport::Result<()> {
+        interact("serial", "clear", || {
+            self.inner.as_ref().unwrap().clear(buffer_to_clear).map_err(RecordedError::from)
+        })
+        .map_err(serialport::Error::from)
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn serialport::SerialPort>> {
+        Err(serialport::Error::new(
+            serialport::ErrorKind::Unknown,
+            "ports of a session can't be cloned",
+        ))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        interact("serial", "set break", || {
+            self.inner.as_ref().unwrap().set_break().map_err(RecordedError::from)
+        })
+        .map_err(serialport::Error::from)
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        interact("serial", "clear break", || {
+            self.inner.as_ref().unwrap().clear_break().map_err(RecordedError::from)
+        })
+        .map_err(serialport::Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{panic, path::PathBuf};
+
+    use dut::fake::FakePort;
+
+    use super::*;
+
+    fn session_path() -> PathBuf {
+        std::env::temp_dir().join(format!("{}.jsonl", uuid::Uuid::new_v4()))
+    }
+
+    fn port(inner: Option<Serial>) -> SessionPort {
+        SessionPort {
+            inner,
+            name: "/dev/ttyUSB0".to_string(),
+            baud_rate: 115200,
+            timeout: time::Duration::from_millis(100),
+        }
+    }
+
+    /// Runs `f`, returning why the replay diverged if it did
+    fn diverges<T>(f: impl FnOnce() -> T) -> Option<String> {
+        match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+            Ok(_) => None,
+            Err(payload) => {
+                assert!(payload.is::<Diverged>());
+
+                divergence()
+            }
+        }
+    }
+
+    fn unreachable<T>() -> T {
+        panic!("replays must not touch the hardware")
+    }
+
+    #[test]
+    fn replays_a_recording() {
+        let _guard = TestGuard::take();
+        let path = session_path();
+
+        record(&path).unwrap();
+        assert_eq!(
+            interact("adc", "vout", || Ok::<f32, String>(4.97)),
+            Ok(4.97)
+        );
+        checkpoint("result", &[("Measure VOUT", false)]).unwrap();
+        {
+            let mut serial = port(Some(Box::new(FakePort::with_chunks(&[b"ready\r\n"]))));
+            assert_eq!(serial.write(b"GET INFO\n").unwrap(), 9);
+            serialport::SerialPort::set_baud_rate(&mut serial, 74880).unwrap();
+
+            let mut buf = [0; 64];
+            let n = serial.read(&mut buf).unwrap();
+            assert_eq!(&buf[..n], b"ready\r\n");
+
+            let e = serial.read(&mut buf).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        }
+        stop();
+
+        replay(&path).unwrap();
+        assert!(is_replaying());
+        assert_eq!(
+            interact("adc", "vout", unreachable::<Result<f32, String>>),
+            Ok(4.97)
+        );
+        checkpoint("result", &[("Measure VOUT", false)]).unwrap();
+        {
+            let mut serial = port(None);
+            assert_eq!(serial.write(b"GET INFO\n").unwrap(), 9);
+            serialport::SerialPort::set_baud_rate(&mut serial, 74880).unwrap();
+            assert_eq!(serialport::SerialPort::baud_rate(&serial).unwrap(), 74880);
+
+            let mut buf = [0; 64];
+            let n = serial.read(&mut buf).unwrap();
+            assert_eq!(&buf[..n], b"ready\r\n");
+
+            let e = serial.read(&mut buf).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        }
+        assert!(is_finished());
+        assert_eq!(divergence(), None);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compares_checkpoints() {
+        let _guard = TestGuard::take();
+        let path = session_path();
+
+        record(&path).unwrap();
+        checkpoint("result", &[("Flashing", false)]).unwrap();
+        stop();
+
+        replay(&path).unwrap();
+        let e = checkpoint("result", &[("Flashing", true)]).unwrap_err();
+        assert_eq!(
+            e,
+            "result differs from the recording:\n\
+             recorded: [[\"Flashing\",false]]\n\
+             replayed: [[\"Flashing\",true]]"
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn diverges_on_another_interaction() {
+        let _guard = TestGuard::take();
+        let path = session_path();
+
+        record(&path).unwrap();
+        interact("adc", "vout", || 4.97);
+        interact("adc", "bplus", || 4.18);
+        stop();
+
+        replay(&path).unwrap();
+        let expected = "replay diverged at interaction 0: expected adc `bplus`, \
+                        but the recording has adc `vout`";
+        assert_eq!(
+            diverges(|| interact("adc", "bplus", unreachable::<f32>)).as_deref(),
+            Some(expected)
+        );
+
+        // Nothing matches after a divergence, the first reason sticks
+        assert_eq!(
+            diverges(|| interact("adc", "bplus", unreachable::<f32>)).as_deref(),
+            Some(expected)
+        );
+        assert_eq!(checkpoint("result", &()).unwrap_err(), expected);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn diverges_at_the_end_of_a_truncated_recording() {
+        let _guard = TestGuard::take();
+        let path = session_path();
+
+        record(&path).unwrap();
+        interact("usb", "connect", || {
+            ("/dev/ttyUSB0".to_string(), "dtr-rts".to_string())
+        });
+        sleep(time::Duration::from_millis(1));
+        stop();
+
+        // The station died while writing the last line
+        let recording = fs::read_to_string(&path).unwrap();
+        fs::write(&path, recording.lines().next().unwrap()).unwrap();
+
+        replay(&path).unwrap();
+        interact("usb", "connect", unreachable::<(String, String)>);
+        assert!(is_finished());
+        assert_eq!(
+            diverges(|| sleep(time::Duration::from_millis(1))).as_deref(),
+            Some("replay diverged at interaction 1: expected sleep `1`, but the recording ended")
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn diverges_when_a_value_does_not_fit() {
+        let _guard = TestGuard::take();
+        let path = session_path();
+
+        record(&path).unwrap();
+        interact("clock", "tested at", || "yesterday".to_string());
+        stop();
+
+        replay(&path).unwrap();
+        let reason = diverges(|| interact("clock", "tested at", unreachable::<u64>)).unwrap();
+        assert!(
+            reason.starts_with("recorded clock `tested at` can't be replayed: "),
+            "{}",
+            reason
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn only_one_session_runs() {
+        let _guard = TestGuard::take();
+        let path = session_path();
+
+        record(&path).unwrap();
+        assert!(replay(&path).is_err());
+        stop();
+
+        assert!(!is_replaying());
+        assert_eq!(interact("adc", "vout", || 4.97), 4.97);
+        assert!(replay(&path).is_ok());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    io::{self, Read},
+    os::unix::process::ExitStatusExt,
+    process, sync, thread, time,
+};
+
+use crate::{control, logger, session};
+
+/// How often a running command checks whether the operator aborted it
+const ABORT_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// Where to report the progress of a command and how long it may take
+pub struct Step<'a> {
+    pub logger: &'a sync::Arc<sync::Mutex<logger::Logger>>,
+    pub message: &'a str,
+    pub timeout: time::Duration,
+}
+
+impl<'a> Step<'a> {
+    pub fn new(
+        logger: &'a sync::Arc<sync::Mutex<logger::Logger>>,
+        message: &'a str,
+        timeout: time::Duration,
+    ) -> Self {
+        Step {
+            logger,
+            message,
+            timeout,
+        }
+    }
+}
+
+pub struct Output {
+    pub status: process::ExitStatus,
+    /// stdout and stderr, interleaved in the order the lines arrived
+    pub log: String,
+}
+
+/// Parses the percentage esptool prints while writing, e.g.
+/// `Writing at 0x00008000... (14 %)`
+pub fn parse_percentage(line: &str) -> Option<u8> {
+    let end = line.rfind(" %)")?;
+    let start = line[..end].rfind('(')?;
+
+    line[start + 1..end].trim().parse().ok()
+}
+
+/// Reads `reader` and sends every line, split on `\n` and `\r` as progress
+/// bars redraw using the latter
+fn forward_lines(mut reader: impl Read, tx: sync::mpsc::Sender<String>) {
+    let mut buf = [0u8; 256];
+    let mut line = Vec::new();
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+
+        for b in &buf[..n] {
+            if *b == b'\n' || *b == b'\r' {
+                if !line.is_empty() {
+                    let _ = tx.send(String::from_utf8_lossy(&line).to_string());
+                    line.clear();
+                }
+            } else {
+                line.push(*b);
+            }
+        }
+    }
+
+    if !line.is_empty() {
+        let _ = tx.send(String::from_utf8_lossy(&line).to_string());
+    }
+}
+
+/// Runs `command`, streaming its output into the logger as progress. The
+/// command is killed if it runs longer than the step's timeout, in which case
+/// the error contains everything it printed until then.
+pub fn run(command: &mut process::Command, step: &Step) -> io::Result<Output> {
+    let program = command.get_program().to_string_lossy().to_string();
+
+    let (status, log) = session::interact("subprocess", &program, || {
+        run_live(command, step)
+            .map(|o| (o.status.into_raw(), o.log))
+            .map_err(session::RecordedError::from)
+    })?;
+
+    Ok(Output {
+        status: process::ExitStatus::from_raw(status),
+        log,
+    })
+}
+
+fn run_live(command: &mut process::Command, step: &Step) -> io::Result<Output> {
+    let mut child = command
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+
+    let (tx, rx) = sync::mpsc::channel();
+
+    let stdout = child.stdout.take().unwrap();
+    let stdout_tx = tx.clone();
+    let stdout_thread = thread::spawn(move || forward_lines(stdout, stdout_tx));
+
+    let stderr = child.stderr.take().unwrap();
+    let stderr_thread = thread::spawn(move || forward_lines(stderr, tx));
+
+    let deadline = time::Instant::now() + step.timeout;
+    let mut log = String::new();
+    let mut last_update = time::Instant::now();
+    let mut last_percentage = None;
+    let mut timed_out = false;
+    let mut aborted = None;
+
+    loop {
+        if let Err(e) = control::check() {
+            aborted = Some(e);
+            let _ = child.kill();
+            break;
+        }
+
+        // Woken up regularly to notice when the operator aborts
+        let remaining = deadline
+            .saturating_duration_since(time::Instant::now())
+            .min(ABORT_POLL_INTERVAL);
+
+        match rx.recv_timeout(remaining) {
+            Ok(line) => {
+                log.push_str(&line);
+                log.push('\n');
+
+                // Redrawing on every line of a build makes the UI flicker
+                let percentage = parse_percentage(&line);
+                if percentage != last_percentage
+                    || last_update.elapsed() > time::Duration::from_millis(200)
+                {
+                    let mut l = step.logger.lock().unwrap();
+                    match percentage {
+                        Some(p) => l.in_progress(format!("{} ({}%)", step.message, p)),
+                        None => l.in_progress(format!("{}: {}", step.message, line)),
+                    }
+
+                    last_update = time::Instant::now();
+                    last_percentage = percentage;
+                }
+            }
+            Err(sync::mpsc::RecvTimeoutError::Timeout) if time::Instant::now() < deadline => {}
+            Err(sync::mpsc::RecvTimeoutError::Timeout) => {
+                timed_out = true;
+                let _ = child.kill();
+                break;
+            }
+            Err(sync::mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    let status = child.wait()?;
+
+    // Grandchildren (e.g. scons spawned by pio) might still hold the pipes
+    // open after a kill, so only wait for the readers if the command exited
+    if !timed_out && aborted.is_none() {
+        let _ = stdout_thread.join();
+        let _ = stderr_thread.join();
+    }
+
+    // Lines that arrived between the timeout and the kill
+    for line in rx.try_iter() {
+        log.push_str(&line);
+        log.push('\n');
+    }
+
+    if let Some(e) = aborted {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            format!("{}:\n{}", e, log),
+        ));
+    }
+
+    if timed_out {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "command timed out after {}s:\n{}",
+                step.timeout.as_secs(),
+                log
+            ),
+        ));
+    }
+
+    Ok(Output { status, log })
+}
@@ -1,6 +1,10 @@
 pub mod api;
+pub mod commands;
+pub mod export;
 mod helpers;
 pub mod options;
+pub mod server;
+pub mod sinks;
 pub mod test_executors;
 
 pub use helpers::*;
@@ -11,6 +15,17 @@ pub struct Board {
 	pub values: Vec<api::TestReportValue>,
 	pub started_at: chrono::DateTime<chrono::Utc>,
 	pub ended_at: chrono::DateTime<chrono::Utc>,
+	#[serde(default)]
+	pub firmware: Option<pio::BuildInfo>,
+	/// Test plan the board was tested with, the configured one if `None`
+	#[serde(default)]
+	pub report_type: Option<String>,
+}
+
+impl Default for Board {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 
 impl Board {
@@ -20,12 +35,28 @@ impl Board {
 			values: Vec::new(),
 			started_at: chrono::Utc::now(),
 			ended_at: chrono::DateTime::<chrono::Utc>::MIN_UTC,
+			firmware: None,
+			report_type: None,
 		}
 	}
 
 	pub fn add_value(&mut self, value: api::TestReportValue) {
 		self.values.push(value);
 	}
+
+	/// Records the firmware the board was tested with
+	pub fn set_firmware(&mut self, firmware: pio::BuildInfo) {
+		self.values.push(api::TestReportValue::new(
+			"Firmware",
+			"none",
+			&firmware.version,
+			Some(serde_json::to_string_pretty(&firmware).unwrap()),
+			false,
+			self.ended_at,
+			self.ended_at,
+		));
+		self.firmware = Some(firmware);
+	}
 }
 
 pub enum TestResult {
@@ -1,28 +1,59 @@
 use rppal::{gpio, i2c};
 use serde::{Deserialize, Serialize};
 use std::{
-	fs::{read_to_string, File},
-	io::Write,
-	sync::{Arc, Mutex},
+	fs, panic,
+	sync::{mpsc, Arc, Mutex},
 	thread::{sleep, spawn},
-	time::Duration,
+	time::{Duration, Instant},
 };
 use tester::{
-	api, logger, options, pio,
+	api, commands, control, database, diagnostics, logger, options, outbox, pio, server, session,
+	sinks, subprocess,
 	test_executors::{auxboard, mainboard, TestExecutor},
 	Board, TestResult,
 };
 
+/// Written by earlier versions, imported into the outbox once
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct BoardUploadFailure {
 	board: Board,
 	error: String,
 }
 
+const LEGACY_FAILED_BOARDS: &str = "failed_boards.json";
+
+fn import_failed_boards(outbox: &mut outbox::Outbox) {
+	let Ok(failed_boards) = fs::read_to_string(LEGACY_FAILED_BOARDS) else {
+		return;
+	};
+
+	let failed_boards = match serde_json::from_str::<Vec<BoardUploadFailure>>(&failed_boards) {
+		Ok(failed_boards) => failed_boards,
+		Err(e) => {
+			tracing::warn!("Could not read {}: {}", LEGACY_FAILED_BOARDS, e);
+			return;
+		}
+	};
+
+	for failure in failed_boards {
+		if let Err(e) = outbox.push(failure.board) {
+			tracing::warn!("Could not import {}: {}", LEGACY_FAILED_BOARDS, e);
+			return;
+		}
+	}
+
+	if let Err(e) = fs::rename(
+		LEGACY_FAILED_BOARDS,
+		format!("{}.imported", LEGACY_FAILED_BOARDS),
+	) {
+		tracing::warn!("Could not rename {}: {}", LEGACY_FAILED_BOARDS, e);
+	}
+}
+
 fn maybe_build_firmware(
 	options: &options::Options,
 	logger: Arc<Mutex<logger::Logger>>,
-) -> gpio::Result<()> {
+) -> gpio::Result<Option<pio::BuildInfo>> {
 	if options.no_build {
 		{
 			let mut l = logger.lock().unwrap();
@@ -36,74 +67,360 @@ fn maybe_build_firmware(
 			l.reset();
 		}
 
-		Ok(())
+		// Without a firmware checkout there is nothing to describe
+		Ok(pio::build_info(&options.firmware_path, &options.pio_environment).ok())
 	} else {
 		{
 			let mut l = logger.lock().unwrap();
 			l.in_progress("Building firmware...");
 		}
 
-		pio::build("esp12e")?;
+		let (info, skipped) = pio::build(
+			&options.firmware_path,
+			&options.pio_environment,
+			&subprocess::Step::new(&logger, "Building firmware", options.timeouts.build),
+		)?;
 
 		{
 			let mut l = logger.lock().unwrap();
 			l.reset();
+			if skipped {
+				l.success(&format!("Firmware {} is up to date", info.version));
+			}
+		}
+
+		Ok(Some(info))
+	}
+}
+
+fn create_executor(
+	report_type: &str,
+	logger: &Arc<Mutex<logger::Logger>>,
+	options: &options::Options,
+) -> Option<Box<dyn TestExecutor>> {
+	let replay = matches!(options.session, Some(options::SessionMode::Replay(_)));
+
+	match report_type {
+		"mainboard" if replay => Some(Box::new(mainboard::MainBoardTestExecutor::replay(
+			logger.clone(),
+			options.clone(),
+		))),
+		"mainboard" => Some(Box::new(mainboard::MainBoardTestExecutor::new(
+			i2c::I2c::with_bus(1).unwrap(),
+			gpio::Gpio::new().unwrap(),
+			logger.clone(),
+			options.clone(),
+		))),
+		"auxboard" => Some(Box::new(auxboard::AuxBoardTestExecutor::new(
+			i2c::I2c::with_bus(1).unwrap(),
+			logger.clone(),
+		))),
+		_ => None,
+	}
+}
+
+/// Blocks while the operator paused the station, returns `false` once they
+/// quit
+fn wait_while_paused(logger: &Arc<Mutex<logger::Logger>>) -> bool {
+	let mut announced = false;
+
+	loop {
+		{
+			let state = control::state();
+			if state.quit {
+				return false;
+			}
+			if !state.paused {
+				return true;
+			}
+		}
+
+		if !announced {
+			let mut l = logger.lock().unwrap();
+			l.action("[ Station paused, press P to resume ]");
+			announced = true;
+		}
+
+		sleep(Duration::from_millis(100));
+	}
+}
+
+/// Runs `f`, or returns why the replay no longer matches its recording
+fn catch_divergence<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+	match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+		Ok(value) => Ok(value),
+		Err(payload) if payload.is::<session::Diverged>() => {
+			Err(session::divergence().unwrap_or_default())
 		}
+		Err(payload) => panic::resume_unwind(payload),
+	}
+}
+
+fn finish_replay(replayed_boards: usize, diverged_boards: usize) -> ! {
+	println!(
+		"Replayed {} boards, {} with different results",
+		replayed_boards, diverged_boards
+	);
 
-		Ok(())
+	std::process::exit(if diverged_boards == 0 { 0 } else { 1 });
+}
+
+/// Adds what the operator did to the board to its report, returns `false`
+/// if that fails the board
+fn add_operator_notes(board: &mut Board) -> bool {
+	let notes = std::mem::take(&mut control::state().notes);
+	if notes.is_empty() {
+		return true;
+	}
+
+	let fails = notes.iter().any(|n| n.fails);
+	let now = chrono::Utc::now();
+	board.add_value(api::TestReportValue::new(
+		"Operator",
+		"none",
+		notes
+			.iter()
+			.map(|n| n.message.as_str())
+			.collect::<Vec<_>>()
+			.join(", "),
+		None::<&str>,
+		fails,
+		now,
+		now,
+	));
+
+	!fails
+}
+
+/// Stores the report of `board` for delivery and in the database, returns
+/// its id unless the outbox could not store it
+fn store_report(
+	outbox: &Mutex<outbox::Outbox>,
+	database: Option<&Mutex<database::Database>>,
+	options: &options::Options,
+	board: &Board,
+	passed: bool,
+	logger: &Mutex<logger::Logger>,
+) -> Option<String> {
+	let id = match outbox.lock().unwrap().push(board.clone()) {
+		Ok(id) => id,
+		Err(e) => {
+			tracing::error!("Could not store report for upload: {}", e);
+
+			let mut l = logger.lock().unwrap();
+			l.error(&format!("Could not store report for upload: {}", e));
+
+			return None;
+		}
+	};
+
+	if let Some(database) = database {
+		if let Err(e) = database.lock().unwrap().insert(
+			&id,
+			board,
+			&options.tester_name,
+			options.lot.as_deref(),
+			passed,
+			database::UploadStatus::Pending,
+		) {
+			tracing::error!("Could not store report in the database: {}", e);
+		}
+	}
+
+	Some(id)
+}
+
+/// Replaces the stored report `id` with `board`, it is delivered again
+fn update_report(
+	outbox: &Mutex<outbox::Outbox>,
+	database: Option<&Mutex<database::Database>>,
+	id: &str,
+	board: &Board,
+	passed: bool,
+	logger: &Mutex<logger::Logger>,
+) {
+	if let Err(e) = outbox.lock().unwrap().update(id, board.clone()) {
+		tracing::error!("Could not update report {} for upload: {}", id, e);
+
+		let mut l = logger.lock().unwrap();
+		l.error(&format!("Could not update report for upload: {}", e));
+	}
+
+	if let Some(database) = database {
+		if let Err(e) = database.lock().unwrap().update(
+			id,
+			board,
+			passed,
+			database::UploadStatus::Pending,
+		) {
+			tracing::error!("Could not update report {} in the database: {}", id, e);
+		}
 	}
 }
 
 fn main() {
 	let options = options::Options::parse();
 
-	let (mut renderer, logger) = logger::LoggerBuilder::split();
+	if let Some(code) = commands::run(&options) {
+		std::process::exit(code);
+	}
+
+	// The dashboard would draw over the reports
+	if options.sinks.contains(&options::Sink::Stdout) && options.output != options::Output::Json {
+		eprintln!("The stdout sink in TESTER_SINKS needs `--output json`");
+
+		std::process::exit(1);
+	}
+
+	let (mut renderer, mut logger) = logger::LoggerBuilder::split();
+	renderer.set_output(options.output);
+
+	// Kept until the end of main so buffered log lines are written
+	let _log_guard = match diagnostics::init(
+		&options.logs.dir,
+		&options.logs.filter,
+		options.logs.keep_files,
+		logger.clone(),
+	) {
+		Ok(guard) => guard,
+		Err(e) => {
+			eprintln!("Could not set up logging: {}", e);
+
+			std::process::exit(1);
+		}
+	};
+
+	if let Some(address) = &options.http_bind {
+		let hub = Arc::new(server::Hub::default());
+		let replay = matches!(options.session, Some(options::SessionMode::Replay(_)));
+
+		// The station works without it, the operator is at the keyboard
+		match server::start(address, hub.clone(), replay) {
+			Ok(()) => renderer.set_hub(hub),
+			Err(e) => logger.warning(&format!("Could not start the HTTP API: {}", e)),
+		}
+	}
+
 	let logger = Arc::new(Mutex::new(logger));
 
-	let boards_to_upload: Arc<Mutex<Vec<Board>>> = Arc::new(Mutex::new(Vec::new()));
+	let outbox = match outbox::Outbox::open(
+		&options.uploads.dir,
+		options.sinks.iter().map(|s| s.name()).collect(),
+		outbox::Backoff {
+			min: options.uploads.retry_min,
+			max: options.uploads.retry_max,
+		},
+	) {
+		Ok(mut outbox) => {
+			import_failed_boards(&mut outbox);
+			Arc::new(Mutex::new(outbox))
+		}
+		Err(e) => {
+			eprintln!("Could not open the upload outbox: {}", e);
 
-	let reports_to_upload_clone = boards_to_upload.clone();
+			std::process::exit(1);
+		}
+	};
+
+	let database = match &options.database {
+		Some(path) => match database::Database::open(path) {
+			Ok(database) => Some(Arc::new(Mutex::new(database))),
+			Err(e) => {
+				eprintln!("Could not open the results database: {}", e);
+
+				std::process::exit(1);
+			}
+		},
+		None => None,
+	};
+
+	let reports_to_upload_clone = outbox.clone();
+	let database_clone = database.clone();
 	let options_clone = options.clone();
 	let logger_clone = logger.clone();
 	spawn(move || {
 		let reports_to_upload = reports_to_upload_clone;
+		let database = database_clone;
 		let options = options_clone;
 		let logger = logger_clone;
 
-		if let Err(e) = maybe_build_firmware(&options, logger.clone()) {
-			println!("Could not build firmware: {}", e);
+		let replay = matches!(options.session, Some(options::SessionMode::Replay(_)));
+
+		// The build depends on the firmware checkout, not on the DUT
+		let firmware = if replay {
+			None
+		} else {
+			match maybe_build_firmware(&options, logger.clone()) {
+				Ok(firmware) => firmware,
+				Err(e) => {
+					eprintln!("Could not build firmware: {}", e);
+
+					std::process::exit(1);
+				}
+			}
+		};
+
+		if options.session.is_some() && options.report_type != "mainboard" {
+			eprintln!("Sessions can only be recorded and replayed for mainboards");
 
 			std::process::exit(1);
 		}
 
-		let i2c = i2c::I2c::with_bus(1).unwrap();
-		let gpio = gpio::Gpio::new().unwrap();
+		let session = match &options.session {
+			Some(options::SessionMode::Record(path)) => session::record(path),
+			Some(options::SessionMode::Replay(path)) => session::replay(path),
+			None => Ok(()),
+		};
+		if let Err(e) = session {
+			eprintln!("Could not start session: {}", e);
 
-		let mut executor: Box<dyn TestExecutor> = match options.report_type.as_str() {
-			"mainboard" => Box::new(mainboard::MainBoardTestExecutor::new(
-				i2c,
-				gpio,
-				logger.clone(),
-				options.clone(),
-			)) as Box<dyn TestExecutor>,
-			"auxboard" => Box::new(auxboard::AuxBoardTestExecutor::new(i2c, logger.clone()))
-				as Box<dyn TestExecutor>,
-			_ => {
-				{
-					let mut l = logger.lock().unwrap();
-					l.error("Invalid report type");
-				}
-				std::process::exit(1);
+			std::process::exit(1);
+		}
+
+		let mut report_type = options.report_type.clone();
+		let Some(mut executor) = create_executor(&report_type, &logger, &options) else {
+			{
+				let mut l = logger.lock().unwrap();
+				l.error("Invalid report type");
 			}
+			std::process::exit(1);
 		};
 
+		{
+			let mut l = logger.lock().unwrap();
+			l.plan(&report_type, &executor.steps());
+		}
+
+		let mut replayed_boards = 0;
+		let mut diverged_boards = 0;
+
 		loop {
+			if session::is_finished() {
+				finish_replay(replayed_boards, diverged_boards);
+			}
+
+			let plan = control::state().plan.take();
+			if let Some(plan) = plan.filter(|p| *p != report_type) {
+				// The hardware of the old executor has to be released first
+				drop(executor);
+				executor = create_executor(&plan, &logger, &options).unwrap();
+				report_type = plan;
+
+				let mut l = logger.lock().unwrap();
+				l.plan(&report_type, &executor.steps());
+				l.success(&format!("Switched to the {} test plan", report_type));
+			}
+
 			{
 				let mut l = logger.lock().unwrap();
 				l.action("[ Please connect the device ]");
 			}
 
-			executor.wait_for_device_connect();
+			// The rest of a diverged replay can't be compared anymore
+			if let Err(e) = catch_divergence(|| executor.wait_for_device_connect()) {
+				eprintln!("{}", e);
+				finish_replay(replayed_boards + 1, diverged_boards + 1);
+			}
 
 			{
 				let mut l = logger.lock().unwrap();
@@ -111,122 +428,256 @@ fn main() {
 				l.success("Device connected");
 			}
 
-			let result = executor.run();
+			// Boards are detected by the executor, connect it again once
+			// the new plan is active
+			if control::state()
+				.plan
+				.as_ref()
+				.is_some_and(|p| *p != report_type)
+			{
+				continue;
+			}
 
-			let mut wait_for_next_board = |board: Board, flash_color: logger::Color| {
-				{
-					let mut reports = reports_to_upload.lock().unwrap();
-					reports.push(board);
+			control::state().connected = true;
+			if !wait_while_paused(&logger) {
+				return;
+			}
+
+			control::start_board();
+
+			loop {
+				let connected_at = Instant::now();
+				let result = match catch_divergence(|| executor.run()) {
+					Ok(result) => result,
+					Err(e) => {
+						eprintln!("{}", e);
+						finish_replay(replayed_boards + 1, diverged_boards + 1);
+					}
+				};
+				let cycle_time = connected_at.elapsed();
+
+				let (mut board, passed) = match result {
+					TestResult::Failed(board) => (board, false),
+					TestResult::Passed(board) => (board, true),
+				};
+
+				let result_diverged = {
+					let outcome = board
+						.values
+						.iter()
+						.map(|v| (v.step.as_str(), v.failed))
+						.collect::<Vec<_>>();
+
+					replayed_boards += 1;
+					if let Err(e) = session::checkpoint("result", &outcome) {
+						diverged_boards += 1;
+
+						tracing::error!("{}", e);
+						let mut l = logger.lock().unwrap();
+						l.error("Replayed result differs from the recording");
+
+						true
+					} else {
+						false
+					}
+				};
+
+				let mut passed = add_operator_notes(&mut board) && passed;
+				board.report_type = Some(report_type.clone());
+				if let Some(firmware) = &firmware {
+					board.set_firmware(firmware.clone());
 				}
 
+				// Replayed boards were reported when they were recorded. The
+				// report is on disk before the operator sees the result.
+				let id = if replay {
+					None
+				} else {
+					store_report(
+						&reports_to_upload,
+						database.as_deref(),
+						&options,
+						&board,
+						passed,
+						&logger,
+					)
+				};
+
 				{
 					let mut l = logger.lock().unwrap();
+					if passed {
+						l.success("Board passed testing");
+					} else {
+						l.error("Board failed testing");
+					}
 					l.action("[ Please disconnect the device ]".to_string());
-					l.fill(flash_color);
+					l.fill(if passed {
+						logger::Color::Green
+					} else {
+						logger::Color::Red
+					});
+				}
+
+				// Retesting and quitting don't need the board to be unplugged
+				let disconnected = catch_divergence(|| {
+					executor.wait_for_device_disconnect(&mut || {
+						let state = control::state();
+						state.retest || state.quit
+					})
+				});
+				if let Err(e) = disconnected {
+					eprintln!("{}", e);
+					// The board was counted when its result was checked
+					let diverged_board = usize::from(!result_diverged);
+					finish_replay(replayed_boards, diverged_boards + diverged_board);
 				}
 
-				executor.wait_for_device_disconnect();
+				let retest = {
+					let mut state = control::state();
+					if state.retest {
+						state.note("retested by operator", false);
+					}
+					state.retest
+				};
+
+				// Notes added after the result, e.g. by scrapping the board,
+				// update the stored report
+				let stored_values = board.values.len();
+				passed = add_operator_notes(&mut board) && passed;
+				if let Some(id) = &id {
+					if board.values.len() > stored_values {
+						update_report(
+							&reports_to_upload,
+							database.as_deref(),
+							id,
+							&board,
+							passed,
+							&logger,
+						);
+					}
+				}
 
 				{
 					let mut l = logger.lock().unwrap();
+					l.finished(&board, passed, cycle_time);
+					l.report(&board);
 					l.reset();
 				}
-			};
 
-			match result {
-				TestResult::Failed(board) => {
-					{
-						let mut l = logger.lock().unwrap();
-						l.error("Board failed testing");
-					}
-
-					wait_for_next_board(board, logger::Color::Red);
+				if !retest {
+					break;
 				}
-				TestResult::Passed(board) => {
-					{
-						let mut l = logger.lock().unwrap();
-						l.success("Board passed testing");
-					}
 
-					wait_for_next_board(board, logger::Color::Green);
-				}
+				control::restart_board();
 			}
+
+			let mut state = control::state();
+			state.testing = false;
+			state.connected = false;
 		}
 	});
 
-	spawn(move || {
-		let client = api::Client::from_options(&options);
+	let (quit_tx, quit_rx) = mpsc::channel::<()>();
+	let uploader = spawn(move || {
+		let mut sinks = sinks::from_options(&options);
 
-		let mut upload_failed_boards = {
-			let failed_boards = read_to_string("failed_boards.json").unwrap_or("[]".to_string());
-
-			serde_json::from_str::<Vec<BoardUploadFailure>>(&failed_boards)
-				.unwrap_or_else(|_| Vec::new())
-		};
+		let mut upload_counts = None;
+		let mut quitting = false;
 
 		loop {
-			let board_tests_to_upload = {
-				let mut reports_to_upload = boards_to_upload.lock().unwrap();
-				let boards_to_upload = reports_to_upload.clone();
-				reports_to_upload.clear();
+			// The lock isn't held while delivering, the executor needs it to
+			// store the next board
+			let due = outbox.lock().unwrap().due();
 
-				boards_to_upload
-			};
-
-			for board in board_tests_to_upload {
-				if let None = board.id {
-					println!("Board has no MAC address, skipping upload");
+			for due in due {
+				let Some(sink) = sinks.iter_mut().find(|s| s.name() == due.sink) else {
 					continue;
-				}
+				};
 
-				match client.send_test_report(
-					&options.report_type,
-					board.id.clone().unwrap(),
-					&options.tester_name,
-					&board.values,
-					board.started_at,
-					board.ended_at,
-				) {
-					Ok(s) => {
-						if s.status() != reqwest::StatusCode::OK {
-							let e = s.text().unwrap();
+				let delivery = sink.send(&sinks::Report {
+					id: &due.id,
+					board: &due.board,
+				});
 
-							println!("Failed to upload report: {}", e);
+				let (result, error) = {
+					let mut outbox = outbox.lock().unwrap();
 
-							upload_failed_boards.push(BoardUploadFailure { board, error: e });
+					match delivery {
+						sinks::Delivery::Delivered => {
+							tracing::info!("Delivered report {} to {}", due.id, due.sink);
 
-							continue;
+							(outbox.delivered(&due.id, &due.sink), None)
 						}
+						sinks::Delivery::Retry(e) => {
+							tracing::warn!(
+								"Failed to deliver report {} to {} (attempt {}): {}",
+								due.id,
+								due.sink,
+								due.attempts + 1,
+								e
+							);
+
+							let error = format!("{}: {}", due.sink, e);
+							(
+								outbox
+									.failed(&due.id, &due.sink, &e)
+									.map(|_| Some(database::UploadStatus::Pending)),
+								Some(error),
+							)
+						}
+						sinks::Delivery::Rejected(e) => {
+							tracing::warn!("{} rejected report {}: {}", due.sink, due.id, e);
 
-						let e = s.text().unwrap();
-
-						println!("{}", e);
+							let error = format!("{}: {}", due.sink, e);
+							(outbox.reject(&due.id, &due.sink, &e), Some(error))
+						}
 					}
-					Err(e) => {
-						println!("Failed to upload report: {}", e);
+				};
 
-						upload_failed_boards.push(BoardUploadFailure {
-							board,
-							error: e.to_string(),
-						});
+				let status = match result {
+					Ok(status) => status,
+					Err(e) => {
+						tracing::error!("Could not update outbox: {}", e);
+						continue;
+					}
+				};
+
+				// Other sinks might still be pending after a delivery
+				if let (Some(status), Some(database)) = (status, &database) {
+					if let Err(e) = database.lock().unwrap().set_upload_status(
+						&due.id,
+						status,
+						error.as_deref(),
+					) {
+						tracing::error!("Could not update upload status: {}", e);
 					}
 				}
 			}
 
-			{
-				let mut failed_boards_file = File::create("failed_boards.json").unwrap();
-				failed_boards_file
-					.write_all(
-						serde_json::to_string_pretty(&upload_failed_boards)
-							.unwrap()
-							.as_bytes(),
-					)
-					.unwrap();
+			// Only redraw the dashboard when the counts change
+			let counts = {
+				let outbox = outbox.lock().unwrap();
+				(outbox.len(), outbox.dead_letters())
+			};
+			if upload_counts != Some(counts) {
+				upload_counts = Some(counts);
+
+				let mut l = logger.lock().unwrap();
+				l.uploads(counts.0, counts.1);
 			}
 
-			sleep(Duration::from_secs(1));
+			// One more round after quitting for the last board
+			if quitting {
+				break;
+			}
+			quitting = quit_rx.recv_timeout(Duration::from_secs(1)).is_ok();
 		}
 	});
 
 	renderer.run().unwrap();
+
+	quit_tx.send(()).unwrap();
+	uploader.join().unwrap();
+
+	std::process::exit(0);
 }
@@ -1,4 +1,6 @@
-use std::env;
+use std::{env, path::PathBuf, time::Duration};
+
+use crate::usb;
 
 #[derive(Clone)]
 pub enum FlashWith {
@@ -6,21 +8,269 @@ pub enum FlashWith {
     ESPTool,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashMode {
+    Qio,
+    Qout,
+    Dio,
+    Dout,
+}
+
+impl FlashMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FlashMode::Qio => "qio",
+            FlashMode::Qout => "qout",
+            FlashMode::Dio => "dio",
+            FlashMode::Dout => "dout",
+        }
+    }
+}
+
+impl std::fmt::Display for FlashMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone)]
+pub enum Hub {
+    Usb {
+        vendor_id: u16,
+        product_id: u16,
+        port_path: Option<String>,
+    },
+    Simulated,
+}
+
+#[derive(Clone)]
+pub struct Timeouts {
+    pub build: Duration,
+    pub flash: Duration,
+    /// Short esptool operations like reading the MAC address
+    pub esptool: Duration,
+    /// Waiting for an expected line on the serial port
+    pub serial: Duration,
+}
+
+#[derive(Clone)]
+pub struct LogFiles {
+    pub dir: PathBuf,
+    /// `EnvFilter` directives, e.g. `info,tester::helpers::usb=debug`
+    pub filter: String,
+    /// Number of daily files kept
+    pub keep_files: usize,
+}
+
+#[derive(Clone)]
+pub struct Uploads {
+    /// Directory of the outbox and the reports the server rejected
+    pub dir: PathBuf,
+    /// Wait before the first retry, doubled after every failed attempt
+    pub retry_min: Duration,
+    pub retry_max: Duration,
+}
+
+/// Where reports are delivered, see `sinks`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Sink {
+    /// The JSON-RPC API at `rpc_url`
+    Api,
+    /// Appends one JSON object per report to the file
+    File(PathBuf),
+    /// A results database like the one of the station
+    Database(PathBuf),
+    /// Prints one JSON object per report, needs `--output json`
+    Stdout,
+    /// POSTs every report as JSON to the URL
+    Webhook(String),
+}
+
+impl Sink {
+    /// How the sink is configured, identifies its reports in the outbox
+    pub fn name(&self) -> String {
+        match self {
+            Sink::Api => "api".to_string(),
+            Sink::File(path) => format!("file:{}", path.display()),
+            Sink::Database(path) => format!("sqlite:{}", path.display()),
+            Sink::Stdout => "stdout".to_string(),
+            Sink::Webhook(url) => format!("webhook:{}", url),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum SessionMode {
+    /// Record all hardware interactions to this file
+    Record(PathBuf),
+    /// Replay a recorded file instead of using the hardware
+    Replay(PathBuf),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Output {
+    /// Full-screen dashboard for the operator
+    Tui,
+    /// One JSON object per event on stdout, for supervisors and other front
+    /// ends
+    Json,
+}
+
+#[derive(Clone)]
+pub struct SerialLogs {
+    /// Directory of the raw serial logs, `None` disables them
+    pub dir: Option<PathBuf>,
+    /// Size in bytes after which a log continues in a new file
+    pub max_file_size: u64,
+    /// Number of files a log of one board may take, the rest is dropped
+    pub max_files_per_board: u32,
+    /// Number of boards whose logs are kept, the oldest ones are deleted
+    pub keep_boards: usize,
+}
+
 #[derive(Clone)]
 pub struct Options {
     pub no_build: bool,
+    /// PlatformIO project of the tracker firmware
+    pub firmware_path: String,
+    pub pio_environment: String,
     pub flash_with: FlashWith,
-    pub flash_baudrate: u32,
+    /// Only accept the DUT on this USB port path (e.g. `1-1.2`)
+    pub usb_port_path: Option<String>,
+    /// USB serial bridges accepted as DUT
+    pub bridges: Vec<usb::Bridge>,
+    /// Chip of the DUT as named by `esptool`, e.g. `esp8266` or `esp32s3`
+    pub chip: String,
+    /// Hub used to power cycle the DUT, if any
+    pub hub: Option<Hub>,
+    /// Hub port of each test slot
+    pub hub_ports: Vec<u8>,
+    /// Test slot of this station, selects its port from `hub_ports`
+    pub slot: usize,
+    pub hub_off_time: Duration,
+    pub cold_boot: bool,
+    /// Baud rates to try when flashing, fastest first
+    pub flash_baudrates: Vec<u32>,
+    /// Flash modes to try when flashing, preferred first
+    pub flash_modes: Vec<FlashMode>,
+    pub timeouts: Timeouts,
+    pub serial_logs: SerialLogs,
+    pub logs: LogFiles,
+    pub uploads: Uploads,
+    /// Every report is delivered to each of them
+    pub sinks: Vec<Sink>,
+    /// Local database of all tested boards, `None` disables it
+    pub database: Option<PathBuf>,
+    pub session: Option<SessionMode>,
+    pub output: Output,
+    /// Address of the HTTP API, `None` disables it
+    pub http_bind: Option<String>,
+    pub provision: bool,
+    pub provisioning_offset: u32,
+    pub hardware_revision: String,
+    /// Production lot of the boards being tested, stored with every result
+    pub lot: Option<String>,
+    pub serial_prefix: String,
     pub rpc_url: String,
     pub rpc_password: String,
     pub report_type: String,
     pub tester_name: String,
 }
 
+/// Value of a command line option given as `--name value` or `--name=value`
+fn arg(name: &str) -> Option<String> {
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args.next();
+        }
+
+        if let Some(value) = arg.strip_prefix(name).and_then(|v| v.strip_prefix('=')) {
+            return Some(value.to_string());
+        }
+    }
+
+    None
+}
+
 impl Options {
     pub fn parse() -> Self {
         let no_build = env::var("TESTER_BUILD").map(|v| v == "no").unwrap_or(false);
 
+        let firmware_path =
+            env::var("TESTER_FIRMWARE_PATH").unwrap_or("/home/pi/slimevr-tracker-esp".to_string());
+        let pio_environment = env::var("TESTER_PIO_ENV").unwrap_or("esp12e".to_string());
+
+        let usb_port_path = env::var("TESTER_USB_PORT_PATH").ok();
+
+        let bridges = usb::parse_bridges(
+            &env::var("TESTER_BRIDGES").unwrap_or("ch340".to_string()),
+        )
+        .unwrap_or_else(|e| panic!("TESTER_BRIDGES is invalid: {}", e));
+
+        let chip = env::var("TESTER_CHIP").unwrap_or("esp8266".to_string());
+        // The ESP8266 has no USB peripheral of its own
+        if chip == "esp8266" && bridges.iter().any(|b| b.reset == usb::ResetMethod::UsbJtag) {
+            panic!(
+                "USB-JTAG bridges need TESTER_CHIP set to an ESP32 with native USB, e.g. esp32s3"
+            );
+        }
+
+        // `<vid>:<pid>[@<port path>]` or `simulated`
+        let hub = env::var("TESTER_HUB").ok().map(|v| {
+            if v == "simulated" {
+                // Nothing would disconnect, cold boots would fail every board
+                if env::var("TESTER_REPLAY").is_err() {
+                    panic!("TESTER_HUB=simulated only works with TESTER_REPLAY");
+                }
+
+                return Hub::Simulated;
+            }
+
+            let (ids, port_path) = match v.split_once('@') {
+                Some((ids, port_path)) => (ids, Some(port_path.to_string())),
+                None => (v.as_str(), None),
+            };
+
+            let parse_id = |id: &str| {
+                u16::from_str_radix(id, 16)
+                    .expect("TESTER_HUB must be `<vid>:<pid>[@<port path>]` or `simulated`")
+            };
+            let (vendor_id, product_id) = ids
+                .split_once(':')
+                .map(|(vid, pid)| (parse_id(vid), parse_id(pid)))
+                .expect("TESTER_HUB must be `<vid>:<pid>[@<port path>]` or `simulated`");
+
+            Hub::Usb {
+                vendor_id,
+                product_id,
+                port_path,
+            }
+        });
+
+        let hub_ports = env::var("TESTER_HUB_PORTS")
+            .unwrap_or("1".to_string())
+            .split(',')
+            .map(|v| {
+                v.trim()
+                    .parse::<u8>()
+                    .expect("TESTER_HUB_PORTS must be a comma separated list of port numbers")
+            })
+            .collect::<Vec<_>>();
+
+        let slot = env::var("TESTER_SLOT")
+            .map(|v| v.parse::<usize>().expect("TESTER_SLOT must be a number"))
+            .unwrap_or(0);
+
+        let hub_off_time = env::var("TESTER_HUB_OFF_TIME_MS")
+            .map(|v| Duration::from_millis(v.parse::<u64>().unwrap()))
+            .unwrap_or(Duration::from_millis(1000));
+
+        let cold_boot = env::var("TESTER_COLD_BOOT")
+            .map(|v| v == "yes")
+            .unwrap_or(false);
+
         let flash_with = env::var("TESTER_FLASH_WITH")
             .map(|v| match v.as_ref() {
                 "pio" => FlashWith::PlatformIO,
@@ -33,6 +283,152 @@ impl Options {
             .map(|v| v.parse::<u32>().unwrap())
             .unwrap_or(921600);
 
+        let flash_baudrates = {
+            let fallbacks = env::var("TESTER_FLASH_FALLBACK_BAUDRATES")
+                .unwrap_or("460800,230400,115200".to_string());
+
+            let mut baudrates = vec![flash_baudrate];
+            baudrates.extend(
+                fallbacks
+                    .split(',')
+                    .filter(|v| !v.trim().is_empty())
+                    .map(|v| {
+                        v.trim().parse::<u32>().expect(
+                            "TESTER_FLASH_FALLBACK_BAUDRATES must be a comma separated list of numbers",
+                        )
+                    })
+                    .filter(|v| *v < flash_baudrate),
+            );
+            // Fastest first, every sync error moves on to the next one
+            baudrates.sort_unstable_by(|a, b| b.cmp(a));
+            baudrates.dedup();
+
+            baudrates
+        };
+
+        let flash_modes = env::var("TESTER_FLASH_MODES")
+            .unwrap_or("qio,dio".to_string())
+            .split(',')
+            .map(|v| match v.trim() {
+                "qio" => FlashMode::Qio,
+                "qout" => FlashMode::Qout,
+                "dio" => FlashMode::Dio,
+                "dout" => FlashMode::Dout,
+                _ => panic!("TESTER_FLASH_MODES must only contain 'qio', 'qout', 'dio' or 'dout'"),
+            })
+            .collect::<Vec<_>>();
+
+        let timeout = |name: &str, default: u64| {
+            env::var(name)
+                .map(|v| Duration::from_secs(v.parse::<u64>().unwrap()))
+                .unwrap_or(Duration::from_secs(default))
+        };
+        let timeouts = Timeouts {
+            build: timeout("TESTER_BUILD_TIMEOUT", 600),
+            flash: timeout("TESTER_FLASH_TIMEOUT", 120),
+            esptool: timeout("TESTER_ESPTOOL_TIMEOUT", 30),
+            serial: timeout("TESTER_SERIAL_TIMEOUT", 20),
+        };
+
+        let serial_logs = SerialLogs {
+            dir: match env::var("TESTER_SERIAL_LOG_DIR") {
+                Ok(v) if v.is_empty() => None,
+                Ok(v) => Some(PathBuf::from(v)),
+                Err(_) => Some(PathBuf::from("/home/pi/serial-logs")),
+            },
+            max_file_size: env::var("TESTER_SERIAL_LOG_MAX_SIZE")
+                .map(|v| v.parse::<u64>().unwrap())
+                .unwrap_or(16 * 1024 * 1024),
+            max_files_per_board: env::var("TESTER_SERIAL_LOG_MAX_FILES")
+                .map(|v| v.parse::<u32>().unwrap())
+                .unwrap_or(8),
+            keep_boards: env::var("TESTER_SERIAL_LOG_KEEP")
+                .map(|v| v.parse::<usize>().unwrap())
+                .unwrap_or(1000),
+        };
+
+        let logs = LogFiles {
+            dir: PathBuf::from(
+                env::var("TESTER_LOG_DIR").unwrap_or("/home/pi/tester-logs".to_string()),
+            ),
+            filter: env::var("TESTER_LOG").unwrap_or("info".to_string()),
+            keep_files: env::var("TESTER_LOG_KEEP")
+                .map(|v| v.parse::<usize>().unwrap())
+                .unwrap_or(14),
+        };
+
+        let uploads = Uploads {
+            dir: PathBuf::from(env::var("TESTER_OUTBOX_DIR").unwrap_or(".".to_string())),
+            retry_min: timeout("TESTER_UPLOAD_RETRY_MIN", 5),
+            retry_max: timeout("TESTER_UPLOAD_RETRY_MAX", 3600),
+        };
+
+        let database = match arg("--database").or_else(|| env::var("TESTER_DATABASE").ok()) {
+            Some(v) if v.is_empty() => None,
+            Some(v) => Some(PathBuf::from(v)),
+            None => Some(PathBuf::from("/home/pi/tester-results.db")),
+        };
+
+        // `api`, `stdout`, `file:<path>`, `sqlite:<path>` or `webhook:<url>`
+        let sinks = env::var("TESTER_SINKS")
+            .unwrap_or("api".to_string())
+            .split(',')
+            .map(|v| match v.trim().split_once(':') {
+                None if v.trim() == "api" => Sink::Api,
+                None if v.trim() == "stdout" => Sink::Stdout,
+                Some(("file", path)) => Sink::File(PathBuf::from(path)),
+                Some(("sqlite", path)) => Sink::Database(PathBuf::from(path)),
+                Some(("webhook", url)) => Sink::Webhook(url.to_string()),
+                _ => panic!(
+                    "TESTER_SINKS must only contain 'api', 'stdout', 'file:<path>', \
+                     'sqlite:<path>' or 'webhook:<url>'"
+                ),
+            })
+            .collect::<Vec<_>>();
+
+        let session = match (env::var("TESTER_RECORD"), env::var("TESTER_REPLAY")) {
+            (Ok(_), Ok(_)) => panic!("TESTER_RECORD and TESTER_REPLAY can't be used together"),
+            (Ok(path), _) => Some(SessionMode::Record(PathBuf::from(path))),
+            (_, Ok(path)) => Some(SessionMode::Replay(PathBuf::from(path))),
+            _ => None,
+        };
+
+        let output = match arg("--output")
+            .or_else(|| env::var("TESTER_OUTPUT").ok())
+            .as_deref()
+        {
+            None | Some("tui") => Output::Tui,
+            Some("json") => Output::Json,
+            Some(v) => panic!("unknown output `{}`, expected `tui` or `json`", v),
+        };
+
+        // Anyone reaching it can control the station, so it is opt-in
+        let http_bind = env::var("TESTER_HTTP_BIND").ok().filter(|v| !v.is_empty());
+
+        let provision = env::var("TESTER_PROVISION")
+            .map(|v| v == "yes")
+            .unwrap_or(false);
+
+        // 0x3FA000 is the unused sector between the filesystem and the EEPROM
+        // area of the 4M1M flash layout used by the tracker firmware
+        let provisioning_offset = env::var("TESTER_PROVISIONING_OFFSET")
+            .map(|v| {
+                let v = v.trim();
+                let v = v
+                    .strip_prefix("0x")
+                    .or_else(|| v.strip_prefix("0X"))
+                    .unwrap_or(v);
+
+                u32::from_str_radix(v, 16)
+                    .expect("TESTER_PROVISIONING_OFFSET must be a hexadecimal number")
+            })
+            .unwrap_or(0x3fa000);
+
+        let hardware_revision =
+            env::var("TESTER_HARDWARE_REVISION").unwrap_or("unknown".to_string());
+        let lot = env::var("TESTER_LOT").ok().filter(|v| !v.is_empty());
+        let serial_prefix = env::var("TESTER_SERIAL_PREFIX").unwrap_or("SVR".to_string());
+
         let rpc_url =
             env::var("TESTER_RPC_URL").unwrap_or("https://localhost:3000/api/rpc".to_string());
         let rpc_password = env::var("TESTER_RPC_PASSWORD").unwrap_or("password".to_string());
@@ -44,8 +440,33 @@ impl Options {
 
         Self {
             no_build,
+            firmware_path,
+            pio_environment,
             flash_with,
-            flash_baudrate,
+            usb_port_path,
+            bridges,
+            chip,
+            hub,
+            hub_ports,
+            slot,
+            hub_off_time,
+            cold_boot,
+            flash_baudrates,
+            flash_modes,
+            timeouts,
+            serial_logs,
+            logs,
+            uploads,
+            sinks,
+            database,
+            session,
+            output,
+            http_bind,
+            provision,
+            provisioning_offset,
+            hardware_revision,
+            lot,
+            serial_prefix,
             rpc_url,
             rpc_password,
             report_type,
@@ -0,0 +1,308 @@
+use std::{
+    collections::VecDeque,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response};
+use tungstenite::{handshake::derive_accept_key, protocol::Role, Message, WebSocket};
+
+use crate::control;
+
+/// How many board reports `/results` returns
+const RECENT_REPORTS: usize = 20;
+
+/// Test plans the station can switch to, the report types of the executors
+pub const PLANS: &[&str] = &["mainboard", "auxboard"];
+
+/// The station as seen through the events of the logger
+#[derive(Default)]
+struct State {
+    plan: Option<String>,
+    /// Planned steps and whether they failed, `None` until they ran
+    steps: Vec<(String, Option<bool>)>,
+    /// Messages shown for the current board
+    messages: Vec<Value>,
+    passed: u64,
+    failed: u64,
+    pending_uploads: u64,
+    failed_uploads: u64,
+    /// Newest first
+    reports: VecDeque<Value>,
+    /// WebSocket connections, dropped once they are closed
+    subscribers: Vec<mpsc::Sender<String>>,
+}
+
+/// Collects the events of the logger for the HTTP API and forwards them to
+/// WebSocket clients
+#[derive(Default)]
+pub struct Hub {
+    state: Mutex<State>,
+}
+
+impl Hub {
+    /// Takes an event as emitted by `--output json`
+    pub fn publish(&self, event: &Value) {
+        let mut state = self.state.lock().unwrap();
+
+        match event["event"].as_str() {
+            Some("plan") => {
+                state.plan = event["name"].as_str().map(|s| s.to_string());
+                state.steps = event["steps"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|s| Some((s.as_str()?.to_string(), None)))
+                    .collect();
+            }
+            Some("step") => {
+                let failed = event["failed"].as_bool().unwrap_or(false);
+                if let Some((_, result)) = state.steps.iter_mut().find(|(s, _)| *s == event["step"])
+                {
+                    *result = Some(result.unwrap_or(false) || failed);
+                }
+            }
+            Some("reset") => {
+                state.messages.clear();
+                for (_, result) in &mut state.steps {
+                    *result = None;
+                }
+            }
+            Some("success" | "error" | "warning" | "in_progress" | "action") => {
+                // Like on the screen, progress is replaced by what follows
+                if state
+                    .messages
+                    .last()
+                    .is_some_and(|m| m["event"] == "in_progress")
+                {
+                    state.messages.pop();
+                }
+                state.messages.push(event.clone());
+            }
+            Some("finished") => {
+                if event["passed"].as_bool().unwrap_or(false) {
+                    state.passed += 1;
+                } else {
+                    state.failed += 1;
+                }
+            }
+            Some("uploads") => {
+                state.pending_uploads = event["pending"].as_u64().unwrap_or(0);
+                state.failed_uploads = event["failed"].as_u64().unwrap_or(0);
+            }
+            Some("report") => {
+                state.reports.push_front(event["board"].clone());
+                state.reports.truncate(RECENT_REPORTS);
+            }
+            _ => {}
+        }
+
+        let line = event.to_string();
+        state.subscribers.retain(|s| s.send(line.clone()).is_ok());
+    }
+
+    fn state(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        let control = control::state();
+
+        // Steps report when they are done, so the first one without a result
+        // is the one running
+        let current = state.steps.iter().position(|(_, r)| r.is_none());
+        let steps = state
+            .steps
+            .iter()
+            .enumerate()
+            .map(|(i, (step, result))| {
+                let status = match result {
+                    Some(false) => "passed",
+                    Some(true) => "failed",
+                    None if control.testing && current == Some(i) => "running",
+                    None => "pending",
+                };
+
+                json!({ "step": step, "status": status })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "plan": state.plan,
+            "testing": control.testing,
+            "paused": control.paused,
+            "steps": steps,
+            "messages": state.messages,
+            "passed": state.passed,
+            "failed": state.failed,
+            "uploads": {
+                "pending": state.pending_uploads,
+                "failed": state.failed_uploads,
+            },
+        })
+    }
+
+    fn queue(&self) -> Value {
+        let state = self.state.lock().unwrap();
+
+        json!({
+            "pending": state.pending_uploads,
+            "failed": state.failed_uploads,
+        })
+    }
+
+    fn results(&self) -> Value {
+        Value::Array(self.state.lock().unwrap().reports.iter().cloned().collect())
+    }
+
+    /// Streams all events from now on, starting with the current state
+    fn subscribe(&self) -> mpsc::Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+
+        let mut state = json!({ "event": "state" });
+        state["state"] = self.state();
+        let _ = tx.send(state.to_string());
+
+        self.state.lock().unwrap().subscribers.push(tx);
+
+        rx
+    }
+}
+
+type Reply = Result<Value, (u16, String)>;
+
+/// Serves the HTTP API on `address` (e.g. `127.0.0.1:8080`) in the
+/// background:
+///
+/// - `GET /state`: plan, step checklist, messages and counters
+/// - `GET /results`: the last board reports, newest first
+/// - `GET /queue`: reports waiting for upload and failed to upload
+/// - `GET /events`: WebSocket streaming every event as JSON
+/// - `POST /start`: resumes the station and tests the connected board again
+/// - `POST /abort`: aborts testing the connected board
+/// - `PUT /plan`: switches the test plan before the next board, e.g.
+///   `{"plan": "auxboard"}`
+///
+/// `replay` rejects plan changes, a replayed session can't switch its
+/// hardware.
+pub fn start(address: &str, hub: Arc<Hub>, replay: bool) -> Result<(), String> {
+    let server = tiny_http::Server::http(address)
+        .map_err(|e| format!("could not listen on {}: {}", address, e))?;
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            handle(request, &hub, replay);
+        }
+    });
+
+    Ok(())
+}
+
+fn handle(mut request: Request, hub: &Hub, replay: bool) {
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+
+    let reply = match (request.method(), path.as_str()) {
+        (Method::Get, "/state") => Ok(hub.state()),
+        (Method::Get, "/results") => Ok(hub.results()),
+        (Method::Get, "/queue") => Ok(hub.queue()),
+        (Method::Get, "/events") => return events(request, hub),
+        (Method::Post, "/start") => start_test(),
+        (Method::Post, "/abort") => abort_test(),
+        (Method::Put, "/plan") => set_plan(&mut request, replay),
+        (_, "/state" | "/results" | "/queue" | "/events" | "/start" | "/abort" | "/plan") => {
+            Err((405, "method not allowed".to_string()))
+        }
+        _ => Err((404, "not found".to_string())),
+    };
+
+    let (status, body) = match reply {
+        Ok(body) => (200, body),
+        Err((status, error)) => (status, json!({ "error": error })),
+    };
+
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+    if let Err(e) = request.respond(response) {
+        tracing::debug!("could not send response: {}", e);
+    }
+}
+
+fn start_test() -> Reply {
+    let mut state = control::state();
+    if !state.connected {
+        return Err((409, "no board is connected".to_string()));
+    }
+
+    state.paused = false;
+    if state.testing {
+        state.retest = true;
+        Ok(json!({ "started": "connected board" }))
+    } else {
+        Ok(json!({ "started": "next board" }))
+    }
+}
+
+fn abort_test() -> Reply {
+    let mut state = control::state();
+    if !state.testing {
+        return Err((409, "no board is being tested".to_string()));
+    }
+
+    control::abort_board();
+    state.note("aborted through the API", true);
+
+    Ok(json!({ "aborted": true }))
+}
+
+fn set_plan(request: &mut Request, replay: bool) -> Reply {
+    if replay {
+        return Err((409, "the plan of a replay can't be changed".to_string()));
+    }
+
+    let mut body = String::new();
+    request
+        .as_reader()
+        .read_to_string(&mut body)
+        .map_err(|e| (400, e.to_string()))?;
+
+    let body = serde_json::from_str::<Value>(&body).map_err(|e| (400, e.to_string()))?;
+    let plan = body["plan"]
+        .as_str()
+        .filter(|p| PLANS.contains(p))
+        .ok_or((400, format!("plan must be one of {}", PLANS.join(", "))))?;
+
+    control::state().plan = Some(plan.to_string());
+
+    Ok(json!({ "plan": plan }))
+}
+
+/// Upgrades to a WebSocket that gets every event until the client goes away
+fn events(request: Request, hub: &Hub) {
+    let Some(key) = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Sec-WebSocket-Key"))
+        .map(|h| h.value.to_string())
+    else {
+        let response = Response::from_string(json!({ "error": "WebSocket only" }).to_string())
+            .with_status_code(400);
+        let _ = request.respond(response);
+        return;
+    };
+
+    let response = Response::empty(101).with_header(
+        Header::from_bytes("Sec-WebSocket-Accept", derive_accept_key(key.as_bytes())).unwrap(),
+    );
+
+    let events = hub.subscribe();
+    let stream = request.upgrade("websocket", response);
+
+    thread::spawn(move || {
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+
+        for event in events {
+            if socket.send(Message::Text(event)).is_err() {
+                break;
+            }
+        }
+    });
+}
@@ -0,0 +1,45 @@
+use crate::{api, options};
+
+use super::{http_delivery, Delivery, Report, ReportSink};
+
+/// The JSON-RPC API of the SlimeVR backend
+pub struct ApiSink {
+    client: api::Client,
+    tester_name: String,
+    /// Test plan of boards that don't know theirs
+    report_type: String,
+}
+
+impl ApiSink {
+    pub fn from_options(options: &options::Options) -> ApiSink {
+        ApiSink {
+            client: api::Client::from_options(options),
+            tester_name: options.tester_name.clone(),
+            report_type: options.report_type.clone(),
+        }
+    }
+}
+
+impl ReportSink for ApiSink {
+    fn name(&self) -> String {
+        options::Sink::Api.name()
+    }
+
+    fn send(&mut self, report: &Report) -> Delivery {
+        let board = report.board;
+
+        // The MAC address is the key of the report
+        let Some(id) = &board.id else {
+            return Delivery::Rejected("no MAC address".to_string());
+        };
+
+        http_delivery(self.client.send_test_report(
+            board.report_type.as_deref().unwrap_or(&self.report_type),
+            id,
+            &self.tester_name,
+            &board.values,
+            board.started_at,
+            board.ended_at,
+        ))
+    }
+}
@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+use crate::{database, options};
+
+use super::{Delivery, Report, ReportSink};
+
+/// Stores reports in a results database, like the one the station keeps
+/// itself but e.g. shared by several stations
+pub struct DatabaseSink {
+    path: PathBuf,
+    tester_name: String,
+    lot: Option<String>,
+    /// Opened on first use, so a missing share is retried like any error
+    database: Option<database::Database>,
+}
+
+impl DatabaseSink {
+    pub fn new(path: PathBuf, tester_name: String, lot: Option<String>) -> DatabaseSink {
+        DatabaseSink {
+            path,
+            tester_name,
+            lot,
+            database: None,
+        }
+    }
+}
+
+impl ReportSink for DatabaseSink {
+    fn name(&self) -> String {
+        options::Sink::Database(self.path.clone()).name()
+    }
+
+    fn send(&mut self, report: &Report) -> Delivery {
+        let database = match &mut self.database {
+            Some(database) => database,
+            None => match database::Database::open(&self.path) {
+                Ok(database) => self.database.insert(database),
+                Err(e) => return Delivery::Retry(format!("{}: {}", self.path.display(), e)),
+            },
+        };
+
+        // Already there if a previous attempt only failed to answer, or if
+        // the report was updated since
+        let result = match database.contains(report.id) {
+            Ok(true) => database.update(
+                report.id,
+                report.board,
+                report.passed(),
+                database::UploadStatus::Uploaded,
+            ),
+            Ok(false) => database.insert(
+                report.id,
+                report.board,
+                &self.tester_name,
+                self.lot.as_deref(),
+                report.passed(),
+                database::UploadStatus::Uploaded,
+            ),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => Delivery::Delivered,
+            Err(e) => {
+                self.database = None;
+                Delivery::Retry(e.to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::options;
+
+use super::{Delivery, Report, ReportSink};
+
+/// Appends one JSON object per report to a file, e.g. on a network share
+pub struct FileSink {
+    path: PathBuf,
+}
+
+impl FileSink {
+    pub fn new(path: PathBuf) -> FileSink {
+        FileSink { path }
+    }
+
+    fn append(path: &Path, line: &[u8]) -> std::io::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        file.write_all(line)?;
+        file.sync_data()
+    }
+}
+
+impl ReportSink for FileSink {
+    fn name(&self) -> String {
+        options::Sink::File(self.path.clone()).name()
+    }
+
+    fn send(&mut self, report: &Report) -> Delivery {
+        let mut line = serde_json::json!({
+            "id": report.id,
+            "passed": report.passed(),
+            "board": report.board,
+        })
+        .to_string();
+        line.push('\n');
+
+        // The file might be on a share that comes back later
+        match FileSink::append(&self.path, line.as_bytes()) {
+            Ok(()) => Delivery::Delivered,
+            Err(e) => Delivery::Retry(format!("{}: {}", self.path.display(), e)),
+        }
+    }
+}
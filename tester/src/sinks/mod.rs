@@ -0,0 +1,122 @@
+mod api;
+mod database;
+mod file;
+mod stdout;
+mod webhook;
+
+pub use api::*;
+pub use database::*;
+pub use file::*;
+pub use stdout::*;
+pub use webhook::*;
+
+use crate::{options, Board};
+
+/// A report as handed to the sinks
+pub struct Report<'a> {
+    /// Id of the report in the outbox, the same for every sink
+    pub id: &'a str,
+    pub board: &'a Board,
+}
+
+impl Report<'_> {
+    /// Whether no step failed, including operator notes
+    pub fn passed(&self) -> bool {
+        !self.board.values.iter().any(|v| v.failed)
+    }
+}
+
+/// What happened to a report sent to a sink
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Delivery {
+    Delivered,
+    /// Might work later, e.g. the server is unreachable
+    Retry(String),
+    /// Will never work, the report goes to the dead letters
+    Rejected(String),
+}
+
+/// A destination for board reports. Sinks are called from the upload thread
+/// only, every sink has its own retry state in the outbox.
+pub trait ReportSink {
+    /// Identifies the reports of this sink in the outbox, see
+    /// `options::Sink::name`
+    fn name(&self) -> String;
+
+    fn send(&mut self, report: &Report) -> Delivery;
+}
+
+/// The sinks configured in `TESTER_SINKS`
+pub fn from_options(options: &options::Options) -> Vec<Box<dyn ReportSink>> {
+    options
+        .sinks
+        .iter()
+        .map(|sink| -> Box<dyn ReportSink> {
+            match sink {
+                options::Sink::Api => Box::new(ApiSink::from_options(options)),
+                options::Sink::File(path) => Box::new(FileSink::new(path.clone())),
+                options::Sink::Database(path) => Box::new(DatabaseSink::new(
+                    path.clone(),
+                    options.tester_name.clone(),
+                    options.lot.clone(),
+                )),
+                options::Sink::Stdout => Box::new(StdoutSink),
+                options::Sink::Webhook(url) => Box::new(WebhookSink::new(
+                    url.clone(),
+                    options.tester_name.clone(),
+                    options.report_type.clone(),
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Whether the server will never accept the report, so retrying is pointless.
+/// Other errors (authentication, a proxy in the way, a missing route) can be
+/// fixed on the server, the report is kept for then.
+fn is_rejection(status: reqwest::StatusCode) -> bool {
+    matches!(
+        status,
+        reqwest::StatusCode::BAD_REQUEST
+            | reqwest::StatusCode::CONFLICT
+            | reqwest::StatusCode::UNPROCESSABLE_ENTITY
+    )
+}
+
+/// Sorts an HTTP response into delivered, retry or rejected
+fn http_delivery(response: reqwest::Result<reqwest::blocking::Response>) -> Delivery {
+    match response {
+        Ok(response) => {
+            let status = response.status();
+            let text = response.text().unwrap_or_default();
+
+            if status.is_success() {
+                tracing::debug!("report accepted: {}", text);
+                Delivery::Delivered
+            } else if is_rejection(status) {
+                Delivery::Rejected(format!("{}: {}", status, text))
+            } else {
+                Delivery::Retry(format!("{}: {}", status, text))
+            }
+        }
+        Err(e) => Delivery::Retry(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_invalid_reports_are_rejected() {
+        for status in [400, 409, 422] {
+            let status = reqwest::StatusCode::from_u16(status).unwrap();
+            assert!(is_rejection(status), "{}", status);
+        }
+
+        for status in [401, 403, 404, 408, 429, 500, 502, 503] {
+            let status = reqwest::StatusCode::from_u16(status).unwrap();
+            assert!(!is_rejection(status), "{}", status);
+        }
+    }
+}
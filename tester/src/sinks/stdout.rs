@@ -0,0 +1,27 @@
+use crate::options;
+
+use super::{Delivery, Report, ReportSink};
+
+/// Prints every report as a JSON object next to the events of
+/// `--output json`
+pub struct StdoutSink;
+
+impl ReportSink for StdoutSink {
+    fn name(&self) -> String {
+        options::Sink::Stdout.name()
+    }
+
+    fn send(&mut self, report: &Report) -> Delivery {
+        println!(
+            "{}",
+            serde_json::json!({
+                "event": "delivery",
+                "id": report.id,
+                "passed": report.passed(),
+                "board": report.board,
+            })
+        );
+
+        Delivery::Delivered
+    }
+}
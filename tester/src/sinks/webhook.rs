@@ -0,0 +1,49 @@
+use reqwest::blocking;
+
+use crate::options;
+
+use super::{http_delivery, Delivery, Report, ReportSink};
+
+/// POSTs every report as JSON, any 2xx answer counts as delivered
+pub struct WebhookSink {
+    url: String,
+    tester_name: String,
+    /// Test plan of boards that don't know theirs
+    report_type: String,
+    client: blocking::Client,
+}
+
+impl WebhookSink {
+    pub fn new(url: String, tester_name: String, report_type: String) -> WebhookSink {
+        WebhookSink {
+            url,
+            tester_name,
+            report_type,
+            client: blocking::Client::new(),
+        }
+    }
+}
+
+impl ReportSink for WebhookSink {
+    fn name(&self) -> String {
+        options::Sink::Webhook(self.url.clone()).name()
+    }
+
+    fn send(&mut self, report: &Report) -> Delivery {
+        let board = report.board;
+
+        let body = serde_json::json!({
+            "id": report.id,
+            "mac": board.id,
+            "type": board.report_type.as_deref().unwrap_or(&self.report_type),
+            "tester": self.tester_name,
+            "passed": report.passed(),
+            "startedAt": board.started_at,
+            "endedAt": board.ended_at,
+            "values": board.values,
+            "firmware": board.firmware,
+        });
+
+        http_delivery(self.client.post(&self.url).json(&body).send())
+    }
+}
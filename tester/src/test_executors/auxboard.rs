@@ -9,7 +9,7 @@ use crate::api;
 use crate::logger;
 use crate::Board;
 
-use super::TestExecutor;
+use super::{report, TestExecutor};
 
 type BNOInterface = bno080::wrapper::BNO080<bno080::interface::I2cInterface<rp_i2c::I2c>>;
 
@@ -35,11 +35,14 @@ impl AuxBoardTestExecutor {
 }
 
 impl TestExecutor for AuxBoardTestExecutor {
+	fn steps(&self) -> Vec<&'static str> {
+		vec!["Init", "Rotation vector", "Handling messages", "Quaternion"]
+	}
+
 	fn wait_for_device_connect(&mut self) {
 		loop {
-			match self.bno.init(&mut self.delay) {
-				Ok(_) => break,
-				_ => {}
+			if self.bno.init(&mut self.delay).is_ok() {
+				break;
 			}
 
 			thread::sleep(time::Duration::from_millis(250));
@@ -59,15 +62,19 @@ impl TestExecutor for AuxBoardTestExecutor {
 		let start = chrono::Utc::now();
 		match self.bno.init(&mut self.delay) {
 			Ok(_) => {
-				board.add_value(api::TestReportValue::new(
-					"Init",
-					"should be successful",
-					"true",
-					None::<String>,
-					false,
-					start,
-					chrono::Utc::now(),
-				));
+				report(
+					&self.logger,
+					&mut board,
+					api::TestReportValue::new(
+						"Init",
+						"should be successful",
+						"true",
+						None::<String>,
+						false,
+						start,
+						chrono::Utc::now(),
+					),
+				);
 
 				{
 					let mut l = self.logger.lock().unwrap();
@@ -75,15 +82,19 @@ impl TestExecutor for AuxBoardTestExecutor {
 				}
 			}
 			Err(e) => {
-				board.add_value(api::TestReportValue::new(
-					"Init",
-					"should be successful",
-					"false",
-					Some(format!("{:?}", e)),
-					true,
-					start,
-					chrono::Utc::now(),
-				));
+				report(
+					&self.logger,
+					&mut board,
+					api::TestReportValue::new(
+						"Init",
+						"should be successful",
+						"false",
+						Some(format!("{:?}", e)),
+						true,
+						start,
+						chrono::Utc::now(),
+					),
+				);
 				board.ended_at = chrono::Utc::now();
 
 				{
@@ -102,15 +113,19 @@ impl TestExecutor for AuxBoardTestExecutor {
 		let start = chrono::Utc::now();
 		match self.bno.enable_rotation_vector(5) {
 			Ok(_) => {
-				board.add_value(api::TestReportValue::new(
-					"Rotation vector",
-					"should be enableable",
-					"true",
-					None::<String>,
-					false,
-					start,
-					chrono::Utc::now(),
-				));
+				report(
+					&self.logger,
+					&mut board,
+					api::TestReportValue::new(
+						"Rotation vector",
+						"should be enableable",
+						"true",
+						None::<String>,
+						false,
+						start,
+						chrono::Utc::now(),
+					),
+				);
 
 				{
 					let mut l = self.logger.lock().unwrap();
@@ -118,15 +133,19 @@ impl TestExecutor for AuxBoardTestExecutor {
 				}
 			}
 			Err(e) => {
-				board.add_value(api::TestReportValue::new(
-					"Rotation vector",
-					"should be enabled",
-					"false",
-					Some(format!("{:?}", e)),
-					true,
-					start,
-					chrono::Utc::now(),
-				));
+				report(
+					&self.logger,
+					&mut board,
+					api::TestReportValue::new(
+						"Rotation vector",
+						"should be enabled",
+						"false",
+						Some(format!("{:?}", e)),
+						true,
+						start,
+						chrono::Utc::now(),
+					),
+				);
 				board.ended_at = chrono::Utc::now();
 
 				{
@@ -146,15 +165,19 @@ impl TestExecutor for AuxBoardTestExecutor {
 		thread::sleep(time::Duration::from_millis(500));
 
 		let processed_messages = self.bno.handle_all_messages(&mut self.delay, u8::MAX);
-		board.add_value(api::TestReportValue::new(
-			"Handling messages",
-			"should process messages",
-			processed_messages,
-			None::<String>,
-			false,
-			start,
-			chrono::Utc::now(),
-		));
+		report(
+			&self.logger,
+			&mut board,
+			api::TestReportValue::new(
+				"Handling messages",
+				"should process messages",
+				processed_messages,
+				None::<String>,
+				false,
+				start,
+				chrono::Utc::now(),
+			),
+		);
 
 		{
 			let mut l = self.logger.lock().unwrap();
@@ -166,15 +189,19 @@ impl TestExecutor for AuxBoardTestExecutor {
 		let start = chrono::Utc::now();
 		match self.bno.rotation_quaternion() {
 			Ok(q) => {
-				board.add_value(api::TestReportValue::new(
-					"Quaternion",
-					"should be valid",
-					"true",
-					Some(format!("{:?}", q)),
-					false,
-					start,
-					chrono::Utc::now(),
-				));
+				report(
+					&self.logger,
+					&mut board,
+					api::TestReportValue::new(
+						"Quaternion",
+						"should be valid",
+						"true",
+						Some(format!("{:?}", q)),
+						false,
+						start,
+						chrono::Utc::now(),
+					),
+				);
 
 				{
 					let mut l = self.logger.lock().unwrap();
@@ -182,15 +209,19 @@ impl TestExecutor for AuxBoardTestExecutor {
 				}
 			}
 			Err(e) => {
-				board.add_value(api::TestReportValue::new(
-					"Quaternion",
-					"should be valid",
-					"false",
-					Some(format!("{:?}", e)),
-					true,
-					start,
-					chrono::Utc::now(),
-				));
+				report(
+					&self.logger,
+					&mut board,
+					api::TestReportValue::new(
+						"Quaternion",
+						"should be valid",
+						"false",
+						Some(format!("{:?}", e)),
+						true,
+						start,
+						chrono::Utc::now(),
+					),
+				);
 				board.ended_at = chrono::Utc::now();
 
 				{
@@ -206,7 +237,9 @@ impl TestExecutor for AuxBoardTestExecutor {
 		crate::TestResult::Passed(board)
 	}
 
-	fn wait_for_device_disconnect(&mut self) {
+	fn wait_for_device_disconnect(&mut self, _: &mut dyn FnMut() -> bool) -> bool {
 		thread::sleep(time::Duration::from_secs(2));
+
+		true
 	}
 }
@@ -1,20 +1,35 @@
 use crate::{
-	adc, api, esp, esptool, logger,
+	adc, api, control, crash, esp, esptool, firmware, firmware_log, hub, logger,
 	options::{self, Options},
-	pio, serial, usb, Board, TestResult,
+	pio, provisioning, rom, serial, serial_log, session, subprocess, usb, Board, TestResult,
 };
 use ads1x1x::ChannelSelection;
 use rppal::{gpio, i2c};
-use std::{sync, thread, time};
+use std::{collections::BTreeMap, sync, thread, time};
 
-use super::TestExecutor;
+use super::{report, TestExecutor};
 
-const USB_VENDOR_ID: u16 = 0x1a86;
-const USB_PRODUCT_ID: u16 = 0x7523;
+const EXPECTED_IMU: &str = "BNO085";
+const EXPECTED_IMU_ADDRESS: &str = "0x4a";
+
+/// Logs what a tool using the serial port printed, the log is dropped if it
+/// can't be written
+fn record_tool_output(transcript: &mut Option<serial_log::Transcript>, output: &str) {
+	if let Some(t) = transcript {
+		if let Err(e) = t.record(serial_log::Direction::Tool, output.as_bytes()) {
+			tracing::warn!("could not write serial log, disabling it: {}", e);
+			*transcript = None;
+		}
+	}
+}
 
 pub struct MainBoardTestExecutor {
-	adc: adc::Ads1115<i2c::I2c>,
+	/// `None` when replaying a session
+	adc: Option<adc::Ads1115<i2c::I2c>>,
 	esp: esp::ESP,
+	/// Started on first use, so replays never touch USB
+	usb: Option<usb::Watcher>,
+	power: Option<hub::PowerSwitch>,
 	logger: sync::Arc<sync::Mutex<logger::Logger>>,
 	options: Options,
 }
@@ -32,84 +47,363 @@ impl MainBoardTestExecutor {
 			let rst_pin = gpio.get(6).unwrap().into_output_high();
 			let flash_pin = gpio.get(22).unwrap().into_output_high();
 
-			esp::ESP::new(rst_pin, flash_pin)
+			esp::ESP::new(Some(rst_pin), Some(flash_pin))
 		};
 
+		Self::with_hardware(Some(adc), esp, logger, options)
+	}
+
+	/// Executor without the I2C and GPIO of the jig, for replaying a recorded
+	/// session
+	pub fn replay(logger: sync::Arc<sync::Mutex<logger::Logger>>, mut options: Options) -> Self {
+		// Power cycles are part of the recording, only whether there is a
+		// switch matters
+		options.hub = options.hub.map(|_| options::Hub::Simulated);
+
+		Self::with_hardware(None, esp::ESP::new(None, None), logger, options)
+	}
+
+	fn with_hardware(
+		adc: Option<adc::Ads1115<i2c::I2c>>,
+		mut esp: esp::ESP,
+		logger: sync::Arc<sync::Mutex<logger::Logger>>,
+		options: Options,
+	) -> Self {
+		let power = options.hub.as_ref().and_then(|h| {
+			let hub: Box<dyn hub::PortPower> = match h {
+				options::Hub::Usb {
+					vendor_id,
+					product_id,
+					port_path,
+				} => match hub::UsbHub::open(*vendor_id, *product_id, port_path.as_deref()) {
+					Ok(hub) => Box::new(hub),
+					Err(e) => {
+						let mut l = logger.lock().unwrap();
+						l.error(&format!("Power cycling unavailable: {}", e));
+
+						return None;
+					}
+				},
+				options::Hub::Simulated => Box::<hub::SimulatedHub>::default(),
+			};
+
+			Some(hub::PowerSwitch::new(
+				hub,
+				options.hub_ports.clone(),
+				options.hub_off_time,
+			))
+		});
+
+		esp.chip = options.chip.clone();
+
 		Self {
 			adc,
 			esp,
+			usb: None,
+			power,
 			logger,
 			options,
 		}
 	}
 }
 
+impl MainBoardTestExecutor {
+	fn measure(&mut self, channel: ChannelSelection, name: &str) -> Result<f32, String> {
+		let adc = &mut self.adc;
+
+		session::interact("adc", name, || match adc {
+			Some(adc) => adc.measure(channel).map_err(adc::describe_error),
+			None => Err("err: no ADC".to_string()),
+		})
+	}
+
+	/// Power cycles the DUT and waits until its serial port is back, returning
+	/// how long that took after power was restored
+	fn power_cycle(&mut self) -> Result<time::Duration, String> {
+		let (result, port) = session::interact("usb", "power cycle", || {
+			let result = self.power_cycle_live().map(|d| d.as_millis() as u64);
+
+			(result, self.esp.port.clone())
+		});
+		self.esp.port = port;
+
+		result.map(time::Duration::from_millis)
+	}
+
+	fn power_cycle_live(&mut self) -> Result<time::Duration, String> {
+		let slot = self.options.slot;
+		let power = self
+			.power
+			.as_mut()
+			.ok_or("no hub configured for power cycling")?;
+
+		power.power_off(slot)?;
+		let off_since = time::Instant::now();
+
+		// Until the old device is gone it would be found again right away
+		let disconnected = self
+			.usb
+			.get_or_insert_with(usb::Watcher::new)
+			.wait_for_disconnect(
+				&self.options.bridges,
+				self.options.usb_port_path.as_deref(),
+				power.off_time().max(time::Duration::from_secs(5)),
+			);
+		if !disconnected {
+			power.power_on(slot)?;
+
+			return Err(format!(
+				"device did not disconnect when the hub port of slot {} was switched off",
+				slot
+			));
+		}
+		thread::sleep(power.off_time().saturating_sub(off_since.elapsed()));
+
+		power.power_on(slot)?;
+		let start = time::Instant::now();
+
+		let device = self
+			.usb
+			.get_or_insert_with(usb::Watcher::new)
+			.wait_for_device(
+				&self.options.bridges,
+				self.options.usb_port_path.as_deref(),
+				time::Duration::from_secs(10),
+			)
+			.ok_or("device did not come back after power cycle")?;
+
+		self.esp.port = usb::wait_for_serial_port(&device, time::Duration::from_secs(5))
+			.ok_or("serial port did not come back after power cycle")?;
+
+		Ok(start.elapsed())
+	}
+
+	fn wait_for_device_connect_live(&mut self) {
+		loop {
+			let device = self
+				.usb
+				.get_or_insert_with(usb::Watcher::new)
+				.wait_until_device_is_connected(
+					&self.options.bridges,
+					self.options.usb_port_path.as_deref(),
+				);
+
+			match usb::wait_for_serial_port(&device, time::Duration::from_secs(5)) {
+				Some(port) => {
+					self.esp.port = port;
+					self.esp.reset_method = device.bridge.reset;
+					return;
+				}
+				None => {
+					let mut l = self.logger.lock().unwrap();
+					l.error(&format!(
+						"No serial port found for device on {}",
+						device.port_path
+					));
+				}
+			}
+
+			self.usb
+				.get_or_insert_with(usb::Watcher::new)
+				.wait_until_device_is_disconnected(&self.options.bridges, Some(&device.port_path));
+		}
+	}
+
+	/// Starts the raw serial log of `board`, named after its MAC address
+	fn create_transcript(&self, board: &mut Board) -> Option<serial_log::Transcript> {
+		let dir = self.options.serial_logs.dir.as_ref()?;
+		let id = board.id.as_deref().unwrap_or("unknown");
+
+		let transcript = match serial_log::Transcript::create(
+			dir,
+			id,
+			self.options.serial_logs.max_file_size,
+			self.options.serial_logs.max_files_per_board,
+		) {
+			Ok(transcript) => {
+				let now = chrono::Utc::now();
+				report(
+					&self.logger,
+					board,
+					api::TestReportValue::new(
+						"Serial log",
+						"Serial traffic should be logged",
+						transcript.path().display(),
+						None::<&str>,
+						false,
+						now,
+						now,
+					),
+				);
+
+				Some(transcript)
+			}
+			Err(e) => {
+				tracing::warn!("could not create serial log: {}", e);
+
+				None
+			}
+		};
+
+		if let Err(e) = serial_log::cleanup(dir, self.options.serial_logs.keep_boards) {
+			tracing::warn!("could not clean up serial logs: {}", e);
+		}
+
+		transcript
+	}
+
+	/// Appends the decoded backtrace to `logs` if the firmware crashed since
+	/// the serial port was opened
+	fn with_crash_trace(&self, logs: String, serial: &serial::Reader) -> String {
+		let Some(crash) = crash::find(serial.history()) else {
+			return logs;
+		};
+
+		{
+			let mut l = self.logger.lock().unwrap();
+			l.error(&format!("Firmware crashed: {}", crash.title));
+		}
+
+		let elf = pio::elf_path(&self.options.firmware_path, &self.options.pio_environment);
+		logs + "\n\n" + &crash.decode(&elf)
+	}
+
+	/// Adds the warnings and errors the firmware logged since the serial port
+	/// was opened to the report. They don't fail the board on their own.
+	fn report_firmware_problems(
+		&self,
+		board: &mut Board,
+		serial: &serial::Reader,
+		start: chrono::DateTime<chrono::Utc>,
+	) {
+		let problems = firmware_log::problems(serial.history());
+		let errors = problems
+			.iter()
+			.filter(|(level, _)| *level >= firmware_log::Level::Error)
+			.count();
+		let warnings = problems.len() - errors;
+
+		if !problems.is_empty() {
+			let mut l = self.logger.lock().unwrap();
+			l.error(&format!(
+				"Firmware logged {} warnings and {} errors",
+				warnings, errors
+			));
+		}
+
+		report(
+			&self.logger,
+			board,
+			api::TestReportValue::new(
+				"Firmware logs",
+				"Firmware logs should not contain warnings or errors",
+				format!("{} warnings, {} errors", warnings, errors),
+				(!problems.is_empty()).then(|| {
+					problems
+						.into_iter()
+						.map(|(_, line)| line)
+						.collect::<Vec<_>>()
+						.join("\n")
+				}),
+				false,
+				start,
+				chrono::Utc::now(),
+			),
+		);
+	}
+}
+
 impl TestExecutor for MainBoardTestExecutor {
+	fn steps(&self) -> Vec<&'static str> {
+		let mut steps = vec![
+			"Measure VOUT",
+			"Measure B+",
+			"Measure 3V3",
+			"Read MAC address",
+			"Flashing",
+		];
+		if self.options.provision {
+			steps.push("Provisioning");
+		}
+		if self.options.cold_boot && self.power.is_some() {
+			steps.push("Cold boot");
+		}
+		steps.extend(["Serial", "Boot mode", "I2C to IMU", "IMU test"]);
+
+		steps
+	}
+
 	fn wait_for_device_connect(&mut self) {
-		usb::wait_until_device_is_connected(USB_VENDOR_ID, USB_PRODUCT_ID);
+		let (port, reset) = session::interact("usb", "connect", || {
+			self.wait_for_device_connect_live();
+
+			(
+				self.esp.port.clone(),
+				self.esp.reset_method.as_str().to_string(),
+			)
+		});
+
+		self.esp.port = port;
+		self.esp.reset_method = usb::ResetMethod::parse(&reset).unwrap();
 	}
 
-	fn wait_for_device_disconnect(&mut self) {
-		usb::wait_until_device_is_disconnected(USB_VENDOR_ID, USB_PRODUCT_ID);
+	fn wait_for_device_disconnect(&mut self, stop: &mut dyn FnMut() -> bool) -> bool {
+		session::interact("usb", "disconnect", || {
+			self.usb
+				.get_or_insert_with(usb::Watcher::new)
+				.wait_until_device_is_disconnected_or(
+					&self.options.bridges,
+					self.options.usb_port_path.as_deref(),
+					time::Duration::from_millis(100),
+					stop,
+				)
+		})
 	}
 
 	fn run(&mut self) -> TestResult {
-		thread::sleep(time::Duration::from_millis(250));
+		session::sleep(time::Duration::from_millis(250));
 
 		let mut board = Board::new();
+		let mut calibration = BTreeMap::new();
+		// Started once the MAC address is known
+		let mut transcript;
 
 		let err = {
 			let start = chrono::Utc::now();
 
-			match self.adc.measure(ChannelSelection::SingleA2) {
+			match self.measure(ChannelSelection::SingleA2, "vout") {
 				Ok(v) => {
-					board.values.push(api::TestReportValue::new(
-						"Measure VOUT",
-						"none",
-						v.to_string() + "V",
-						None::<&str>,
-						false,
-						start,
-						chrono::Utc::now(),
-					));
+					calibration.insert("vout".to_string(), v);
+
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
+							"Measure VOUT",
+							"none",
+							v.to_string() + "V",
+							None::<&str>,
+							false,
+							start,
+							chrono::Utc::now(),
+						),
+					);
 				}
-				Err(e) => match e {
-					nb::Error::WouldBlock => {
-						board.values.push(api::TestReportValue::new(
+				Err(e) => {
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
 							"Measure VOUT",
 							"none",
 							"N/A",
-							Some("err: would block"),
+							Some(e),
 							false,
 							start,
 							chrono::Utc::now(),
-						));
-					}
-					nb::Error::Other(e) => match e {
-						ads1x1x::Error::I2C(e) => {
-							board.values.push(api::TestReportValue::new(
-								"Measure VOUT",
-								"none",
-								"N/A",
-								Some(&format!("err: i2c: {}", e)),
-								false,
-								start,
-								chrono::Utc::now(),
-							));
-						}
-						ads1x1x::Error::InvalidInputData => {
-							board.values.push(api::TestReportValue::new(
-								"Measure VOUT",
-								"none",
-								"N/A",
-								Some("err: invalid input data"),
-								false,
-								start,
-								chrono::Utc::now(),
-							));
-						}
-					},
-				},
+						),
+					);
+				}
 			};
 
 			{
@@ -118,8 +412,10 @@ impl TestExecutor for MainBoardTestExecutor {
 			}
 
 			let start = chrono::Utc::now();
-			let bplus_err = match self.adc.measure(ChannelSelection::SingleA3) {
+			let bplus_err = match self.measure(ChannelSelection::SingleA3, "bplus") {
 				Ok(v) => {
+					calibration.insert("bplus".to_string(), v);
+
 					let bplus_err = v < 4.0;
 					{
 						let mut l = self.logger.lock().unwrap();
@@ -130,61 +426,39 @@ impl TestExecutor for MainBoardTestExecutor {
 						}
 					}
 
-					board.values.push(api::TestReportValue::new(
-						"Measure B+",
-						"B+ > 4.0V",
-						v.to_string() + "V",
-						None::<&str>,
-						bplus_err,
-						start,
-						chrono::Utc::now(),
-					));
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
+							"Measure B+",
+							"B+ > 4.0V",
+							v.to_string() + "V",
+							None::<&str>,
+							bplus_err,
+							start,
+							chrono::Utc::now(),
+						),
+					);
 
 					bplus_err
 				}
-				Err(e) => match e {
-					nb::Error::WouldBlock => {
-						board.values.push(api::TestReportValue::new(
+				Err(e) => {
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
 							"Measure B+",
 							"B+ > 4.0V",
 							"N/A",
-							Some("err: would block"),
+							Some(e),
 							true,
 							start,
 							chrono::Utc::now(),
-						));
-
-						true
-					}
-					nb::Error::Other(e) => match e {
-						ads1x1x::Error::I2C(e) => {
-							board.values.push(api::TestReportValue::new(
-								"Measure B+",
-								"B+ > 4.0V",
-								"N/A",
-								Some(&format!("err: i2c: {}", e)),
-								true,
-								start,
-								chrono::Utc::now(),
-							));
+						),
+					);
 
-							true
-						}
-						ads1x1x::Error::InvalidInputData => {
-							board.values.push(api::TestReportValue::new(
-								"Measure B+",
-								"B+ > 4.0V",
-								"N/A",
-								Some("err: invalid input data"),
-								true,
-								start,
-								chrono::Utc::now(),
-							));
-
-							true
-						}
-					},
-				},
+					true
+				}
 			};
 
 			{
@@ -193,8 +467,10 @@ impl TestExecutor for MainBoardTestExecutor {
 			}
 
 			let start = chrono::Utc::now();
-			let r3v3_err = match self.adc.measure(ChannelSelection::SingleA0) {
+			let r3v3_err = match self.measure(ChannelSelection::SingleA0, "3v3") {
 				Ok(v) => {
+					calibration.insert("3v3".to_string(), v);
+
 					let r3v3_err = v < 2.8 || v > 3.2;
 					{
 						let mut l = self.logger.lock().unwrap();
@@ -205,61 +481,39 @@ impl TestExecutor for MainBoardTestExecutor {
 						}
 					}
 
-					board.values.push(api::TestReportValue::new(
-						"Measure 3V3",
-						"2.8V > 3V3 < 3.2V",
-						v.to_string() + "V",
-						None::<&str>,
-						r3v3_err,
-						start,
-						chrono::Utc::now(),
-					));
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
+							"Measure 3V3",
+							"2.8V > 3V3 < 3.2V",
+							v.to_string() + "V",
+							None::<&str>,
+							r3v3_err,
+							start,
+							chrono::Utc::now(),
+						),
+					);
 
 					r3v3_err
 				}
-				Err(e) => match e {
-					nb::Error::WouldBlock => {
-						board.values.push(api::TestReportValue::new(
+				Err(e) => {
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
 							"Measure 3V3",
 							"2.8V > 3V3 < 3.2V",
 							"N/A",
-							Some("err: would block"),
+							Some(e),
 							true,
 							start,
 							chrono::Utc::now(),
-						));
-
-						true
-					}
-					nb::Error::Other(e) => match e {
-						ads1x1x::Error::I2C(e) => {
-							board.values.push(api::TestReportValue::new(
-								"Measure 3V3",
-								"2.8V > 3V3 < 3.2V",
-								"N/A",
-								Some(&format!("err: i2c: {}", e)),
-								true,
-								start,
-								chrono::Utc::now(),
-							));
-
-							true
-						}
-						ads1x1x::Error::InvalidInputData => {
-							board.values.push(api::TestReportValue::new(
-								"Measure 3V3",
-								"2.8V > 3V3 < 3.2V",
-								"N/A",
-								Some("err: invalid input data"),
-								true,
-								start,
-								chrono::Utc::now(),
-							));
+						),
+					);
 
-							true
-						}
-					},
-				},
+					true
+				}
 			};
 
 			bplus_err || r3v3_err
@@ -281,19 +535,52 @@ impl TestExecutor for MainBoardTestExecutor {
 				l.in_progress("Reading MAC address...");
 			}
 
+			let logger = self.logger.clone();
+			let step = subprocess::Step::new(
+				&logger,
+				"Reading MAC address",
+				self.options.timeouts.esptool,
+			);
+
 			let start = chrono::Utc::now();
-			match esptool::read_mac_address(&mut self.esp) {
+			let mut result = esptool::read_mac_address(&mut self.esp, &step);
+
+			// A board stuck in a weird state often recovers after losing power
+			if result.is_err() && self.power.is_some() {
+				{
+					let mut l = self.logger.lock().unwrap();
+					l.in_progress("Power cycling board and retrying...");
+				}
+
+				match self.power_cycle() {
+					Ok(_) => result = esptool::read_mac_address(&mut self.esp, &step),
+					Err(e) => {
+						let mut l = self.logger.lock().unwrap();
+						l.error(&format!("Power cycle failed: {}", e));
+					}
+				}
+			}
+
+			match result {
 				Ok(esptool::ReadMacAddressResult { mac, log }) => {
 					board.id = Some(mac.clone());
-					board.values.push(api::TestReportValue::new(
-						"Read MAC address",
-						"MAC address should be readable",
-						mac.clone(),
-						Some(log),
-						false,
-						start,
-						chrono::Utc::now(),
-					));
+
+					transcript = self.create_transcript(&mut board);
+					record_tool_output(&mut transcript, &log);
+
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
+							"Read MAC address",
+							"MAC address should be readable",
+							mac.clone(),
+							Some(log),
+							false,
+							start,
+							chrono::Utc::now(),
+						),
+					);
 
 					{
 						let mut l = self.logger.lock().unwrap();
@@ -301,15 +588,19 @@ impl TestExecutor for MainBoardTestExecutor {
 					}
 				}
 				Err(e) => {
-					board.values.push(api::TestReportValue::new(
-						"Read MAC address",
-						"MAC address should be readable",
-						"N/A",
-						Some(e.to_string()),
-						true,
-						start,
-						chrono::Utc::now(),
-					));
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
+							"Read MAC address",
+							"MAC address should be readable",
+							"N/A",
+							Some(e.to_string()),
+							true,
+							start,
+							chrono::Utc::now(),
+						),
+					);
 
 					{
 						let mut l = self.logger.lock().unwrap();
@@ -329,44 +620,79 @@ impl TestExecutor for MainBoardTestExecutor {
 				l.in_progress("Flashing...");
 			}
 
+			let step = subprocess::Step::new(&self.logger, "Flashing", self.options.timeouts.flash);
+
 			let start = chrono::Utc::now();
 			let result = match self.options.flash_with {
-				options::FlashWith::ESPTool => esptool::write_flash(
-					"slimevr-tracker-esp/.pio/build/esp12e/firmware.bin",
+				options::FlashWith::ESPTool => esptool::write_flash_with_fallback(
+					&pio::artifact_path(&self.options.firmware_path, &self.options.pio_environment)
+						.to_string_lossy(),
 					&mut self.esp,
-					self.options.flash_baudrate,
-				),
-				options::FlashWith::PlatformIO => pio::flash("esp12e", &mut self.esp),
+					&self.options.flash_baudrates,
+					&self.options.flash_modes,
+					&step,
+				)
+				.map(|r| {
+					(
+						format!("{} baud, {}", r.baudrate, r.mode),
+						r.summary() + "\n\n" + &r.log,
+						r.degraded(),
+					)
+				}),
+				options::FlashWith::PlatformIO => pio::flash(
+					&self.options.firmware_path,
+					&self.options.pio_environment,
+					&mut self.esp,
+					&step,
+				)
+				.map(|l| ("pio".to_string(), l, false)),
 			};
 			let end = chrono::Utc::now();
 
+			match &result {
+				Ok((_, logs, _)) => record_tool_output(&mut transcript, logs),
+				Err(e) => record_tool_output(&mut transcript, &e.to_string()),
+			}
+
 			match result {
-				Ok(logs) => {
-					board.values.push(api::TestReportValue::new(
-						"Flashing",
-						"Flashing should work",
-						true,
-						Some(logs),
-						false,
-						start,
-						end,
-					));
+				Ok((params, logs, degraded)) => {
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
+							"Flashing",
+							"Flashing should work",
+							params.clone(),
+							Some(logs),
+							false,
+							start,
+							end,
+						),
+					);
 
 					{
 						let mut l = self.logger.lock().unwrap();
-						l.success("Flashing successful");
+						if degraded {
+							l.success(&format!("Flashing successful ({}, after retries)", params));
+						} else {
+							l.success("Flashing successful");
+						}
 					}
 				}
 				Err(e) => {
-					board.values.push(api::TestReportValue::new(
-						"Flashing",
-						"Flashing should work",
-						false,
-						Some(e.to_string()),
-						true,
-						start,
-						end,
-					));
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
+							"Flashing",
+							"Flashing should work",
+							false,
+							Some(e.to_string()),
+							true,
+							start,
+							end,
+						),
+					);
 
 					{
 						let mut l = self.logger.lock().unwrap();
@@ -380,18 +706,162 @@ impl TestExecutor for MainBoardTestExecutor {
 			}
 		};
 
+		if self.options.provision {
+			{
+				let mut l = self.logger.lock().unwrap();
+				l.in_progress("Writing provisioning record...");
+			}
+
+			let record = provisioning::ProvisioningRecord::new(
+				&self.options.serial_prefix,
+				board.id.as_deref().unwrap_or_default(),
+				&self.options.hardware_revision,
+				calibration,
+			);
+
+			let start = chrono::Utc::now();
+			let result = provisioning::provision(
+				&record,
+				self.options.provisioning_offset,
+				&mut self.esp,
+				*self.options.flash_baudrates.last().unwrap(),
+				&subprocess::Step::new(
+					&self.logger,
+					"Writing provisioning record",
+					self.options.timeouts.esptool,
+				),
+			);
+			let end = chrono::Utc::now();
+
+			match &result {
+				Ok(log) => record_tool_output(&mut transcript, log),
+				Err(e) => record_tool_output(&mut transcript, &e.to_string()),
+			}
+
+			let record_json = serde_json::to_string_pretty(&record).unwrap();
+
+			match result {
+				Ok(log) => {
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
+							"Provisioning",
+							"Provisioning record should read back identically",
+							&record.serial_number,
+							Some(record_json + "\n\n" + &log),
+							false,
+							start,
+							end,
+						),
+					);
+
+					{
+						let mut l = self.logger.lock().unwrap();
+						l.success(&format!("Provisioned as {}", record.serial_number));
+					}
+				}
+				Err(e) => {
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
+							"Provisioning",
+							"Provisioning record should read back identically",
+							&record.serial_number,
+							Some(record_json + "\n\n" + &e.to_string()),
+							true,
+							start,
+							end,
+						),
+					);
+
+					{
+						let mut l = self.logger.lock().unwrap();
+						l.error(&format!("Provisioning: {}", e));
+						l.error("-> Provisioning failed");
+					}
+
+					board.ended_at = chrono::Utc::now();
+					return TestResult::Failed(board);
+				}
+			}
+		}
+
+		if self.options.cold_boot && self.power.is_some() {
+			{
+				let mut l = self.logger.lock().unwrap();
+				l.in_progress("Cold booting...");
+			}
+
+			let start = chrono::Utc::now();
+			let result = self.power_cycle();
+			let end = chrono::Utc::now();
+
+			match result {
+				Ok(elapsed) => {
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
+							"Cold boot",
+							"Board should re-enumerate after power cycle",
+							format!("{}ms", elapsed.as_millis()),
+							None::<&str>,
+							false,
+							start,
+							end,
+						),
+					);
+
+					{
+						let mut l = self.logger.lock().unwrap();
+						l.success(&format!("Cold boot took {}ms", elapsed.as_millis()));
+					}
+				}
+				Err(e) => {
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
+							"Cold boot",
+							"Board should re-enumerate after power cycle",
+							"N/A",
+							Some(&e),
+							true,
+							start,
+							end,
+						),
+					);
+
+					{
+						let mut l = self.logger.lock().unwrap();
+						l.error(&format!("Cold boot: {}", e));
+						l.error("-> Board does not come back after power cycle");
+					}
+
+					board.ended_at = chrono::Utc::now();
+					return TestResult::Failed(board);
+				}
+			}
+		}
+
 		{
 			{
 				let mut l = self.logger.lock().unwrap();
 				l.in_progress("Connecting to serial port...");
 			}
 
+			let rom = rom::Rom::of_chip(&self.options.chip);
+
 			let start = chrono::Utc::now();
-			let serial = serialport::new("/dev/ttyUSB0", 115200)
-				.timeout(time::Duration::from_millis(10000))
-				.data_bits(serialport::DataBits::Eight)
-				.open();
+			let serial = session::open_serial(
+				&self.esp.port,
+				rom.baudrate(),
+				time::Duration::from_millis(10000),
+			);
 			let end = chrono::Utc::now();
+			let serial_start = start;
 
 			let mut serial = match serial {
 				Ok(serial) => {
@@ -400,15 +870,23 @@ impl TestExecutor for MainBoardTestExecutor {
 						l.success("Serial port opened");
 					}
 
-					board.values.push(api::TestReportValue::new(
-						"Serial",
-						"Serial should work",
-						true,
-						None::<&str>,
-						false,
-						start,
-						end,
-					));
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
+							"Serial",
+							"Serial should work",
+							true,
+							None::<&str>,
+							false,
+							start,
+							end,
+						),
+					);
+
+					let mut serial = serial::Reader::new(serial);
+					serial.set_clock(session::now);
+					serial.set_interrupt(control::check);
 
 					serial
 				}
@@ -419,23 +897,147 @@ impl TestExecutor for MainBoardTestExecutor {
 						l.error("-> Serial port failed");
 					}
 
-					board.values.push(api::TestReportValue::new(
-						"Serial",
-						"Serial should work",
-						false,
-						Some(error),
-						true,
-						start,
-						end,
-					));
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
+							"Serial",
+							"Serial should work",
+							false,
+							Some(error),
+							true,
+							start,
+							end,
+						),
+					);
 
 					board.ended_at = chrono::Utc::now();
 					return TestResult::Failed(board);
 				}
 			};
 
-			if let Err(e) = serial.clear(serialport::ClearBuffer::All) {
-				println!("(warn) failed to clear serial port: {}", e);
+			if let Err(e) = serial.clear() {
+				tracing::warn!("{}", e);
+			}
+
+			if let Some(transcript) = transcript.take() {
+				serial.set_tap(transcript);
+			}
+
+			// The ROM bootloader prints the boot mode at its own baud rate
+			// before the firmware switches to 115200
+			{
+				{
+					let mut l = self.logger.lock().unwrap();
+					l.in_progress("Checking boot mode...");
+				}
+
+				let start = chrono::Utc::now();
+
+				// The DUT might have been unplugged since the port was opened
+				if let Err(e) = self.esp.reset_with_serial(serial.serial_mut()) {
+					{
+						let mut l = self.logger.lock().unwrap();
+						l.error(&format!("Failed to reset the ESP: {}", e));
+						l.error("-> Boot mode check failed");
+					}
+
+					report(
+						&self.logger,
+						&mut board,
+						api::TestReportValue::new(
+							"Boot mode",
+							"ESP should boot from flash",
+							"unknown",
+							Some(e),
+							true,
+							start,
+							chrono::Utc::now(),
+						),
+					);
+
+					board.ended_at = chrono::Utc::now();
+					return TestResult::Failed(board);
+				}
+
+				let result = rom::read_banner(&mut serial, rom, time::Duration::from_secs(2))
+					.and_then(|banner| {
+						serial.set_baud_rate(115200)?;
+
+						Ok(banner)
+					});
+
+				match result {
+					Ok(banner) if banner.is_flash_boot() => {
+						{
+							let mut l = self.logger.lock().unwrap();
+							l.success(&format!("Booting from flash ({})", banner));
+						}
+
+						report(
+							&self.logger,
+							&mut board,
+							api::TestReportValue::new(
+								"Boot mode",
+								"ESP should boot from flash",
+								&banner,
+								Some(&banner.logs),
+								false,
+								start,
+								chrono::Utc::now(),
+							),
+						);
+					}
+					Ok(banner) => {
+						{
+							let mut l = self.logger.lock().unwrap();
+							l.error(&format!("Not booting from flash ({})", banner));
+							l.error("-> Check GPIO0, GPIO2 and GPIO15 for shorts");
+						}
+
+						report(
+							&self.logger,
+							&mut board,
+							api::TestReportValue::new(
+								"Boot mode",
+								"ESP should boot from flash",
+								&banner,
+								Some(&banner.logs),
+								true,
+								start,
+								chrono::Utc::now(),
+							),
+						);
+
+						board.ended_at = chrono::Utc::now();
+						return TestResult::Failed(board);
+					}
+					Err(logs) => {
+						// Not fatal, the firmware output tells if it started
+						{
+							let mut l = self.logger.lock().unwrap();
+							l.error("No ROM boot message received");
+						}
+
+						report(
+							&self.logger,
+							&mut board,
+							api::TestReportValue::new(
+								"Boot mode",
+								"ESP should boot from flash",
+								"unknown",
+								Some(logs),
+								false,
+								start,
+								chrono::Utc::now(),
+							),
+						);
+
+						if let Err(e) = serial.set_baud_rate(115200) {
+							tracing::warn!("{}", e);
+						}
+					}
+				}
 			}
 
 			// Read serial logs for checking the logs for sensor errors
@@ -445,46 +1047,87 @@ impl TestExecutor for MainBoardTestExecutor {
 					l.in_progress("Checking I2C connection to IMU...");
 				}
 
-				self.esp.reset_no_delay().unwrap();
-
 				let start = chrono::Utc::now();
-				match serial::read_string_until(
-					&mut serial,
-					vec!["[INFO ] [BNO080Sensor:0] Connected to BNO085 on 0x4a"],
-					vec!["ERR", "[FATAL"],
-				) {
-					Ok(logs) => {
+				let mut detector = crash::Detector::default();
+				let result = serial.expect_with(self.options.timeouts.serial, |line| {
+					if let Some(crash) = detector.feed(&line.text) {
+						return Some(Err(format!("firmware crashed: {}", crash.title)));
+					}
+
+					if detector.in_dump() {
+						return None;
+					}
+
+					let entry = firmware_log::Entry::parse(&line.text);
+
+					match entry.event() {
+						Some(firmware_log::Event::SensorFound {
+							sensor: Some(0),
+							imu,
+							address,
+						}) => Some(if imu == EXPECTED_IMU && address == EXPECTED_IMU_ADDRESS {
+							Ok(format!("IMU type = {}, address = {}", imu, address))
+						} else {
+							Err(format!(
+								"expected {} on {}, found {} on {}",
+								EXPECTED_IMU, EXPECTED_IMU_ADDRESS, imu, address
+							))
+						}),
+						Some(firmware_log::Event::SensorError { message, .. }) => {
+							Some(Err(format!("sensor error: {}", message)))
+						}
+						_ if entry.level >= Some(firmware_log::Level::Error) => {
+							Some(Err(format!("firmware error: {}", entry.message)))
+						}
+						_ => None,
+					}
+				});
+
+				match result {
+					Ok((value, logs)) => {
 						{
 							let mut l = self.logger.lock().unwrap();
-							l.success("I2C to IMU working");
+							l.success(&format!("I2C to IMU working ({})", value));
 						}
 
-						board.values.push(api::TestReportValue::new(
-							"I2C to IMU",
-							"I2C to IMU should work",
-							true,
-							Some(logs),
-							false,
-							start,
-							chrono::Utc::now(),
-						));
+						report(
+							&self.logger,
+							&mut board,
+							api::TestReportValue::new(
+								"I2C to IMU",
+								"I2C to IMU should work",
+								value,
+								Some(logs),
+								false,
+								start,
+								chrono::Utc::now(),
+							),
+						);
 					}
 					Err(logs) => {
+						let logs = self.with_crash_trace(logs, &serial);
+
 						{
 							let mut l = self.logger.lock().unwrap();
 							l.error("I2C to IMU faulty");
 							l.error(&logs);
 						}
 
-						board.values.push(api::TestReportValue::new(
-							"I2C to IMU",
-							"I2C to IMU should work",
-							false,
-							Some(logs),
-							true,
-							start,
-							chrono::Utc::now(),
-						));
+						report(
+							&self.logger,
+							&mut board,
+							api::TestReportValue::new(
+								"I2C to IMU",
+								"I2C to IMU should work",
+								false,
+								Some(logs),
+								true,
+								start,
+								chrono::Utc::now(),
+							),
+						);
+
+						self.report_firmware_problems(&mut board, &serial, serial_start);
 
 						board.ended_at = chrono::Utc::now();
 						return TestResult::Failed(board);
@@ -492,7 +1135,7 @@ impl TestExecutor for MainBoardTestExecutor {
 				};
 			};
 
-			thread::sleep(time::Duration::from_millis(100));
+			session::sleep(time::Duration::from_millis(100));
 
 			{
 				{
@@ -501,72 +1144,140 @@ impl TestExecutor for MainBoardTestExecutor {
 				}
 
 				let start = chrono::Utc::now();
-				if let Err(e) = serial::write(&mut serial, b"GET TEST\n") {
-					{
-						let mut l = self.logger.lock().unwrap();
-						l.error(&format!("Failed to write to serial port: {}", e));
-					}
-
-					board.values.push(api::TestReportValue::new(
-						"IMU test",
-						"IMU test should work",
-						false,
-						Some(format!("Failed to write to serial port: {}", e)),
-						true,
-						start,
-						chrono::Utc::now(),
-					));
-
-					board.ended_at = chrono::Utc::now();
-					return TestResult::Failed(board);
-				}
-
-				match serial::read_string_until(
-					&mut serial,
-					vec!["Sensor 1 sent some data, looks working."],
-					vec!["Sensor 1 didn't send any data yet!"],
-				) {
-					Ok(logs) => {
+				let mut client = firmware::Client::new(&mut serial, self.options.timeouts.serial);
+				match client.get_test() {
+					Ok(test) if test.sensor.had_data => {
 						{
 							let mut l = self.logger.lock().unwrap();
 							l.success("IMU test successful");
 						}
 
-						board.values.push(api::TestReportValue::new(
-							"IMU test",
-							"IMU test should work",
-							true,
-							Some(logs),
-							false,
-							start,
-							chrono::Utc::now(),
-						));
+						report(
+							&self.logger,
+							&mut board,
+							api::TestReportValue::new(
+								"IMU test",
+								"IMU test should work",
+								format!("Sensor[{}]: {}", test.sensor.id, test.sensor.imu),
+								Some(test.logs),
+								false,
+								start,
+								chrono::Utc::now(),
+							),
+						);
 					}
-					Err(logs) => {
+					result => {
+						let (value, logs) = match result {
+							Ok(test) => (
+								format!(
+									"Sensor[{}]: {} didn't send any data",
+									test.sensor.id, test.sensor.imu
+								),
+								test.logs,
+							),
+							Err(e) => ("no response".to_string(), e.to_string()),
+						};
+						let logs = self.with_crash_trace(logs, &serial);
+
 						{
 							let mut l = self.logger.lock().unwrap();
 							l.error("IMU test failed");
 							l.error(&logs);
 						}
 
-						board.values.push(api::TestReportValue::new(
-							"IMU test",
-							"IMU test should work",
-							false,
-							Some(logs),
-							true,
-							start,
-							chrono::Utc::now(),
-						));
+						report(
+							&self.logger,
+							&mut board,
+							api::TestReportValue::new(
+								"IMU test",
+								"IMU test should work",
+								value,
+								Some(logs),
+								true,
+								start,
+								chrono::Utc::now(),
+							),
+						);
+
+						self.report_firmware_problems(&mut board, &serial, serial_start);
 
 						board.ended_at = chrono::Utc::now();
 						return TestResult::Failed(board);
 					}
 				};
 			};
+
+			self.report_firmware_problems(&mut board, &serial, serial_start);
 		}
 
 		board.ended_at = chrono::Utc::now();
 		TestResult::Passed(board)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::path::Path;
+
+	use super::*;
+
+	/// Outcome of every step, like the checkpoint of the tester
+	fn outcome(board: &Board) -> Vec<(&str, bool)> {
+		board
+			.values
+			.iter()
+			.map(|v| (v.step.as_str(), v.failed))
+			.collect()
+	}
+
+	#[test]
+	fn replays_recorded_boards() {
+		let _guard = session::TestGuard::take();
+		session::replay(
+			&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/sessions/failures.jsonl"),
+		)
+		.unwrap();
+
+		let (_renderer, logger) = logger::LoggerBuilder::split();
+		let mut options = Options::parse();
+		options.chip = "esp8266".to_string();
+		options.hub = None;
+		options.cold_boot = false;
+		options.provision = false;
+		options.flash_with = options::FlashWith::ESPTool;
+		options.flash_baudrates = vec![921600];
+		options.flash_modes = vec![options::FlashMode::Dio];
+		options.serial_logs.dir = None;
+
+		let mut executor =
+			MainBoardTestExecutor::replay(sync::Arc::new(sync::Mutex::new(logger)), options);
+
+		executor.wait_for_device_connect();
+		let TestResult::Failed(board) = executor.run() else {
+			panic!("a low B+ has to fail the board");
+		};
+		assert_eq!(board.id, None);
+		assert_eq!(board.values[1].value, "3.12V");
+		session::checkpoint("result", &outcome(&board)).unwrap();
+		assert!(executor.wait_for_device_disconnect(&mut || false));
+
+		executor.wait_for_device_connect();
+		assert_eq!(executor.esp.port, "/dev/ttyUSB0");
+		assert_eq!(executor.esp.reset_method, usb::ResetMethod::DtrRts);
+		let TestResult::Failed(board) = executor.run() else {
+			panic!("a failed flash has to fail the board");
+		};
+		assert_eq!(board.id.as_deref(), Some("84:f3:eb:12:34:56"));
+		let flashing = board.values.last().unwrap();
+		assert!(flashing
+			.logs
+			.as_deref()
+			.unwrap()
+			.contains("MD5 of file does not match data in flash!"));
+		session::checkpoint("result", &outcome(&board)).unwrap();
+		assert!(executor.wait_for_device_disconnect(&mut || false));
+
+		assert!(session::is_finished());
+		assert_eq!(session::divergence(), None);
+	}
+}
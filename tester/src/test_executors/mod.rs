@@ -1,10 +1,23 @@
-use crate::TestResult;
+use std::sync;
+
+use crate::{api, logger, Board, TestResult};
 
 pub mod auxboard;
 pub mod mainboard;
 
 pub trait TestExecutor {
+	/// Steps shown in the dashboard checklist, in the order they run
+	fn steps(&self) -> Vec<&'static str>;
 	fn wait_for_device_connect(&mut self);
 	fn run(&mut self) -> TestResult;
-	fn wait_for_device_disconnect(&mut self);
+	/// Waits until the board is unplugged, or until `stop` returns `true`.
+	/// Returns whether the board was unplugged.
+	fn wait_for_device_disconnect(&mut self, stop: &mut dyn FnMut() -> bool) -> bool;
+}
+
+/// Adds `value` to the report of `board` and ticks its step off in the
+/// dashboard
+fn report(logger: &sync::Mutex<logger::Logger>, board: &mut Board, value: api::TestReportValue) {
+	logger.lock().unwrap().step(&value.step, value.failed);
+	board.add_value(value);
 }
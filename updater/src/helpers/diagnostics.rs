@@ -0,0 +1,48 @@
+use std::path::Path;
+
+use tracing::{Level, Metadata};
+use tracing_appender::{non_blocking::WorkerGuard, rolling};
+use tracing_subscriber::{
+	filter::filter_fn, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
+};
+
+/// Target of the messages printed by [`super::logger`], they are logged to
+/// the files as well
+pub const OPERATOR: &str = "operator";
+
+/// Logs everything allowed by `filter` (`EnvFilter` syntax, e.g.
+/// `info,updater::helpers::serial=debug`) to daily files in `dir`, keeping
+/// the last `keep_files`. Other warnings and errors are printed as well.
+/// Logs are written in the background until the returned guard is dropped.
+pub fn init(dir: &Path, filter: &str, keep_files: usize) -> Result<WorkerGuard, String> {
+	let appender = rolling::Builder::new()
+		.rotation(rolling::Rotation::DAILY)
+		.filename_prefix("updater")
+		.filename_suffix("log")
+		.max_log_files(keep_files)
+		.build(dir)
+		.map_err(|e| format!("could not create log files in {}: {}", dir.display(), e))?;
+	let (writer, guard) = tracing_appender::non_blocking(appender);
+
+	let filter = EnvFilter::try_new(filter).map_err(|e| format!("invalid log filter: {}", e))?;
+
+	tracing_subscriber::registry()
+		.with(
+			tracing_subscriber::fmt::layer()
+				.with_writer(writer)
+				.with_ansi(false)
+				.with_filter(filter),
+		)
+		.with(
+			tracing_subscriber::fmt::layer()
+				.without_time()
+				.with_target(false)
+				.with_filter(filter_fn(|m: &Metadata| {
+					m.target() != OPERATOR && *m.level() <= Level::WARN
+				})),
+		)
+		.try_init()
+		.map_err(|e| e.to_string())?;
+
+	Ok(guard)
+}
@@ -1,22 +1,28 @@
 use colored::Colorize;
 
+use super::diagnostics::OPERATOR;
+
 pub fn write(msg: &str, color: colored::Color) {
 	println!("{}", msg.color(color));
 }
 
 pub fn error(msg: &str) {
+	tracing::error!(target: OPERATOR, "{}", msg);
 	write(&format!("❌ {}", msg), colored::Color::Red);
 }
 
 pub fn success(msg: &str) {
+	tracing::info!(target: OPERATOR, "{}", msg);
 	write(&format!("✅ {}", msg), colored::Color::Green);
 }
 
 pub fn in_progress(msg: &str) {
+	tracing::debug!(target: OPERATOR, "{}", msg);
 	write(&format!("🕑 {}", msg), colored::Color::BrightBlue);
 }
 
 pub fn action(msg: &str) {
+	tracing::info!(target: OPERATOR, "{}", msg);
 	write(&format!("❗ {}", msg), colored::Color::BrightYellow);
 }
 
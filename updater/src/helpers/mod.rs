@@ -1,9 +1,11 @@
+pub mod diagnostics;
 pub mod logger;
 pub mod pio;
-pub mod serial;
 pub mod usb;
 
+pub use dut::{firmware, serial};
+
 pub struct ESP {
-	pub(crate) serial: serial::Serial,
+	pub(crate) serial: serial::Reader,
 	pub(crate) ip: String,
 }
@@ -14,10 +14,7 @@ pub fn build(environment: &str) -> Result<(), io::Error> {
 		.status()?;
 
 	if !status.success() {
-		return Err(io::Error::new(
-			io::ErrorKind::Other,
-			"`pio` exited with non-zero exit code".to_string(),
-		));
+		return Err(io::Error::other("`pio` exited with non-zero exit code"));
 	}
 
 	Ok(())
@@ -38,10 +35,7 @@ pub fn flash(environment: &str, esp: &mut ESP) -> Result<(), io::Error> {
 		.status()?;
 
 	if !status.success() {
-		return Err(io::Error::new(
-			io::ErrorKind::Other,
-			"`pio` exited with non-zero exit code".to_string(),
-		));
+		return Err(io::Error::other("`pio` exited with non-zero exit code"));
 	}
 	Ok(())
 }
@@ -1,26 +1,416 @@
+use rusb::UsbContext;
+use std::sync::mpsc;
 use std::thread;
 use std::time;
 
-pub fn find_device(vendor_id: u16, product_id: u16) -> bool {
-    for device in rusb::devices().unwrap().iter() {
-        let device_desc = device.device_descriptor().unwrap();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bridge {
+    pub name: &'static str,
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+pub const BRIDGES: [Bridge; 6] = [
+    Bridge {
+        name: "ch340",
+        vendor_id: 0x1a86,
+        product_id: 0x7523,
+    },
+    Bridge {
+        name: "ch9102",
+        vendor_id: 0x1a86,
+        product_id: 0x55d4,
+    },
+    Bridge {
+        name: "cp210x",
+        vendor_id: 0x10c4,
+        product_id: 0xea60,
+    },
+    Bridge {
+        name: "ft232r",
+        vendor_id: 0x0403,
+        product_id: 0x6001,
+    },
+    Bridge {
+        name: "ft231x",
+        vendor_id: 0x0403,
+        product_id: 0x6015,
+    },
+    // Shared by the ESP32-S3 and ESP32-C3
+    Bridge {
+        name: "esp-usb-jtag",
+        vendor_id: 0x303a,
+        product_id: 0x1001,
+    },
+];
+
+/// Parses a comma separated list of bridge names, e.g. `ch340,cp210x`
+pub fn parse_bridges(s: &str) -> Result<Vec<Bridge>, String> {
+    s.split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|name| {
+            BRIDGES
+                .iter()
+                .find(|b| b.name == name)
+                .copied()
+                .ok_or_else(|| format!("unknown USB bridge `{}`", name))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbDevice {
+    pub bridge: Bridge,
+    pub bus_number: u8,
+    /// Port path as used by sysfs, e.g. `1-1.2`
+    pub port_path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbDeviceInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bus_number: u8,
+    /// Port path as used by sysfs, e.g. `1-1.2`
+    pub port_path: String,
+}
+
+impl UsbDeviceInfo {
+    pub fn from_device<T: rusb::UsbContext>(device: &rusb::Device<T>) -> Option<Self> {
+        let device_desc = device.device_descriptor().ok()?;
+
+        Some(UsbDeviceInfo {
+            vendor_id: device_desc.vendor_id(),
+            product_id: device_desc.product_id(),
+            bus_number: device.bus_number(),
+            port_path: port_path(device),
+        })
+    }
+
+    /// Returns the device if it uses one of `bridges` and is plugged into
+    /// `port` (any port if `None`)
+    pub fn matches(&self, bridges: &[Bridge], port: Option<&str>) -> Option<UsbDevice> {
+        let bridge = bridges
+            .iter()
+            .find(|b| (self.vendor_id == b.vendor_id) && (self.product_id == b.product_id))?;
+
+        if port.is_some_and(|p| p != self.port_path) {
+            return None;
+        }
+
+        Some(UsbDevice {
+            bridge: *bridge,
+            bus_number: self.bus_number,
+            port_path: self.port_path.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsbEvent {
+    Arrived(UsbDeviceInfo),
+    Left(UsbDeviceInfo),
+}
+
+fn port_path<T: rusb::UsbContext>(device: &rusb::Device<T>) -> String {
+    let ports = device
+        .port_numbers()
+        .unwrap_or_default()
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>()
+        .join(".");
+
+    format!("{}-{}", device.bus_number(), ports)
+}
+
+fn list_devices() -> Vec<UsbDeviceInfo> {
+    match rusb::devices() {
+        Ok(devices) => devices
+            .iter()
+            .filter_map(|d| UsbDeviceInfo::from_device(&d))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Finds a device using any of `bridges`, optionally restricted to a single
+/// port path
+pub fn find_device(bridges: &[Bridge], port: Option<&str>) -> Option<UsbDevice> {
+    list_devices()
+        .iter()
+        .find_map(|d| d.matches(bridges, port))
+}
+
+/// Resolves the tty of a USB serial adapter by looking at the interfaces of
+/// the device in sysfs, e.g. `/sys/bus/usb/devices/1-1.2/1-1.2:1.0/ttyUSB0`.
+#[cfg(target_os = "linux")]
+pub fn find_serial_port(device: &UsbDevice) -> Option<String> {
+    let device_dir = std::path::Path::new("/sys/bus/usb/devices").join(&device.port_path);
+    let interface_prefix = format!("{}:", device.port_path);
+
+    let mut interfaces = std::fs::read_dir(device_dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with(&interface_prefix))
+        .map(|e| e.path())
+        .collect::<Vec<_>>();
+    interfaces.sort();
+
+    for interface in interfaces {
+        // USB-serial drivers (ch341, cp210x, ftdi_sio) put the tty directly
+        // into the interface, CDC ACM puts it into a `tty` subdirectory
+        for dir in [interface.clone(), interface.join("tty")] {
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                continue;
+            };
+
+            for entry in entries.filter_map(|e| e.ok()) {
+                let name = entry.file_name().to_string_lossy().to_string();
+
+                if name.starts_with("ttyUSB") || name.starts_with("ttyACM") {
+                    return Some(format!("/dev/{}", name));
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Resolves the serial port of a USB serial adapter by its VID/PID. The port
+/// path is not available here, so the first matching port wins.
+#[cfg(not(target_os = "linux"))]
+pub fn find_serial_port(device: &UsbDevice) -> Option<String> {
+    serialport::available_ports()
+        .ok()?
+        .into_iter()
+        .find(|p| match &p.port_type {
+            serialport::SerialPortType::UsbPort(info) => {
+                info.vid == device.bridge.vendor_id && info.pid == device.bridge.product_id
+            }
+            _ => false,
+        })
+        .map(|p| p.port_name)
+}
+
+/// Waits for the tty of `device` to show up, the driver usually binds a few
+/// hundred milliseconds after the device enumerated
+pub fn wait_for_serial_port(device: &UsbDevice, timeout: time::Duration) -> Option<String> {
+    let start = time::Instant::now();
+
+    loop {
+        if let Some(port) = find_serial_port(device) {
+            return Some(port);
+        }
+
+        if start.elapsed() > timeout {
+            return None;
+        }
+
+        thread::sleep(time::Duration::from_millis(100));
+    }
+}
+
+struct HotplugSender {
+    tx: mpsc::Sender<UsbEvent>,
+}
+
+impl rusb::Hotplug<rusb::Context> for HotplugSender {
+    fn device_arrived(&mut self, device: rusb::Device<rusb::Context>) {
+        if let Some(info) = UsbDeviceInfo::from_device(&device) {
+            let _ = self.tx.send(UsbEvent::Arrived(info));
+        }
+    }
 
-        if (device_desc.vendor_id() == vendor_id) && (device_desc.product_id() == product_id) {
-            return true;
+    fn device_left(&mut self, device: rusb::Device<rusb::Context>) {
+        if let Some(info) = UsbDeviceInfo::from_device(&device) {
+            let _ = self.tx.send(UsbEvent::Left(info));
         }
     }
+}
+
+fn watch_hotplug(tx: mpsc::Sender<UsbEvent>) -> rusb::Result<()> {
+    if !rusb::has_hotplug() {
+        return Err(rusb::Error::NotSupported);
+    }
+
+    let context = rusb::Context::new()?;
+    let registration = rusb::HotplugBuilder::new()
+        .enumerate(true)
+        .register(&context, Box::new(HotplugSender { tx }))?;
+
+    thread::spawn(move || {
+        let _registration = registration;
+
+        loop {
+            if let Err(e) = context.handle_events(None) {
+                tracing::warn!("failed to handle USB events: {}", e);
+                thread::sleep(time::Duration::from_secs(1));
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn watch_polling(tx: mpsc::Sender<UsbEvent>) {
+    thread::spawn(move || {
+        let mut known: Vec<UsbDeviceInfo> = Vec::new();
+
+        loop {
+            let devices = list_devices();
+
+            for device in known.iter().filter(|d| !devices.contains(d)) {
+                if tx.send(UsbEvent::Left(device.clone())).is_err() {
+                    return;
+                }
+            }
+
+            for device in devices.iter().filter(|d| !known.contains(d)) {
+                if tx.send(UsbEvent::Arrived(device.clone())).is_err() {
+                    return;
+                }
+            }
 
-    false
+            known = devices;
+
+            thread::sleep(time::Duration::from_millis(250));
+        }
+    });
 }
 
-pub fn wait_until_device_is_connected(vendor_id: u16, product_id: u16) {
-    while !find_device(vendor_id, product_id) {
-        thread::sleep(time::Duration::from_secs(1));
+/// Streams USB arrive/leave events, starting with an `Arrived` event for every
+/// device that is already connected. Uses libusb hotplug callbacks where
+/// available and falls back to polling the device list.
+pub fn watch() -> mpsc::Receiver<UsbEvent> {
+    let (tx, rx) = mpsc::channel();
+
+    if let Err(e) = watch_hotplug(tx.clone()) {
+        tracing::warn!("USB hotplug unavailable ({}), polling instead", e);
+
+        watch_polling(tx);
     }
+
+    rx
+}
+
+/// Keeps track of the connected USB devices through [`watch`]
+pub struct Watcher {
+    events: mpsc::Receiver<UsbEvent>,
+    connected: Vec<UsbDeviceInfo>,
 }
 
-pub fn wait_until_device_is_disconnected(vendor_id: u16, product_id: u16) {
-    while find_device(vendor_id, product_id) {
-        thread::sleep(time::Duration::from_secs(1));
+impl Default for Watcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Watcher {
+    pub fn new() -> Self {
+        Watcher {
+            events: watch(),
+            connected: Vec::new(),
+        }
+    }
+
+    fn handle(&mut self, event: UsbEvent) {
+        match event {
+            UsbEvent::Arrived(device) => {
+                if !self.connected.contains(&device) {
+                    self.connected.push(device);
+                }
+            }
+            UsbEvent::Left(device) => self.connected.retain(|d| *d != device),
+        }
+    }
+
+    fn find(&mut self, bridges: &[Bridge], port: Option<&str>) -> Option<UsbDevice> {
+        while let Ok(event) = self.events.try_recv() {
+            self.handle(event);
+        }
+
+        self.connected
+            .iter()
+            .find_map(|d| d.matches(bridges, port))
+    }
+
+    fn next_event(&mut self) {
+        match self.events.recv() {
+            Ok(event) => self.handle(event),
+            // The watcher thread is gone, don't spin
+            Err(_) => thread::sleep(time::Duration::from_secs(1)),
+        }
+    }
+
+    pub fn wait_until_device_is_connected(
+        &mut self,
+        bridges: &[Bridge],
+        port: Option<&str>,
+    ) -> UsbDevice {
+        loop {
+            if let Some(device) = self.find(bridges, port) {
+                return device;
+            }
+
+            self.next_event();
+        }
+    }
+
+    /// Like [`Watcher::wait_until_device_is_connected`], but gives up after
+    /// `timeout`
+    pub fn wait_for_device(
+        &mut self,
+        bridges: &[Bridge],
+        port: Option<&str>,
+        timeout: time::Duration,
+    ) -> Option<UsbDevice> {
+        let deadline = time::Instant::now() + timeout;
+
+        loop {
+            if let Some(device) = self.find(bridges, port) {
+                return Some(device);
+            }
+
+            let remaining = deadline.checked_duration_since(time::Instant::now())?;
+            match self.events.recv_timeout(remaining) {
+                Ok(event) => self.handle(event),
+                Err(mpsc::RecvTimeoutError::Timeout) => return None,
+                Err(mpsc::RecvTimeoutError::Disconnected) => thread::sleep(remaining),
+            }
+        }
+    }
+
+    pub fn wait_until_device_is_disconnected(&mut self, bridges: &[Bridge], port: Option<&str>) {
+        while self.find(bridges, port).is_some() {
+            self.next_event();
+        }
+    }
+
+    /// Like [`Watcher::wait_until_device_is_disconnected`], but gives up as
+    /// soon as `stop` returns `true`, which is checked every `interval`.
+    /// Returns whether the device was disconnected.
+    pub fn wait_until_device_is_disconnected_or(
+        &mut self,
+        bridges: &[Bridge],
+        port: Option<&str>,
+        interval: time::Duration,
+        mut stop: impl FnMut() -> bool,
+    ) -> bool {
+        while self.find(bridges, port).is_some() {
+            if stop() {
+                return false;
+            }
+
+            match self.events.recv_timeout(interval) {
+                Ok(event) => self.handle(event),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => thread::sleep(interval),
+            }
+        }
+
+        true
     }
 }
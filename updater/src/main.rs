@@ -1,7 +1,7 @@
 use std::{io, thread::sleep, time::Duration};
 
 use updater::{
-	helpers::{logger, pio},
+	helpers::{diagnostics, logger, pio},
 	options,
 	upload_update::UploadUpdateExecutor,
 };
@@ -25,6 +25,17 @@ fn maybe_build_firmware(options: &options::Options) -> Result<(), io::Error> {
 fn main() {
 	let options = options::Options::parse();
 
+	// Kept until the end of main so buffered log lines are written
+	let _log_guard =
+		match diagnostics::init(&options.log_dir, &options.log_filter, options.log_keep_files) {
+			Ok(guard) => guard,
+			Err(e) => {
+				println!("Could not set up logging: {}", e);
+
+				std::process::exit(1);
+			}
+		};
+
 	if let Err(e) = maybe_build_firmware(&options) {
 		println!("Could not build firmware: {}", e);
 
@@ -1,4 +1,6 @@
-use std::env;
+use std::{env, path::PathBuf, time::Duration};
+
+use crate::helpers::usb;
 
 #[derive(Clone)]
 pub enum FlashWith {
@@ -10,6 +12,18 @@ pub struct Options {
 	pub no_build: bool,
 	pub ssid: String,
 	pub password: String,
+	/// Only accept the device on this USB port path (e.g. `1-1.2`)
+	pub usb_port_path: Option<String>,
+	/// USB serial bridges accepted as device
+	pub bridges: Vec<usb::Bridge>,
+	/// How long to wait for an expected line on the serial port
+	pub serial_timeout: Duration,
+	/// Directory of the daily log files
+	pub log_dir: PathBuf,
+	/// What to write to the log files, in `RUST_LOG` syntax
+	pub log_filter: String,
+	/// How many daily log files to keep
+	pub log_keep_files: usize,
 }
 
 impl Options {
@@ -17,11 +31,30 @@ impl Options {
 		let no_build = env::var("BUILD").map(|v| v == "no").unwrap_or(false);
 		let ssid = env::var("SSID").unwrap_or("".to_string());
 		let password = env::var("PASSWORD").unwrap_or("".to_string());
+		let usb_port_path = env::var("USB_PORT_PATH").ok();
+		let bridges = usb::parse_bridges(&env::var("BRIDGES").unwrap_or("ch340".to_string()))
+			.unwrap_or_else(|e| panic!("BRIDGES is invalid: {}", e));
+		let serial_timeout = env::var("SERIAL_TIMEOUT")
+			.map(|v| Duration::from_secs(v.parse::<u64>().unwrap()))
+			.unwrap_or(Duration::from_secs(60));
+		let log_dir = env::var("LOG_DIR")
+			.map(PathBuf::from)
+			.unwrap_or(PathBuf::from("/home/pi/updater-logs"));
+		let log_filter = env::var("LOG").unwrap_or("info".to_string());
+		let log_keep_files = env::var("LOG_KEEP")
+			.map(|v| v.parse::<usize>().unwrap())
+			.unwrap_or(14);
 
 		Self {
 			no_build,
 			ssid,
 			password,
+			usb_port_path,
+			bridges,
+			serial_timeout,
+			log_dir,
+			log_filter,
+			log_keep_files,
 		}
 	}
 }
@@ -1,33 +1,38 @@
-use regex::Regex;
-
 use crate::{helpers::ESP, options::Options};
 
-use super::helpers::{logger, pio, serial, usb};
+use super::helpers::{firmware, logger, pio, serial, usb};
 
 use std::{io, thread, time};
 
-const USB_VENDOR_ID: u16 = 0x1a86;
-const USB_PRODUCT_ID: u16 = 0x7523;
-
 pub struct UploadUpdateExecutor {
 	options: Options,
-	ip_regex: Regex,
+	usb: usb::Watcher,
+	port: Option<String>,
 }
 
 impl UploadUpdateExecutor {
 	pub fn new(options: Options) -> Self {
 		Self {
 			options,
-			ip_regex: Regex::new("((25[0-5]|(2[0-4]|1\\d|[1-9]|)\\d)\\.?\\b){4}").unwrap(),
+			usb: usb::Watcher::new(),
+			port: None,
 		}
 	}
 
 	pub fn wait_for_device_connect(&mut self) {
-		usb::wait_until_device_is_connected(USB_VENDOR_ID, USB_PRODUCT_ID);
+		let device = self.usb.wait_until_device_is_connected(
+			&self.options.bridges,
+			self.options.usb_port_path.as_deref(),
+		);
+
+		self.port = usb::wait_for_serial_port(&device, time::Duration::from_secs(5));
 	}
 
 	pub fn wait_for_device_disconnect(&mut self) {
-		usb::wait_until_device_is_disconnected(USB_VENDOR_ID, USB_PRODUCT_ID);
+		self.usb.wait_until_device_is_disconnected(
+			&self.options.bridges,
+			self.options.usb_port_path.as_deref(),
+		);
 	}
 
 	pub fn run(&mut self) -> Result<(), io::Error> {
@@ -36,7 +41,16 @@ impl UploadUpdateExecutor {
 		let mut serial = {
 			logger::in_progress("Connecting to serial port...");
 
-			let serial = serialport::new("COM3", 115200)
+			let Some(port) = self.port.clone() else {
+				logger::error("No serial port found for the device");
+
+				return Err(io::Error::new(
+					io::ErrorKind::NotFound,
+					"no serial port found for the device",
+				));
+			};
+
+			let serial = serialport::new(port, 115200)
 				.timeout(time::Duration::from_millis(10000))
 				.data_bits(serialport::DataBits::Eight)
 				.open();
@@ -61,43 +75,35 @@ impl UploadUpdateExecutor {
 				}
 			};
 
-			if let Err(e) = serial.clear(serialport::ClearBuffer::All) {
-				println!("(warn) failed to clear serial port: {}", e);
-			}
-
-			serial
+			serial::Reader::new(serial)
 		};
 
-		serial
-			.clear(serialport::ClearBuffer::All)
-			.map_err(|v| io::Error::new(io::ErrorKind::Other, v))?;
+		serial.clear().map_err(io::Error::other)?;
 
 		logger::in_progress("Setting WiFi credentials...");
-		serial::write(
-			&mut serial,
-			format!(
-				"SET WIFI \"{}\" \"{}\"\n",
-				self.options.ssid, self.options.password
-			)
-			.as_bytes(),
-		)
-		.map_err(|v| io::Error::new(io::ErrorKind::Other, v))?;
+		firmware::Client::new(&mut serial, self.options.serial_timeout)
+			.set_wifi(&self.options.ssid, &self.options.password)
+			.map_err(io::Error::other)?;
 
 		let ip = {
 			logger::in_progress("Waiting for network connection");
 
-			match serial::read_string_until(
-				&mut serial,
-				vec!["Connected successfully to SSID"],
-				vec![],
-			) {
-				Ok(logs) => {
-					logger::success("Found network connection message");
-
-					self.ip_regex.find(&logs).unwrap().as_str().to_string()
-				}
-				Err(_) => unreachable!(),
-			}
+			let m = serial
+				.expect(
+					// Firmware versions differ in case and wording after the
+					// SSID, the first IPv4 address is the one we got
+					&[serial::Pattern::regex(
+						r"(?i)connected successfully to SSID .*?(?P<ip>\d{1,3}(\.\d{1,3}){3})",
+					)
+					.unwrap()],
+					&[],
+					self.options.serial_timeout,
+				)
+				.map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))?;
+
+			logger::success("Found network connection message");
+
+			m.get("ip").unwrap_or_default().to_string()
 		};
 
 		thread::sleep(time::Duration::from_secs(2));
@@ -123,14 +129,14 @@ impl UploadUpdateExecutor {
 		thread::sleep(time::Duration::from_secs(2));
 
 		logger::in_progress("Resetting device to factory defaults...");
-		serial::write(&mut esp.serial, b"FRST\n")
-			.map_err(|v| io::Error::new(io::ErrorKind::Other, v))?;
+		firmware::Client::new(&mut esp.serial, self.options.serial_timeout)
+			.factory_reset()
+			.map_err(io::Error::other)?;
 
 		logger::in_progress("Waiting for startup message...");
-		match serial::read_string_until(&mut esp.serial, vec!["starting up"], vec![]) {
-			Ok(_) => (),
-			Err(_) => unreachable!(),
-		};
+		esp.serial
+			.read_until(&["starting up"], &[], self.options.serial_timeout)
+			.map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))?;
 
 		Ok(())
 	}